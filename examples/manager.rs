@@ -25,6 +25,8 @@ async fn main() -> Result<(), Error> {
     //     .await?;
     zone.play_now(Apple("album:1439398162".into())).await?;
     // zone.play_now(SonosFavorite("New York Rhapsody".into())).await?;
+    // zone.play_now(Apple("station:ra.978194965".into())).await?;
+    // zone.play_now(AppleAlbumAt("album:1439398162".into(), 3)).await?;
     // sleep(Duration::from_secs(10)).await;
     // zone.set_play_mode(sonor::RepeatMode::One, true).await?;
     // zone.play_or_pause().await?;
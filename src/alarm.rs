@@ -0,0 +1,296 @@
+//! Sonos alarms, managed with the AlarmClock service
+
+use crate::{
+    types::HouseholdId,
+    utils::{format_time, parse_play_mode, parse_time, play_mode},
+    Error, MediaSource, Result,
+};
+use roxmltree::Document;
+use sonor::{utils::escape_str_pcdata, RepeatMode, Speaker, URN};
+use std::time::Duration;
+
+/// Service for the alarms of a household, found on every speaker
+pub(crate) const ALARM_CLOCK: &URN = &URN::service("schemas-upnp-org", "AlarmClock", 1);
+
+const CHIME_URI: &str = "x-rincon-buzzer:0";
+
+/// Days an alarm goes off
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Recurrence {
+    Once,
+    Daily,
+    Weekdays,
+    Weekends,
+    /// Days of the week, from 0 for Sunday to 6 for Saturday
+    Days(Vec<u8>),
+}
+
+impl Recurrence {
+    fn to_sonos(&self) -> String {
+        match self {
+            Recurrence::Once => "ONCE".into(),
+            Recurrence::Daily => "DAILY".into(),
+            Recurrence::Weekdays => "WEEKDAYS".into(),
+            Recurrence::Weekends => "WEEKENDS".into(),
+            Recurrence::Days(days) => {
+                let mut days = days.clone();
+                days.sort_unstable();
+                days.dedup();
+                days.iter().fold(String::from("ON_"), |mut acc, d| {
+                    acc.push(char::from(b'0' + d));
+                    acc
+                })
+            }
+        }
+    }
+
+    fn from_sonos(recurrence: &str) -> Option<Recurrence> {
+        match recurrence {
+            "ONCE" => Some(Recurrence::Once),
+            "DAILY" => Some(Recurrence::Daily),
+            "WEEKDAYS" => Some(Recurrence::Weekdays),
+            "WEEKENDS" => Some(Recurrence::Weekends),
+            _ => recurrence
+                .strip_prefix("ON_")?
+                .chars()
+                .map(|c| c.to_digit(7).map(|d| d as u8))
+                .collect::<Option<Vec<_>>>()
+                .map(Recurrence::Days),
+        }
+    }
+}
+
+/// What an alarm plays
+#[derive(Debug, Clone)]
+pub enum AlarmSource {
+    /// The sonos chime
+    Chime,
+    /// Media to find when the alarm is saved, e.g. a sonos favorite
+    Media(MediaSource),
+    /// A transport URI and its DIDL-Lite metadata, as listed alarms report
+    Uri { uri: String, metadata: String },
+}
+
+/// An alarm of a sonos household
+#[derive(Debug, Clone)]
+pub struct Alarm {
+    /// Set by the speakers when the alarm is created
+    pub id: Option<u32>,
+    /// Household of the alarm. When not given, it's the household of the room.
+    pub household: Option<HouseholdId>,
+    /// Zone the alarm plays in: a room name, alias or ID. Listed alarms give
+    /// the UUID of the room's speaker.
+    pub room: String,
+    /// Local time of day the alarm goes off
+    pub start_time: Duration,
+    /// How long the alarm plays
+    pub duration: Duration,
+    pub recurrence: Recurrence,
+    pub enabled: bool,
+    pub volume: u16,
+    pub source: AlarmSource,
+    pub shuffle: bool,
+    pub repeat: RepeatMode,
+    /// Whether rooms grouped with the room play the alarm too
+    pub include_linked_zones: bool,
+}
+
+impl Alarm {
+    /// An enabled alarm that plays the chime in a room every day for an hour
+    pub fn new(room: impl Into<String>, start_time: Duration) -> Alarm {
+        Alarm {
+            id: None,
+            household: None,
+            room: room.into(),
+            start_time,
+            duration: Duration::from_secs(60 * 60),
+            recurrence: Recurrence::Daily,
+            enabled: true,
+            volume: 20,
+            source: AlarmSource::Chime,
+            shuffle: false,
+            repeat: RepeatMode::None,
+            include_linked_zones: false,
+        }
+    }
+
+    /// Arguments of CreateAlarm and UpdateAlarm, after the ID. The source
+    /// must already be resolved to a URI.
+    fn payload(&self) -> Result<String> {
+        // The speakers turn down "ON_" without days
+        if let Recurrence::Days(ref days) = self.recurrence {
+            if days.is_empty() || days.iter().any(|&d| d > 6) {
+                return Err(Error::InvalidAlarm(format!(
+                    "days of the week must be 0 to 6, not {:?}",
+                    days
+                )));
+            }
+        }
+        let (uri, metadata) = match self.source {
+            AlarmSource::Chime => (CHIME_URI, ""),
+            AlarmSource::Uri {
+                ref uri,
+                ref metadata,
+            } => (uri.as_str(), metadata.as_str()),
+            AlarmSource::Media(_) => return Err(Error::ContentNotFound),
+        };
+        Ok(format!(
+            "<StartLocalTime>{}</StartLocalTime><Duration>{}</Duration>\
+            <Recurrence>{}</Recurrence><Enabled>{}</Enabled><RoomUUID>{}</RoomUUID>\
+            <ProgramURI>{}</ProgramURI><ProgramMetaData>{}</ProgramMetaData>\
+            <PlayMode>{}</PlayMode><Volume>{}</Volume>\
+            <IncludeLinkedZones>{}</IncludeLinkedZones>",
+            format_time(self.start_time),
+            format_time(self.duration),
+            self.recurrence.to_sonos(),
+            u8::from(self.enabled),
+            self.room,
+            escape_str_pcdata(uri),
+            escape_str_pcdata(metadata),
+            play_mode(self.shuffle, self.repeat),
+            self.volume.min(100),
+            u8::from(self.include_linked_zones),
+        ))
+    }
+}
+
+/// Parse the CurrentAlarmList returned by ListAlarms. Alarms that can't be
+/// parsed are left out with a warning.
+pub(crate) fn parse_alarm_list(xml: &str, household: &str) -> Result<Vec<Alarm>> {
+    let doc = Document::parse(xml).map_err(sonor::Error::from)?;
+    Ok(doc
+        .descendants()
+        .filter(|n| n.has_tag_name("Alarm"))
+        .filter_map(|n| match parse_alarm(n, household) {
+            Ok(alarm) => Some(alarm),
+            Err(attribute) => {
+                log::warn!(
+                    "Leaving out alarm {} of {}: bad or missing {} {:?}",
+                    n.attribute("ID").unwrap_or("without ID"),
+                    household,
+                    attribute,
+                    n.attribute(attribute).unwrap_or_default()
+                );
+                None
+            }
+        })
+        .collect())
+}
+
+/// Parse an alarm of the CurrentAlarmList, or get the attribute that
+/// couldn't be parsed
+fn parse_alarm(n: roxmltree::Node, household: &str) -> Result<Alarm, &'static str> {
+    let attribute = |name: &'static str| n.attribute(name).ok_or(name);
+    let uri = n.attribute("ProgramURI").unwrap_or(CHIME_URI);
+    let (shuffle, repeat) = parse_play_mode(n.attribute("PlayMode").unwrap_or(""));
+    Ok(Alarm {
+        id: Some(attribute("ID")?.parse().map_err(|_| "ID")?),
+        household: Some(household.to_owned()),
+        room: attribute("RoomUUID")?.to_owned(),
+        start_time: parse_time(attribute("StartTime")?).ok_or("StartTime")?,
+        duration: n
+            .attribute("Duration")
+            .and_then(parse_time)
+            .unwrap_or_default(),
+        recurrence: Recurrence::from_sonos(attribute("Recurrence")?).ok_or("Recurrence")?,
+        enabled: n.attribute("Enabled") == Some("1"),
+        volume: n
+            .attribute("Volume")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_default(),
+        source: match uri {
+            CHIME_URI => AlarmSource::Chime,
+            _ => AlarmSource::Uri {
+                uri: uri.to_owned(),
+                metadata: n.attribute("ProgramMetaData").unwrap_or("").to_owned(),
+            },
+        },
+        shuffle,
+        repeat,
+        include_linked_zones: n.attribute("IncludeLinkedZones") == Some("1"),
+    })
+}
+
+pub(crate) async fn list_alarms(speaker: &Speaker, household: &str) -> Result<Vec<Alarm>> {
+    let xml = speaker
+        .action(ALARM_CLOCK, "ListAlarms", "")
+        .await?
+        .remove("CurrentAlarmList")
+        .ok_or(Error::ZoneActionError)?;
+    parse_alarm_list(&xml, household)
+}
+
+pub(crate) async fn create_alarm(speaker: &Speaker, alarm: &Alarm) -> Result<u32> {
+    speaker
+        .action(ALARM_CLOCK, "CreateAlarm", &alarm.payload()?)
+        .await?
+        .get("AssignedID")
+        .and_then(|id| id.parse().ok())
+        .ok_or(Error::ZoneActionError)
+}
+
+pub(crate) async fn update_alarm(speaker: &Speaker, alarm: &Alarm) -> Result<()> {
+    let id = alarm.id.ok_or(Error::ZoneActionError)?;
+    let payload = format!("<ID>{}</ID>{}", id, alarm.payload()?);
+    speaker.action(ALARM_CLOCK, "UpdateAlarm", &payload).await?;
+    Ok(())
+}
+
+pub(crate) async fn destroy_alarm(speaker: &Speaker, id: u32) -> Result<()> {
+    let payload = format!("<ID>{}</ID>", id);
+    speaker
+        .action(ALARM_CLOCK, "DestroyAlarm", &payload)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alarm_list() -> Result<()> {
+        let xml = r#"<Alarms>
+            <Alarm ID="12" StartTime="07:30:00" Duration="02:00:00" Recurrence="ON_12345" Enabled="1" RoomUUID="RINCON_1" ProgramURI="x-rincon-buzzer:0" ProgramMetaData="" PlayMode="SHUFFLE_NOREPEAT" Volume="25" IncludeLinkedZones="0"/>
+            <Alarm ID="13" StartTime="21:00:00" Duration="00:30:00" Recurrence="ONCE" Enabled="0" RoomUUID="RINCON_2" ProgramURI="x-sonosapi-radio:abc" ProgramMetaData="&lt;DIDL-Lite/&gt;" PlayMode="NORMAL" Volume="10" IncludeLinkedZones="1"/>
+        </Alarms>"#;
+        let alarms = parse_alarm_list(xml, "Sonos_1")?;
+        assert_eq!(alarms.len(), 2);
+        let alarm = &alarms[0];
+        assert_eq!(alarm.id, Some(12));
+        assert_eq!(alarm.start_time, Duration::from_secs(7 * 3600 + 30 * 60));
+        assert_eq!(alarm.recurrence, Recurrence::Days(vec![1, 2, 3, 4, 5]));
+        assert!(alarm.shuffle && alarm.enabled && !alarm.include_linked_zones);
+        assert_eq!(
+            alarm.payload()?,
+            "<StartLocalTime>07:30:00</StartLocalTime><Duration>02:00:00</Duration>\
+            <Recurrence>ON_12345</Recurrence><Enabled>1</Enabled><RoomUUID>RINCON_1</RoomUUID>\
+            <ProgramURI>x-rincon-buzzer:0</ProgramURI><ProgramMetaData></ProgramMetaData>\
+            <PlayMode>SHUFFLE_NOREPEAT</PlayMode><Volume>25</Volume>\
+            <IncludeLinkedZones>0</IncludeLinkedZones>"
+        );
+        assert!(matches!(
+            alarms[1].source,
+            AlarmSource::Uri { ref metadata, .. } if metadata == "<DIDL-Lite/>"
+        ));
+
+        // Alarms that can't be parsed are left out, not the whole list
+        let xml = r#"<Alarms>
+            <Alarm ID="14" StartTime="07:30:00" Recurrence="ON_SOMEDAY" RoomUUID="RINCON_1"/>
+            <Alarm ID="15" StartTime="08:00:00" Recurrence="DAILY" RoomUUID="RINCON_1"/>
+        </Alarms>"#;
+        let alarms = parse_alarm_list(xml, "Sonos_1")?;
+        assert_eq!(alarms.len(), 1);
+        assert_eq!(alarms[0].id, Some(15));
+
+        // Recurrences without valid days are turned down before they're sent
+        for days in [vec![], vec![7], vec![1, 9]] {
+            let alarm = Alarm {
+                recurrence: Recurrence::Days(days),
+                ..alarms[0].clone()
+            };
+            assert!(matches!(alarm.payload(), Err(Error::InvalidAlarm(_))));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,340 @@
+//! Configuration of a [`Manager`] before it starts
+
+use crate::{
+    cache::TopologyCache,
+    controller::{Controller, Discovery},
+    gena::CallbackServer,
+    types::Uuid,
+    utils, Error, Manager, Result,
+};
+use std::fmt::Write as _;
+use std::net::{IpAddr, Ipv4Addr};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Timings of discovery, subscriptions and rediscovery
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Timings {
+    /// How long SSDP discovery listens for speakers
+    pub discovery_timeout: Duration,
+    /// Timeout requested for event subscriptions
+    pub subscription_timeout: Duration,
+    /// How often event subscriptions are renewed
+    pub renewal_interval: Duration,
+    /// How long a new subscription waits for its initial event before the
+    /// callback URL is reported as unreachable
+    pub initial_notify_timeout: Duration,
+    /// Delay before the first rediscovery retry
+    pub rediscovery_min: Duration,
+    /// Longest delay between rediscovery retries
+    pub rediscovery_max: Duration,
+}
+
+impl Default for Timings {
+    fn default() -> Self {
+        Timings {
+            discovery_timeout: Duration::from_secs(5),
+            subscription_timeout: Duration::from_secs(300),
+            renewal_interval: Duration::from_secs(60),
+            initial_notify_timeout: Duration::from_secs(10),
+            rediscovery_min: Duration::from_secs(1),
+            rediscovery_max: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Timings {
+    /// How often subscriptions are actually renewed: at least once a second,
+    /// and well before they time out.
+    pub fn renewal_period(&self) -> Duration {
+        self.renewal_interval
+            .min(self.subscription_timeout.mul_f64(0.9))
+            .max(Duration::from_secs(1))
+    }
+
+    /// Delay before retrying rediscovery after a number of consecutive
+    /// failures. The delay doubles with each failure up to the maximum, and
+    /// is randomized to between half and all of that so that many
+    /// controllers don't retry in lockstep.
+    pub fn rediscovery_delay(&self, failures: u32) -> Duration {
+        let delay = self
+            .rediscovery_min
+            .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+            .min(self.rediscovery_max);
+        delay.mul_f64(0.5 + fastrand::f64() / 2.0)
+    }
+}
+
+/// Where speakers send events to
+#[derive(Debug, Clone)]
+pub(crate) struct CallbackConfig {
+    /// Local address the event listener binds to
+    pub bind: IpAddr,
+    /// Ports tried for the event listener, in order. 0 for any free port.
+    pub ports: RangeInclusive<u16>,
+    /// Host in callback URLs, instead of the local address that routes to
+    /// the speaker
+    pub host: Option<String>,
+    /// Port in callback URLs, instead of the port of the listener
+    pub port: Option<u16>,
+}
+
+impl Default for CallbackConfig {
+    fn default() -> Self {
+        CallbackConfig {
+            bind: Ipv4Addr::UNSPECIFIED.into(),
+            ports: 0..=0,
+            host: None,
+            port: None,
+        }
+    }
+}
+
+/// Builder for a [`Manager`], created with [`Manager::builder`].
+///
+/// ```no_run
+/// # async fn example() -> Result<(), sonos_manager::Error> {
+/// use std::time::Duration;
+///
+/// let manager = sonos_manager::Manager::builder()
+///     .cache("/var/cache/sonos.xml")
+///     .discovery_timeout(Duration::from_secs(2))
+///     .rediscovery_backoff(Duration::from_secs(1), Duration::from_secs(300))
+///     .build()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ManagerBuilder {
+    room: Option<String>,
+    addresses: Option<Vec<String>>,
+    cache: Option<PathBuf>,
+    aliases: Vec<(String, Uuid)>,
+    timings: Timings,
+    callback: CallbackConfig,
+    channel_size: usize,
+}
+
+impl Default for ManagerBuilder {
+    fn default() -> Self {
+        ManagerBuilder {
+            room: None,
+            addresses: None,
+            cache: None,
+            aliases: Vec::new(),
+            timings: Timings::default(),
+            callback: CallbackConfig::default(),
+            channel_size: 32,
+        }
+    }
+}
+
+impl ManagerBuilder {
+    /// Only control the household with a speaker with this room name.
+    /// Ignored when speaker addresses are given.
+    pub fn room(mut self, room: impl Into<String>) -> Self {
+        self.room = Some(room.into());
+        self
+    }
+
+    /// Contact speakers at known addresses instead of using SSDP discovery.
+    /// Addresses may be IPs, host names or device description URLs.
+    pub fn ips<I, S>(mut self, addresses: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.addresses = Some(
+            addresses
+                .into_iter()
+                .map(|addr| addr.as_ref().to_owned())
+                .collect(),
+        );
+        self
+    }
+
+    /// Start from, and keep up-to-date, the topology and aliases cached in a
+    /// file.
+    /// [`ManagerBuilder::build`] then returns without contacting speakers.
+    /// The cached speakers are loaded in the background, and commands wait
+    /// only for the speakers they need.
+    pub fn cache(mut self, path: impl Into<PathBuf>) -> Self {
+        self.cache = Some(path.into());
+        self
+    }
+
+    /// Start with an alias for the zone with this stable ID, see
+    /// [`crate::Zone::id`]. Aliases added with [`crate::Zone::add_alias`] are
+    /// only kept across runs in the cache.
+    pub fn alias(mut self, alias: impl Into<String>, id: impl Into<Uuid>) -> Self {
+        let alias = alias.into();
+        self.aliases
+            .retain(|(a, _)| !a.eq_ignore_ascii_case(&alias));
+        self.aliases.push((alias, id.into()));
+        self
+    }
+
+    /// How long SSDP discovery listens for speakers. Defaults to 5 seconds.
+    pub fn discovery_timeout(mut self, timeout: Duration) -> Self {
+        self.timings.discovery_timeout = timeout;
+        self
+    }
+
+    /// Timeout requested from speakers for event subscriptions. Defaults to
+    /// 5 minutes.
+    pub fn subscription_timeout(mut self, timeout: Duration) -> Self {
+        self.timings.subscription_timeout = timeout;
+        self
+    }
+
+    /// How often event subscriptions are renewed. Kept to at least 1 second
+    /// and below the subscription timeout. Defaults to 1 minute.
+    pub fn renewal_interval(mut self, interval: Duration) -> Self {
+        self.timings.renewal_interval = interval;
+        self
+    }
+
+    /// How long a new subscription waits for the speaker's initial event.
+    /// Without it, the speaker can't reach the callback URL, which is
+    /// reported in the [`crate::Diagnostics`]. Defaults to 10 seconds.
+    pub fn initial_notify_timeout(mut self, timeout: Duration) -> Self {
+        self.timings.initial_notify_timeout = timeout;
+        self
+    }
+
+    /// Local address the listener for events binds to. Defaults to all
+    /// interfaces.
+    pub fn callback_bind(mut self, addr: IpAddr) -> Self {
+        self.callback.bind = addr;
+        self
+    }
+
+    /// Ports to try for the listener for events, in order. Defaults to any
+    /// free port.
+    pub fn callback_ports(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.callback.ports = ports;
+        self
+    }
+
+    /// Host that speakers send events to, when they can't reach the
+    /// listener at its local address, e.g. from outside a container.
+    /// Defaults to the bind address, or else the local address of the
+    /// interface that routes to each speaker.
+    pub fn callback_host(mut self, host: impl Into<String>) -> Self {
+        self.callback.host = Some(host.into());
+        self
+    }
+
+    /// Port that speakers send events to, when it is forwarded to the
+    /// listener from another port. Defaults to the port of the listener.
+    pub fn callback_port(mut self, port: u16) -> Self {
+        self.callback.port = Some(port);
+        self
+    }
+
+    /// Delays between attempts to rediscover a lost system. The delay starts
+    /// at `min`, doubles with every failed attempt up to `max`, and is
+    /// randomized. Defaults to 1 second and 1 minute.
+    pub fn rediscovery_backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.timings.rediscovery_min = min;
+        self.timings.rediscovery_max = max.max(min);
+        self
+    }
+
+    /// Number of commands that can be waiting for the controller. Defaults
+    /// to 32.
+    pub fn channel_size(mut self, size: usize) -> Self {
+        self.channel_size = size.max(1);
+        self
+    }
+
+    /// Find the sonos system(s) and start the manager. If no system can be
+    /// found, an error is returned.
+    pub async fn build(self) -> Result<Manager> {
+        let discovery = match self.addresses {
+            Some(addresses) => {
+                let urls = addresses
+                    .iter()
+                    .map(|addr| {
+                        utils::speaker_description_url(addr)
+                            .ok_or_else(|| Error::InvalidAddress(addr.to_owned()))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                if urls.is_empty() {
+                    return Err(Error::InvalidAddress(String::new()));
+                }
+                Discovery::Static(urls)
+            }
+            None => Discovery::Ssdp(self.room),
+        };
+        let cache = self.cache.map(TopologyCache::new);
+        let callback = CallbackServer::start(self.callback).await?;
+
+        let (tx, rx) = mpsc::channel(self.channel_size);
+        let mut controller =
+            Controller::new(rx, discovery, cache, self.aliases, self.timings, callback);
+        controller.init().await?;
+        log::debug!(
+            "Initialized controller with devices:\n{}",
+            controller
+                .systems
+                .iter()
+                .fold(String::new(), |mut acc, system| {
+                    let _ = writeln!(acc, "   {}:", system.household);
+                    for device in system.speakers() {
+                        let _ = writeln!(acc, "     - {}", device.name());
+                    }
+                    acc
+                })
+        );
+
+        let events = controller.events();
+        let controller_handle = tokio::spawn(async move { controller.run().await });
+
+        Ok(Manager {
+            controller_handle,
+            tx,
+            events,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_renewal_period() {
+        let secs = Duration::from_secs;
+        let timings = |renewal_interval, subscription_timeout| Timings {
+            renewal_interval,
+            subscription_timeout,
+            ..Default::default()
+        };
+        assert_eq!(timings(secs(60), secs(300)).renewal_period(), secs(60));
+        assert_eq!(timings(Duration::ZERO, secs(300)).renewal_period(), secs(1));
+        assert_eq!(timings(secs(600), secs(300)).renewal_period(), secs(270));
+    }
+
+    #[test]
+    fn test_rediscovery_delay() {
+        let timings = Timings {
+            rediscovery_min: Duration::from_secs(1),
+            rediscovery_max: Duration::from_secs(60),
+            ..Default::default()
+        };
+        for (failures, full) in [(0, 1), (1, 1), (2, 2), (3, 4), (7, 60), (100, 60)] {
+            let full = Duration::from_secs(full);
+            let delay = timings.rediscovery_delay(failures);
+            assert!(
+                delay >= full / 2 && delay <= full,
+                "{:?} for {}",
+                delay,
+                failures
+            );
+        }
+    }
+}
@@ -0,0 +1,236 @@
+//! On-disk cache of the last known topology of each household, of the zone
+//! aliases, and of the event URLs of the speakers
+
+use crate::{
+    types::{Bonds, HouseholdId, Topology, Uuid},
+    utils::{escape_attribute, extract_zone_group_state, topology_to_xml},
+    Error, Result,
+};
+use log::warn;
+use roxmltree::{Document, Node};
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+/// A file holding the ZoneGroupState of every household, which includes the
+/// location of every speaker, the aliases of zones and the event URLs of the
+/// speakers. Starting from it skips SSDP discovery, and fetching the device
+/// descriptions again to subscribe.
+#[derive(Debug, Clone)]
+pub(crate) struct TopologyCache {
+    path: PathBuf,
+    /// Contents waiting to be written. Saves made meanwhile replace them, so
+    /// only the latest is written.
+    pending: Arc<Mutex<Option<String>>>,
+    /// Held while writing, so writes never interleave
+    writing: Arc<Mutex<()>>,
+}
+
+impl TopologyCache {
+    pub fn new(path: PathBuf) -> TopologyCache {
+        TopologyCache {
+            path,
+            pending: Arc::default(),
+            writing: Arc::default(),
+        }
+    }
+
+    /// Read the cached topology of each household, the aliases and the
+    /// event URLs.
+    pub fn load(
+        &self,
+    ) -> Result<(
+        Vec<(HouseholdId, Topology, Bonds)>,
+        Vec<(String, Uuid)>,
+        EventUrls,
+    )> {
+        let text = fs::read_to_string(&self.path).map_err(|e| Error::CacheError(e.to_string()))?;
+        let (households, aliases, event_urls) = deserialize(&text)?;
+        let households = households
+            .into_iter()
+            .map(|(household, xml)| {
+                let (topology, bonds) = extract_zone_group_state(&xml)?;
+                Ok((household, topology, bonds))
+            })
+            .collect::<Result<_>>()?;
+        Ok((households, aliases, event_urls))
+    }
+
+    /// Replace the cache with the given households, aliases and event URLs.
+    /// The file is written on a blocking thread, so this doesn't wait for the
+    /// disk.
+    pub fn save<'a>(
+        &self,
+        households: impl IntoIterator<Item = (&'a HouseholdId, &'a Topology, &'a Bonds)>,
+        aliases: &[(String, Uuid)],
+        event_urls: &EventUrls,
+    ) {
+        let text = serialize(
+            households.into_iter().map(|(household, topology, bonds)| {
+                (household.as_str(), topology_to_xml(topology, bonds))
+            }),
+            aliases,
+            event_urls,
+        );
+        // A write that hasn't started yet picks up the new contents
+        if self.pending.lock().unwrap().replace(text).is_some() {
+            return;
+        }
+        let cache = self.clone();
+        drop(tokio::task::spawn_blocking(move || {
+            let _writing = cache.writing.lock().unwrap();
+            let Some(text) = cache.pending.lock().unwrap().take() else {
+                return;
+            };
+            cache
+                .write(text)
+                .unwrap_or_else(|err| warn!("Unable to save topology cache: {}", err));
+        }));
+    }
+
+    fn write(&self, text: String) -> Result<()> {
+        // Write then rename so a crash never leaves a partial cache behind
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, text)
+            .and_then(|_| fs::rename(&tmp, &self.path))
+            .map_err(|e| Error::CacheError(e.to_string()))
+    }
+}
+
+/// Event URLs as (service type, URL), by the URL of the description of their
+/// device
+pub(crate) type EventUrls = Vec<(String, Vec<(String, String)>)>;
+
+fn serialize<'a>(
+    households: impl Iterator<Item = (&'a str, String)>,
+    aliases: &[(String, Uuid)],
+    event_urls: &EventUrls,
+) -> String {
+    let mut text = String::from(r#"<SonosManagerCache version="1">"#);
+    for (household, xml) in households {
+        text.push_str(&format!(
+            r#"<Household ID="{}">{}</Household>"#,
+            escape_attribute(household),
+            xml
+        ));
+    }
+    for (alias, uuid) in aliases {
+        text.push_str(&format!(
+            r#"<Alias Name="{}" ID="{}"/>"#,
+            escape_attribute(alias),
+            escape_attribute(uuid)
+        ));
+    }
+    for (device_url, urls) in event_urls {
+        text.push_str(&format!(
+            r#"<Device URL="{}">"#,
+            escape_attribute(device_url)
+        ));
+        for (typ, url) in urls {
+            text.push_str(&format!(
+                r#"<Event Service="{}" URL="{}"/>"#,
+                escape_attribute(typ),
+                escape_attribute(url)
+            ));
+        }
+        text.push_str("</Device>");
+    }
+    text.push_str("</SonosManagerCache>");
+    text
+}
+
+/// Get the household IDs and ZoneGroupState XML, the aliases and the event
+/// URLs from the cache file contents.
+fn deserialize(text: &str) -> Result<(Vec<(HouseholdId, String)>, Vec<(String, Uuid)>, EventUrls)> {
+    let doc = Document::parse(text).map_err(|e| Error::CacheError(e.to_string()))?;
+    let root = doc.root_element();
+    if !root.has_tag_name("SonosManagerCache") || root.attribute("version") != Some("1") {
+        return Err(Error::CacheError("unknown cache format".into()));
+    }
+    let households =
+        root.children()
+            .filter(|n| n.has_tag_name("Household"))
+            .map(|household| {
+                let id = household
+                    .attribute("ID")
+                    .ok_or_else(|| Error::CacheError("household without ID".into()))?;
+                let state = household.children().find(Node::is_element).ok_or_else(|| {
+                    Error::CacheError(format!("no topology for household {}", id))
+                })?;
+                Ok((id.to_owned(), text[state.range()].to_owned()))
+            })
+            .collect::<Result<_>>()?;
+    // Aliases missing a part are dropped rather than failing the whole cache
+    let aliases = root
+        .children()
+        .filter(|n| n.has_tag_name("Alias"))
+        .filter_map(|alias| {
+            Some((
+                alias.attribute("Name")?.into(),
+                alias.attribute("ID")?.into(),
+            ))
+        })
+        .collect();
+    let event_urls = root
+        .children()
+        .filter(|n| n.has_tag_name("Device"))
+        .filter_map(|device| {
+            let urls = device
+                .children()
+                .filter(|n| n.has_tag_name("Event"))
+                .filter_map(|event| {
+                    Some((
+                        event.attribute("Service")?.into(),
+                        event.attribute("URL")?.into(),
+                    ))
+                })
+                .collect();
+            Some((device.attribute("URL")?.into(), urls))
+        })
+        .collect();
+    Ok((households, aliases, event_urls))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_roundtrip() -> Result<()> {
+        let state = r#"<ZoneGroupState><ZoneGroups><ZoneGroup Coordinator="RINCON_1" ID="RINCON_1:0"><ZoneGroupMember UUID="RINCON_1" Location="http://192.168.1.20:1400/xml/device_description.xml" ZoneName="Kid&apos;s &amp; Den"/></ZoneGroup></ZoneGroups></ZoneGroupState>"#;
+        let aliases = vec![
+            ("tv".to_owned(), "RINCON_1".to_owned()),
+            ("Tom & Jerry's".to_owned(), "RINCON_2".to_owned()),
+        ];
+        let event_urls = vec![(
+            "http://192.168.1.20:1400/xml/device_description.xml".to_owned(),
+            vec![(
+                "AVTransport".to_owned(),
+                "http://192.168.1.20:1400/MediaRenderer/AVTransport/Event".to_owned(),
+            )],
+        )];
+        let text = serialize(
+            [
+                ("Sonos_abc", state.to_owned()),
+                ("Sonos_\"x\"", state.to_owned()),
+            ]
+            .into_iter(),
+            &aliases,
+            &event_urls,
+        );
+        let (households, cached_aliases, cached_event_urls) = deserialize(&text)?;
+        assert_eq!(
+            households,
+            vec![
+                ("Sonos_abc".to_owned(), state.to_owned()),
+                ("Sonos_\"x\"".to_owned(), state.to_owned())
+            ]
+        );
+        assert_eq!(cached_aliases, aliases);
+        assert_eq!(cached_event_urls, event_urls);
+        assert!(deserialize("<SomethingElse/>").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,232 @@
+//! Browse results kept until the speakers report that they changed
+
+use crate::Result;
+use sonor::Speaker;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+/// Items of a container, valid as long as the update IDs the speakers report
+/// for it don't change.
+#[derive(Debug)]
+pub(crate) struct Cached<T> {
+    state: Mutex<CachedState<T>>,
+}
+
+#[derive(Debug)]
+struct CachedState<T> {
+    /// Last update ID seen for each source, e.g. "FV:2" in ContainerUpdateIDs
+    update_ids: Vec<(String, String)>,
+    /// Bumped on every change, so fetches that raced with one are not kept
+    generation: u64,
+    items: Option<Arc<Vec<T>>>,
+    /// Whether events keep the items up-to-date. Without them, items are
+    /// fetched every time.
+    live: bool,
+}
+
+impl<T> Default for Cached<T> {
+    fn default() -> Self {
+        Cached {
+            state: Mutex::new(CachedState {
+                update_ids: Vec::new(),
+                generation: 0,
+                items: None,
+                live: false,
+            }),
+        }
+    }
+}
+
+impl<T> Cached<T> {
+    /// Get the cached items, fetching them if the container changed since
+    /// they were cached. Items are only kept while the cache is live.
+    pub async fn get_or_fetch<F, Fut>(&self, fetch: F) -> Result<Arc<Vec<T>>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Vec<T>>>,
+    {
+        let generation = {
+            let state = self.lock();
+            if let Some(ref items) = state.items {
+                return Ok(items.clone());
+            }
+            state.generation
+        };
+        let items = Arc::new(fetch().await?);
+        let mut state = self.lock();
+        if state.live && state.generation == generation {
+            state.items = Some(items.clone());
+        }
+        Ok(items)
+    }
+
+    /// Drop the items, e.g. after changing them, so that they are fetched
+    /// again even if the event for the change is late.
+    pub fn invalidate(&self) {
+        let mut state = self.lock();
+        state.generation += 1;
+        state.items = None;
+    }
+
+    /// Mark whether events keep the cache up-to-date, e.g. once the first
+    /// event of a subscription arrived, or after it was lost.
+    pub fn set_live(&self, live: bool) {
+        let mut state = self.lock();
+        if !live {
+            state.generation += 1;
+            state.items = None;
+        }
+        state.live = live;
+    }
+
+    /// Record an update ID reported by a speaker, dropping the cached items
+    /// if it changed. Returns whether the container changed since we first
+    /// heard about it.
+    pub fn update(&self, source: &str, update_id: &str) -> bool {
+        let mut state = self.lock();
+        let changed = match state.update_ids.iter_mut().find(|(s, _)| s == source) {
+            Some((_, id)) if id == update_id => return false,
+            Some((_, id)) => {
+                *id = update_id.to_owned();
+                true
+            }
+            None => {
+                state
+                    .update_ids
+                    .push((source.to_owned(), update_id.to_owned()));
+                false
+            }
+        };
+        state.generation += 1;
+        state.items = None;
+        changed
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CachedState<T>> {
+        // The state is always consistent, so a panic elsewhere doesn't matter
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A favorite or playlist
+#[derive(Debug, Clone)]
+pub(crate) struct ContentItem {
+    pub title: String,
+    pub uri: Option<String>,
+    pub metadata: Option<String>,
+}
+
+/// Browse a container, keeping what we need of each item
+pub(crate) async fn browse(speaker: &Speaker, id: &str) -> Result<Vec<ContentItem>> {
+    Ok(speaker
+        .browse(id, 0, 0)
+        .await?
+        .iter()
+        .map(|c| ContentItem {
+            title: c.title().to_owned(),
+            uri: c.uri().map(ToString::to_string),
+            metadata: c.metadata().map(ToString::to_string),
+        })
+        .collect())
+}
+
+/// Containers shared by every speaker of a household
+#[derive(Debug, Default)]
+pub(crate) struct ContentCache {
+    pub favorites: Cached<ContentItem>,
+    pub playlists: Cached<ContentItem>,
+}
+
+/// A change to a container, from a ContentDirectory event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ContentChange {
+    Favorites,
+    Playlists,
+    Queue,
+}
+
+/// Find the containers that a ContentDirectory event reports update IDs for,
+/// as (source, update ID, container).
+pub(crate) fn content_updates(
+    state_vars: &[(String, String)],
+) -> Vec<(String, String, ContentChange)> {
+    let mut updates = Vec::new();
+    for (name, value) in state_vars {
+        match name.as_str() {
+            // Pairs of container ID and update ID, e.g. "FV:2,12,Q:0,3"
+            "ContainerUpdateIDs" => {
+                let values = value.split(',').collect::<Vec<_>>();
+                for pair in values.chunks_exact(2) {
+                    let change = match pair[0] {
+                        "FV:2" => ContentChange::Favorites,
+                        "SQ:" => ContentChange::Playlists,
+                        "Q:0" => ContentChange::Queue,
+                        _ => continue,
+                    };
+                    updates.push((pair[0].to_owned(), pair[1].to_owned(), change));
+                }
+            }
+            "FavoritesUpdateID" => {
+                updates.push((name.clone(), value.clone(), ContentChange::Favorites))
+            }
+            "SavedQueuesUpdateID" => {
+                updates.push((name.clone(), value.clone(), ContentChange::Playlists))
+            }
+            _ => (),
+        }
+    }
+    updates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cached() -> Result<()> {
+        let cached = Cached::default();
+        // Not kept until events keep it up-to-date
+        assert_eq!(*cached.get_or_fetch(|| async { Ok(vec![0]) }).await?, [0]);
+        assert_eq!(*cached.get_or_fetch(|| async { Ok(vec![1]) }).await?, [1]);
+        cached.set_live(true);
+        cached.update("FV:2", "1");
+        assert_eq!(*cached.get_or_fetch(|| async { Ok(vec![1]) }).await?, [1]);
+        // Same update ID: still cached
+        assert!(!cached.update("FV:2", "1"));
+        assert!(!cached.update("FavoritesUpdateID", "7"));
+        assert_eq!(*cached.get_or_fetch(|| async { Ok(vec![2]) }).await?, [2]);
+        assert!(!cached.update("FavoritesUpdateID", "7"));
+        assert_eq!(*cached.get_or_fetch(|| async { Ok(vec![3]) }).await?, [2]);
+        // New update ID: fetched again
+        assert!(cached.update("FV:2", "2"));
+        assert_eq!(*cached.get_or_fetch(|| async { Ok(vec![4]) }).await?, [4]);
+        // Subscription lost: fetched every time
+        cached.set_live(false);
+        assert_eq!(*cached.get_or_fetch(|| async { Ok(vec![5]) }).await?, [5]);
+        assert_eq!(*cached.get_or_fetch(|| async { Ok(vec![6]) }).await?, [6]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_content_updates() {
+        let vars = [
+            (
+                "ContainerUpdateIDs".to_owned(),
+                "FV:2,12,S:,4,Q:0,3".to_owned(),
+            ),
+            ("SavedQueuesUpdateID".to_owned(), "RINCON_1,9".to_owned()),
+        ];
+        let updates = content_updates(&vars)
+            .into_iter()
+            .map(|(_, id, change)| (id, change))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            updates,
+            vec![
+                ("12".to_owned(), ContentChange::Favorites),
+                ("3".to_owned(), ContentChange::Queue),
+                ("RINCON_1,9".to_owned(), ContentChange::Playlists),
+            ]
+        );
+    }
+}
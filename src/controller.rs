@@ -2,41 +2,190 @@
 
 //! API backend for tracking sonos system topology
 
+pub(crate) mod alarmaction;
+pub(crate) mod bulkaction;
+mod sceneaction;
+mod sleeptimer;
 pub(crate) mod zoneaction;
 
 use crate::{
-    subscriber::Subscriber,
-    types::{AVStatus, CmdReceiver, Event, EventReceiver, Topology, Uuid, ZoneActionResponder},
-    Command, Error, Result,
+    alarm::{Alarm, ALARM_CLOCK},
+    builder::Timings,
+    cache::TopologyCache,
+    contentcache::{content_updates, Cached, ContentCache, ContentChange},
+    gena::CallbackServer,
+    position::PositionTracker,
+    scene::Scene,
+    snapshot::{capture_group, restore, HouseSnapshot, HouseSpeakers, HouseholdSnapshot},
+    subscriber::{ServiceSubscription, Subscriber},
+    types::{
+        AVStatus, Bonds, BulkResponder, CmdReceiver, Diagnostics, Event, EventReceiver,
+        GroupRenderingState, HouseholdId, Position, RenderingState, RenderingStatus, Response,
+        RestoreResponder, Satellite, SceneResponder, ServiceEvent, ShutdownResponder, StateVar,
+        SubscriptionStatus, Topology, Uuid, ZoneActionResponder, ZoneEvent, ZoneName,
+    },
+    utils::extract_zone_group_state,
+    Command, Error, Result, Track,
 };
-use zoneaction::ZoneAction;
+use alarmaction::AlarmAction;
+use bulkaction::BulkAction;
+use sleeptimer::SleepFades;
+use zoneaction::{respond, ActionTask, Detach, ZoneAction};
 
-use futures_util::{stream::SelectAll, FutureExt as _};
+use futures_util::{
+    future::{join_all, Shared},
+    stream::{FuturesUnordered, SelectAll},
+    FutureExt as _,
+};
 use log::{debug, info, warn};
 use sonor::{
-    discover_one, find,
-    urns::{AV_TRANSPORT, ZONE_GROUP_TOPOLOGY},
-    Speaker,
+    discover, find,
+    rupnp::{http::Uri, Device},
+    urns::{
+        AV_TRANSPORT, CONTENT_DIRECTORY, DEVICE_PROPERTIES, GROUP_RENDERING_CONTROL,
+        RENDERING_CONTROL, ZONE_GROUP_TOPOLOGY,
+    },
+    Speaker, SpeakerInfo, URN,
 };
+use std::collections::HashMap;
 use std::fmt::Write as _;
+use std::mem;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::select;
-use tokio_stream::{wrappers::WatchStream, StreamExt as _};
+use tokio::{
+    select,
+    sync::{broadcast, mpsc},
+    task::JoinHandle,
+    time::{self, Instant},
+};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt as _};
 
 #[derive(Debug)]
 pub(crate) struct SpeakerData {
     pub speaker: Speaker,
     transport_subscription: Option<Subscriber>,
     pub transport_data: AVStatus,
+    rendering_subscription: Option<Subscriber>,
+    pub rendering_data: RenderingState,
+    group_rendering_subscription: Option<Subscriber>,
+    pub group_rendering_data: GroupRenderingState,
+    content_subscription: Option<Subscriber>,
+    /// Last status of subscriptions that could not be made again, by
+    /// service type
+    lost_subscriptions: HashMap<String, SubscriptionStatus>,
+    /// Favorites and playlists of the household
+    pub content: Arc<ContentCache>,
+    pub queue: Arc<Cached<Track>>,
+    pub position: Arc<PositionTracker>,
 }
 
+/// Services of every speaker we keep track of through events
+const SPEAKER_SERVICES: [&URN; 4] = [
+    AV_TRANSPORT,
+    RENDERING_CONTROL,
+    GROUP_RENDERING_CONTROL,
+    CONTENT_DIRECTORY,
+];
+
 impl SpeakerData {
-    fn new(speaker: Speaker) -> SpeakerData {
+    fn new(speaker: Speaker, content: Arc<ContentCache>) -> SpeakerData {
         SpeakerData {
             speaker,
             transport_data: Default::default(),
             transport_subscription: Default::default(),
+            rendering_data: Default::default(),
+            rendering_subscription: Default::default(),
+            group_rendering_data: Default::default(),
+            group_rendering_subscription: Default::default(),
+            content_subscription: Default::default(),
+            lost_subscriptions: Default::default(),
+            content,
+            queue: Default::default(),
+            position: Default::default(),
+        }
+    }
+
+    /// Where the subscription to a speaker service is kept
+    fn subscription_mut(&mut self, service: &str) -> Option<&mut Option<Subscriber>> {
+        match service {
+            "AVTransport" => Some(&mut self.transport_subscription),
+            "RenderingControl" => Some(&mut self.rendering_subscription),
+            "GroupRenderingControl" => Some(&mut self.group_rendering_subscription),
+            "ContentDirectory" => Some(&mut self.content_subscription),
+            _ => None,
+        }
+    }
+
+    fn subscription(&self, service: &str) -> Option<&Subscriber> {
+        match service {
+            "AVTransport" => self.transport_subscription.as_ref(),
+            "RenderingControl" => self.rendering_subscription.as_ref(),
+            "GroupRenderingControl" => self.group_rendering_subscription.as_ref(),
+            "ContentDirectory" => self.content_subscription.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Status of the subscription to every service we track, including the
+    /// ones that could not be made
+    fn subscription_statuses(&self) -> Vec<SubscriptionStatus> {
+        SPEAKER_SERVICES
+            .iter()
+            .map(|urn| match self.subscription(urn.typ()) {
+                Some(sub) => sub.status(),
+                None => self
+                    .lost_subscriptions
+                    .get(urn.typ())
+                    .cloned()
+                    .unwrap_or_else(|| SubscriptionStatus {
+                        service: urn.typ().to_owned(),
+                        uuid: Some(self.speaker.uuid().to_owned()),
+                        last_error: Some("Unable to subscribe".into()),
+                        ..Default::default()
+                    }),
+            })
+            .collect()
+    }
+
+    fn subscriptions_mut(&mut self) -> impl Iterator<Item = &mut Subscriber> {
+        self.transport_subscription
+            .iter_mut()
+            .chain(self.rendering_subscription.iter_mut())
+            .chain(self.group_rendering_subscription.iter_mut())
+            .chain(self.content_subscription.iter_mut())
+    }
+
+    /// Subscribe to events from every speaker service we track
+    fn subscribe(
+        &mut self,
+        timings: &Timings,
+        callback: &Arc<CallbackServer>,
+    ) -> Vec<EventReceiver> {
+        let mut receivers = Vec::new();
+        for urn in SPEAKER_SERVICES {
+            if let Some((sub, rx)) = get_subscription(&self.speaker, urn, timings, callback) {
+                if let Some(slot) = self.subscription_mut(urn.typ()) {
+                    *slot = Some(sub);
+                    receivers.push(rx);
+                }
+            }
         }
+        receivers
+    }
+
+    /// Get the queue of this speaker, from the cache if it hasn't changed.
+    pub async fn queue(&self) -> Result<Vec<Track>> {
+        let queue = self
+            .queue
+            .get_or_fetch(|| async { self.speaker.queue().await.map_err(Error::from) })
+            .await?;
+        Ok(queue.to_vec())
+    }
+
+    /// Get the playback position of this speaker, extrapolated from the last
+    /// time it was asked for.
+    pub async fn position(&self) -> Result<Position> {
+        self.position.position(&self.speaker).await
     }
 
     /// Get the current track number for this speaker. Take value from cache if
@@ -61,51 +210,80 @@ impl SpeakerData {
     }
 }
 
-#[derive(Debug, Default)]
+/// The speakers and topology of a single sonos household.
+#[derive(Debug)]
 pub(crate) struct System {
+    pub household: HouseholdId,
     pub speakerdata: Vec<SpeakerData>,
     topology: Topology,
+    bonds: Bonds,
+    content: Arc<ContentCache>,
+    alarms: Arc<Cached<Alarm>>,
     queued_event_handles: Vec<EventReceiver>,
     topology_subscription: Option<Subscriber>,
-    seed: Option<String>,
+    alarm_subscription: Option<Subscriber>,
+    /// The topology came from the cache, and its speakers are still being
+    /// loaded and checked
+    needs_validation: bool,
+    /// UUIDs of the speakers of the cached topology that are still loading
+    loading: Vec<Uuid>,
+    timings: Timings,
+    /// Listener for the events of the subscriptions
+    callback: Arc<CallbackServer>,
 }
 
 impl System {
-    fn new(seed: Option<String>) -> System {
+    fn new(household: HouseholdId, timings: Timings, callback: Arc<CallbackServer>) -> System {
         System {
-            seed,
-            ..Default::default()
+            household,
+            speakerdata: Vec::new(),
+            topology: Vec::new(),
+            bonds: Vec::new(),
+            content: Arc::default(),
+            alarms: Arc::default(),
+            queued_event_handles: Vec::new(),
+            topology_subscription: None,
+            alarm_subscription: None,
+            needs_validation: false,
+            loading: Vec::new(),
+            timings,
+            callback,
         }
     }
 
-    async fn discover(&mut self) -> Result<()> {
-        let topology = if let Some(ref room) = self.seed {
-            debug!("Looking for seed: {}", room);
-            find(room, Duration::from_secs(5))
-                .await?
-                .ok_or(Error::ZoneDoesNotExist)?
-                .zone_group_state()
-                .await?
-        } else {
-            discover_one(Duration::from_secs(5))
-                .await?
-                .zone_group_state()
-                .await?
-        };
-
-        self.update_from_topology(topology).await?;
-        self.update_topology_subscription()?;
-        Ok(())
-    }
-
     /// Get a reference to the vector of speakers.
     pub fn speakers(&self) -> Vec<&Speaker> {
         self.speakerdata.iter().map(|sd| &sd.speaker).collect()
     }
 
-    /// Update speakers and topology
-    async fn update_from_topology(&mut self, topology: Topology) -> Result<()> {
-        let infos = topology.iter().flat_map(|(_, infos)| infos);
+    /// Names of the logical rooms, one per bonded set
+    fn zone_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for sd in self.speakerdata.iter() {
+            if !names
+                .iter()
+                .any(|n| n.eq_ignore_ascii_case(sd.speaker.name()))
+            {
+                names.push(sd.speaker.name().to_owned());
+            }
+        }
+        names
+    }
+
+    /// Whether the topology events of this system come from the speaker with
+    /// the given UUID.
+    fn is_topology_source(&self, uuid: &str) -> bool {
+        self.topology_subscription
+            .as_ref()
+            .and_then(|sub| sub.uuid.as_deref())
+            .is_some_and(|sub_uuid| sub_uuid.eq_ignore_ascii_case(uuid))
+    }
+
+    /// Update speakers and topology. Satellites of bonded sets are not kept
+    /// as speakers, since they can't be controlled on their own. Returns the
+    /// speakers that are new, to be loaded and added.
+    fn update_from_topology(&mut self, topology: Topology, bonds: Bonds) -> Vec<SpeakerInfo> {
+        let infos = controllable(&topology, &bonds);
 
         // Drop speakers and subscriptions that are no longer in the topology
         // Todo: (speakers, av_transport_data, subscription) should probably be
@@ -116,7 +294,8 @@ impl System {
                 .any(|info| info.uuid().eq_ignore_ascii_case(sd.speaker.uuid()))
         });
 
-        // Check if we have any new speakers in the system and add them. Update speaker info otherwise
+        // Check if we have any new speakers in the system. Update speaker info otherwise
+        let mut new_infos = Vec::new();
         for info in infos {
             if let Some(speakerdata) = self
                 .speakerdata
@@ -126,25 +305,32 @@ impl System {
                 speakerdata.speaker.set_name(info.name().into());
                 speakerdata.speaker.set_location(info.location().into());
             } else {
-                let new_speaker = Speaker::from_speaker_info(info)
-                    .await?
-                    .ok_or(sonor::Error::SpeakerNotIncludedInOwnZoneGroupState)?;
-
-                // Subscribe to AV Transport events on new speakers
-                let mut new_speakerdata = SpeakerData::new(new_speaker);
-                if let Some((device_sub, rx)) =
-                    get_av_transport_subscription(&new_speakerdata.speaker).await
-                {
-                    new_speakerdata.transport_subscription = Some(device_sub);
-                    self.queued_event_handles.push(rx);
-                }
-                debug!("Adding UUID: {}", info.uuid());
-                self.speakerdata.push(new_speakerdata);
+                new_infos.push(info.clone());
             }
         }
 
         self.topology = topology;
-        Ok(())
+        self.bonds = bonds;
+        new_infos
+    }
+
+    /// Add speakers and subscribe to events from the services we track on
+    /// them
+    fn add_speakers(&mut self, speakers: Vec<Speaker>) {
+        for speaker in speakers {
+            if self
+                .speakerdata
+                .iter()
+                .any(|sd| sd.speaker.uuid().eq_ignore_ascii_case(speaker.uuid()))
+            {
+                continue;
+            }
+            debug!("Adding UUID: {}", speaker.uuid());
+            let mut speakerdata = SpeakerData::new(speaker, self.content.clone());
+            self.queued_event_handles
+                .extend(speakerdata.subscribe(&self.timings, &self.callback));
+            self.speakerdata.push(speakerdata);
+        }
     }
 
     fn update_topology_subscription(&mut self) -> Result<()> {
@@ -155,7 +341,8 @@ impl System {
         // because the last speaker went offline.. and we don't know.
         // There's a chance we can recover quickly if we find an extant speaker.
         let i = fastrand::usize(..self.speakerdata.len());
-        let device = self.speakerdata[i].speaker.device();
+        let speaker = &self.speakerdata[i].speaker;
+        let device = speaker.device();
         let (service, url) = device
             .find_service(ZONE_GROUP_TOPOLOGY)
             .ok_or(sonor::Error::MissingServiceForUPnPAction {
@@ -164,11 +351,159 @@ impl System {
                 payload: String::new(),
             })
             .map(|service| (service.clone(), device.url().clone()))?;
-        let mut sub = Subscriber::new(service, url, None);
+        let uuid = Some(speaker.uuid().to_owned());
+        let mut sub = Subscriber::new(service, url, uuid, &self.timings, &self.callback);
         self.queued_event_handles.push(sub.subscribe()?);
         self.topology_subscription = Some(sub);
+        self.update_alarm_subscription();
         Ok(())
     }
+
+    /// Subscribe to alarm changes of the household on a random speaker
+    fn update_alarm_subscription(&mut self) {
+        // Until the first event of the new subscription
+        self.alarms.set_live(false);
+        if self.speakerdata.is_empty() {
+            return;
+        }
+        let i = fastrand::usize(..self.speakerdata.len());
+        let speaker = &self.speakerdata[i].speaker;
+        match get_subscription(speaker, ALARM_CLOCK, &self.timings, &self.callback) {
+            Some((sub, rx)) => {
+                self.queued_event_handles.push(rx);
+                self.alarm_subscription = Some(sub);
+            }
+            None => {
+                info!("Unable to subscribe to alarms of {}", self.household);
+                self.alarm_subscription = None;
+            }
+        }
+    }
+
+    fn subscriptions_mut(&mut self) -> impl Iterator<Item = &mut Subscriber> {
+        self.topology_subscription
+            .iter_mut()
+            .chain(self.alarm_subscription.iter_mut())
+            .chain(
+                self.speakerdata
+                    .iter_mut()
+                    .flat_map(SpeakerData::subscriptions_mut),
+            )
+    }
+
+    /// Stop caching content of a speaker whose ContentDirectory events were
+    /// lost, until events arrive again. Favorites and playlists stay cached
+    /// while another speaker's events keep them up-to-date.
+    fn content_lost(&self, uuid: &str) {
+        let mut others_live = false;
+        for sd in self.speakerdata.iter() {
+            if sd.speaker.uuid().eq_ignore_ascii_case(uuid) {
+                sd.queue.set_live(false);
+            } else if sd
+                .content_subscription
+                .as_ref()
+                .is_some_and(|sub| sub.status().subscribed)
+            {
+                others_live = true;
+            }
+        }
+        if !others_live {
+            self.content.favorites.set_live(false);
+            self.content.playlists.set_live(false);
+        }
+    }
+
+    /// Subscribe again to a service of a speaker after the subscription was
+    /// lost, with the speaker loaded again.
+    fn resubscribe(&mut self, uuid: &str, urn: &URN, speaker: Speaker) {
+        let Some(speakerdata) = self
+            .speakerdata
+            .iter_mut()
+            .find(|s| s.speaker.uuid().eq_ignore_ascii_case(uuid))
+        else {
+            return;
+        };
+        // The speaker still exists! Resubscribe
+        debug!("Recreating speaker {}. Did it's IP change?", speaker.name());
+        let typ = urn.typ();
+        let previous = match speakerdata.subscription(typ) {
+            Some(sub) => Some(sub.status()),
+            None => speakerdata.lost_subscriptions.remove(typ),
+        };
+        let Some(slot) = speakerdata.subscription_mut(typ) else {
+            return;
+        };
+        match get_subscription(&speaker, urn, &self.timings, &self.callback) {
+            Some((sub, rx)) => {
+                if let Some(ref previous) = previous {
+                    sub.carry_over(previous);
+                }
+                *slot = Some(sub);
+                self.queued_event_handles.push(rx);
+            }
+            None => {
+                *slot = None;
+                if let Some(mut status) = previous {
+                    status.subscribed = false;
+                    status.sid = None;
+                    status.last_error = Some("Unable to subscribe again".into());
+                    speakerdata
+                        .lost_subscriptions
+                        .insert(typ.to_owned(), status);
+                }
+            }
+        }
+    }
+}
+
+/// Number of client events kept for slow receivers
+const EVENT_CAPACITY: usize = 64;
+
+/// An action on every group, like restoring a house snapshot, that actions
+/// queued after it wait for
+type HouseAction = Shared<ActionTask>;
+
+/// Runs actions one after the other in a background task
+#[derive(Debug)]
+struct ActionQueue {
+    tx: mpsc::UnboundedSender<ActionTask>,
+    handle: JoinHandle<()>,
+}
+
+impl ActionQueue {
+    /// Start a queue, running its actions once the house action is done
+    fn new(after: Option<HouseAction>) -> ActionQueue {
+        let (tx, mut rx) = mpsc::unbounded_channel::<ActionTask>();
+        let handle = tokio::spawn(async move {
+            if let Some(after) = after {
+                after.await;
+            }
+            while let Some(task) = rx.recv().await {
+                task.await;
+            }
+        });
+        ActionQueue { tx, handle }
+    }
+
+    fn push(&self, task: ActionTask) {
+        // The task only ends once we drop the sender
+        let _ = self.tx.send(task);
+    }
+
+    /// Run the remaining actions and stop
+    async fn finish(self) {
+        drop(self.tx);
+        let _ = self.handle.await;
+    }
+}
+
+/// How the controller finds the speakers of the sonos system(s)
+#[derive(Debug, Clone)]
+pub(crate) enum Discovery {
+    /// Use SSDP, optionally looking only for the household with a certain room
+    Ssdp(Option<String>),
+    /// Contact speakers at known device description URLs, bypassing SSDP
+    Static(Vec<Uri>),
 }
 
 #[derive(Debug)]
@@ -176,7 +511,42 @@ impl System {
 /// so it can perform actions using the appropriate coordinating speakers.
 /// It is constructed and then moved into a thread/task.
 pub(crate) struct Controller {
-    pub system: System,
+    pub systems: Vec<System>,
+    discovery: Discovery,
+    cache: Option<TopologyCache>,
+    /// User-defined zone names, mapped to the UUID of the zone's speaker.
+    /// Kept in the cache, if there is one.
+    aliases: Vec<(String, Uuid)>,
+    scenes: Vec<Scene>,
+    timings: Timings,
+    /// Listener for the events of all subscriptions
+    callback: Arc<CallbackServer>,
+    rediscovery_attempts: u32,
+    rediscovery_failures: u32,
+    /// Failed rediscovery attempts since the last success, for backoff
+    consecutive_failures: u32,
+    /// Discovery of lost systems running in the background, and when it
+    /// started
+    rediscovery: Option<(Instant, JoinHandle<Result<LoadedHouseholds>>)>,
+    /// No rediscovery before this, so a powered-off system isn't flooded
+    /// with requests
+    next_rediscovery: Instant,
+    /// Speakers loaded in the background, for the run loop to add
+    loaded_tx: mpsc::UnboundedSender<Loaded>,
+    loaded_rx: mpsc::UnboundedReceiver<Loaded>,
+    /// Commands waiting for speakers of the cache that are still loading
+    waiting: Vec<Command>,
+    /// Set once a client asked to shut down, to answer when done
+    shutdown: Option<ShutdownResponder>,
+    /// Queues of actions running concurrently, one per coordinator, so that
+    /// actions on a zone run in order without holding up other zones
+    action_queues: HashMap<Uuid, ActionQueue>,
+    /// The last action on every group, which later actions wait for
+    house_action: Option<HouseAction>,
+    /// Events for clients, see `Manager::events`
+    events: broadcast::Sender<ZoneEvent>,
+    /// Fade-outs of sleep timers, shared with the actions that set them
+    sleep_fades: Arc<SleepFades>,
     rx: CmdReceiver,
 }
 
@@ -184,31 +554,384 @@ impl Controller {
     /// Make a new controller -- an "actor" that will handle maintenance of
     /// the sonos system state and dispatch commands to groups of speakers.
     ///
-    /// If there are multiple sonos systems on the network, we can specify that
-    /// we want the discovered system to have a speaker with certain name.
-    /// Otherwise, the first speaker found will define the system and be used
-    /// to build the system topology.
-    pub fn new(rx: CmdReceiver, seed_room: Option<String>) -> Self {
-        let system = System::new(seed_room);
-        Controller { system, rx }
+    /// Every sonos household found on the network is controlled, unless we
+    /// specify that we want only the household with a speaker with a certain
+    /// name. If speaker addresses are given, only the households of those
+    /// speakers are controlled and SSDP is never used.
+    ///
+    /// With a cache, the controller first tries to start from the topology of
+    /// the last run, and keeps the aliases added since. The given aliases
+    /// take precedence over the cached ones.
+    pub fn new(
+        rx: CmdReceiver,
+        discovery: Discovery,
+        cache: Option<TopologyCache>,
+        aliases: Vec<(String, Uuid)>,
+        timings: Timings,
+        callback: Arc<CallbackServer>,
+    ) -> Self {
+        let (loaded_tx, loaded_rx) = mpsc::unbounded_channel();
+        Controller {
+            systems: Vec::new(),
+            discovery,
+            cache,
+            aliases,
+            scenes: Vec::new(),
+            timings,
+            callback,
+            rediscovery_attempts: 0,
+            rediscovery_failures: 0,
+            consecutive_failures: 0,
+            rediscovery: None,
+            next_rediscovery: Instant::now(),
+            loaded_tx,
+            loaded_rx,
+            waiting: Vec::new(),
+            shutdown: None,
+            action_queues: HashMap::new(),
+            house_action: None,
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            sleep_fades: Arc::default(),
+            rx,
+        }
     }
 
+    /// Discover households and (re)build the systems that need it. Returns an
+    /// error if any known system could not be recovered.
     pub async fn init(&mut self) -> Result<()> {
-        self.system.discover().await.map_err(|e| {
-            info!("Unable to discover system: {}", e);
-            e
-        })?;
-        self.system.update_topology_subscription().map_err(|e| {
-            info!("Unable to get topology subscription: {}", e);
-            e
-        })
+        if self.systems.is_empty() && self.cache.is_some() {
+            match self.init_from_cache().await {
+                Ok(_) => return Ok(()),
+                Err(err) => {
+                    info!("Unable to start from cache: {}", err);
+                    self.systems.clear();
+                }
+            }
+        }
+        let households = discover_households(&self.discovery, self.timings.discovery_timeout)
+            .await
+            .map_err(|e| {
+                info!("Unable to discover system: {}", e);
+                e
+            })?;
+        self.add_households(load_households(households).await)
+    }
+
+    /// (Re)build the systems of discovered households that need it, with
+    /// their speakers loaded. Returns an error if any known system could not
+    /// be recovered.
+    fn add_households(&mut self, households: LoadedHouseholds) -> Result<()> {
+        for (household, topology, bonds, speakers) in households {
+            let system = match self.systems.iter().position(|s| s.household == household) {
+                Some(i) if self.systems[i].topology_subscription.is_some() => continue,
+                Some(i) => &mut self.systems[i],
+                None => {
+                    debug!("Adding household: {}", household);
+                    let callback = self.callback.clone();
+                    self.systems
+                        .push(System::new(household, self.timings, callback));
+                    self.systems.last_mut().unwrap()
+                }
+            };
+            // Speakers that couldn't be loaded are left out
+            system.update_from_topology(topology, bonds);
+            system.add_speakers(speakers);
+            // Systems that fail are retried with the lost ones
+            if let Err(err) = system.update_topology_subscription() {
+                info!("Unable to get topology subscription: {}", err);
+            }
+        }
+        self.save_cache();
+        match self
+            .systems
+            .iter()
+            .find(|s| s.topology_subscription.is_none())
+        {
+            Some(system) => Err(Error::HouseholdNotFound(system.household.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Build the systems from the cached topologies. Their speakers are
+    /// loaded and checked in the background by the run loop, so nothing is
+    /// fetched from the network here.
+    async fn init_from_cache(&mut self) -> Result<()> {
+        let (households, aliases, event_urls) = self
+            .cache
+            .as_ref()
+            .ok_or(Error::CacheError("no cache".into()))?
+            .load()?;
+        for (device_url, urls) in event_urls {
+            self.callback.add_event_urls(device_url, urls);
+        }
+        for (alias, uuid) in aliases {
+            if !self
+                .aliases
+                .iter()
+                .any(|(a, _)| a.eq_ignore_ascii_case(&alias))
+            {
+                self.aliases.push((alias, uuid));
+            }
+        }
+        if households.is_empty() {
+            return Err(Error::CacheError("cache is empty".into()));
+        }
+        for (household, topology, bonds) in households {
+            debug!("Restoring household {} from cache", household);
+            let mut system = System::new(household, self.timings, self.callback.clone());
+            system.topology = topology;
+            system.bonds = bonds;
+            system.needs_validation = true;
+            self.systems.push(system);
+        }
+        Ok(())
+    }
+
+    /// Load the speakers of the cached systems in the background, and ask
+    /// the first that answers for the current topology. Each speaker is
+    /// added as soon as it's loaded, so one that doesn't answer holds up
+    /// only the commands for it.
+    fn start_validation(&mut self) {
+        for system in self.systems.iter_mut().filter(|s| s.needs_validation) {
+            let infos = controllable(&system.topology, &system.bonds)
+                .cloned()
+                .collect::<Vec<_>>();
+            system.loading = infos.iter().map(|info| info.uuid().to_owned()).collect();
+            let (household, tx) = (system.household.clone(), self.loaded_tx.clone());
+            drop(tokio::spawn(async move {
+                let mut loading = infos
+                    .iter()
+                    .map(|info| async move { (info, Speaker::from_speaker_info(info).await) })
+                    .collect::<FuturesUnordered<_>>();
+                let mut validated = false;
+                while let Some((info, result)) = loading.next().await {
+                    let speaker = loaded_speaker(info, result);
+                    if let (false, Some(speaker)) = (validated, &speaker) {
+                        if let Some(state) = household_topology([speaker]).await {
+                            validated = true;
+                            let _ = tx.send(Loaded::Validated(household.clone(), Some(state)));
+                        }
+                    }
+                    let uuid = info.uuid().to_owned();
+                    let _ = tx.send(Loaded::Speaker(household.clone(), uuid, speaker));
+                }
+                if !validated {
+                    let _ = tx.send(Loaded::Validated(household, None));
+                }
+            }));
+        }
+    }
+
+    /// Bring the topology of a cached system up to date. Systems whose
+    /// speakers didn't answer are stale and will be rediscovered.
+    fn finish_validation(&mut self, household: HouseholdId, state: Option<(Topology, Bonds)>) {
+        let Some(system) = self.systems.iter_mut().find(|s| s.household == household) else {
+            return;
+        };
+        system.needs_validation = false;
+        let Some((topology, bonds)) = state else {
+            info!("Cached topology for {} is stale", system.household);
+            return;
+        };
+        let mut new = system.update_from_topology(topology, bonds);
+        // Speakers of the cache still loading are added once they are
+        new.retain(|info| !system.loading.iter().any(|uuid| uuid == info.uuid()));
+        if let Err(err) = system.update_topology_subscription() {
+            info!("Unable to get topology subscription: {}", err);
+        }
+        self.load_in_background(household, new);
+        self.save_cache();
+    }
+
+    fn save_cache(&self) {
+        if let Some(ref cache) = self.cache {
+            cache.save(
+                self.systems
+                    .iter()
+                    .map(|s| (&s.household, &s.topology, &s.bonds)),
+                &self.aliases,
+                &self.callback.known_event_urls(),
+            );
+        }
+    }
+
+    /// Look for lost systems in the background, keeping count of attempts.
+    /// The systems that are still there are served meanwhile.
+    fn start_rediscovery(&mut self) {
+        info!("Lost system. Rediscovering...");
+        self.rediscovery_attempts += 1;
+        let discovery = self.discovery.clone();
+        let timeout = self.timings.discovery_timeout;
+        // The speakers of systems that are still there aren't loaded again
+        let healthy = self
+            .systems
+            .iter()
+            .filter(|s| s.topology_subscription.is_some())
+            .map(|s| s.household.clone())
+            .collect::<Vec<_>>();
+        let task = tokio::spawn(async move {
+            let households = discover_households(&discovery, timeout)
+                .await?
+                .into_iter()
+                .filter(|(household, ..)| !healthy.contains(household))
+                .collect();
+            Ok(load_households(households).await)
+        });
+        self.rediscovery = Some((Instant::now(), task));
+    }
+
+    /// Rebuild the lost systems that rediscovery found, and back off if any
+    /// is still lost
+    fn finish_rediscovery(&mut self, started: Instant, result: Result<LoadedHouseholds>) {
+        let result = match result {
+            Ok(households) => self.add_households(households),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(_) => {
+                info!("  ...success!");
+                self.consecutive_failures = 0;
+            }
+            Err(err) => {
+                info!("  ...failed: {}", err);
+                self.rediscovery_failures += 1;
+                self.consecutive_failures += 1;
+                let delay = self.timings.rediscovery_delay(self.consecutive_failures);
+                debug!("Retrying rediscovery in {:?}", delay);
+                self.next_rediscovery = started + delay;
+            }
+        }
+    }
+
+    /// Fetch the device descriptions of new speakers of a household in the
+    /// background. They are added once loaded.
+    fn load_in_background(&self, household: HouseholdId, infos: Vec<SpeakerInfo>) {
+        if infos.is_empty() {
+            return;
+        }
+        let tx = self.loaded_tx.clone();
+        drop(tokio::spawn(async move {
+            let speakers = load_speakers(infos.into_iter()).await;
+            let _ = tx.send(Loaded::Speakers(household, speakers));
+        }));
+    }
+
+    /// Load a speaker again in the background, to subscribe again to a
+    /// service whose subscription was lost. The speaker may have gone
+    /// offline or gotten a new IP, in which case its Device is out of date.
+    fn reload_speaker(&self, uuid: &str, urn: &URN) {
+        let Some(info) = self.get_speaker_by_uuid(uuid).map(|s| s.info().clone()) else {
+            return;
+        };
+        let (uuid, urn, tx) = (uuid.to_owned(), urn.clone(), self.loaded_tx.clone());
+        drop(tokio::spawn(async move {
+            match Speaker::from_speaker_info(&info).await {
+                Ok(Some(speaker)) => {
+                    let _ = tx.send(Loaded::Resubscribe(uuid, urn, speaker));
+                }
+                Ok(None) => debug!("{} is not in its own topology", info.name()),
+                Err(err) => debug!("Unable to reach {}: {}", info.name(), err),
+            }
+        }));
+    }
+
+    /// Add speakers loaded in the background
+    fn finish_loading(&mut self, loaded: Loaded) {
+        match loaded {
+            Loaded::Speaker(household, uuid, speaker) => {
+                let Some(system) = self.systems.iter_mut().find(|s| s.household == household)
+                else {
+                    return;
+                };
+                system.loading.retain(|u| *u != uuid);
+                // The topology may have changed while it was loading
+                if let Some(speaker) = speaker.filter(|speaker| {
+                    controllable(&system.topology, &system.bonds)
+                        .any(|info| info.uuid().eq_ignore_ascii_case(speaker.uuid()))
+                }) {
+                    system.add_speakers(vec![speaker]);
+                }
+            }
+            Loaded::Validated(household, state) => self.finish_validation(household, state),
+            Loaded::Speakers(household, speakers) => {
+                let Some(system) = self.systems.iter_mut().find(|s| s.household == household)
+                else {
+                    return;
+                };
+                // The topology may have changed while they were loading
+                let speakers = speakers
+                    .into_iter()
+                    .filter(|speaker| {
+                        controllable(&system.topology, &system.bonds)
+                            .any(|info| info.uuid().eq_ignore_ascii_case(speaker.uuid()))
+                    })
+                    .collect();
+                system.add_speakers(speakers);
+            }
+            Loaded::Resubscribe(uuid, urn, speaker) => {
+                if let Some(system) = self.get_mut_system_for_uuid(&uuid) {
+                    system.resubscribe(&uuid, &urn, speaker);
+                }
+            }
+        }
+    }
+
+    /// Report the health of every subscription
+    fn diagnostics(&self) -> Diagnostics {
+        let subscriptions = self
+            .systems
+            .iter()
+            .flat_map(|system| {
+                let household = system.topology_subscription.iter();
+                let household = household.chain(system.alarm_subscription.iter());
+                household.map(Subscriber::status).chain(
+                    system
+                        .speakerdata
+                        .iter()
+                        .flat_map(SpeakerData::subscription_statuses),
+                )
+            })
+            .map(|mut status| {
+                status.speaker_name = status
+                    .uuid
+                    .as_deref()
+                    .and_then(|uuid| self.get_speaker_by_uuid(uuid))
+                    .map(|speaker| speaker.name().to_owned());
+                status
+            })
+            .collect();
+        Diagnostics {
+            rediscovery_attempts: self.rediscovery_attempts,
+            rediscovery_failures: self.rediscovery_failures,
+            subscriptions,
+        }
+    }
+
+    /// Cancel every event subscription
+    async fn unsubscribe_all(&mut self) {
+        let subscriptions = self.systems.iter_mut().flat_map(System::subscriptions_mut);
+        join_all(subscriptions.map(Subscriber::unsubscribe)).await;
+    }
+
+    /// Whether any system needs to be rediscovered. Cached systems are
+    /// checked before.
+    fn is_lost(&self) -> bool {
+        self.systems.is_empty()
+            || self
+                .systems
+                .iter()
+                .any(|s| !s.needs_validation && s.topology_subscription.is_none())
     }
 
-    /// Handle events.
-    async fn handle_event(&mut self, event: Event) {
+    /// Handle events. Anything that needs the network, like loading new
+    /// speakers, runs in the background, and is applied once it's done.
+    fn handle_event(&mut self, event: Event) {
         use Event::*;
+        let Some(event) = typed_event(event) else {
+            return;
+        };
         match event {
-            TopoUpdate(_uuid, topology) => {
+            TopoUpdate(uuid, topology, bonds) => {
                 debug!(
                     "Got topology update: {}",
                     topology.iter().fold(String::new(), |mut acc, (u, s)| {
@@ -223,11 +946,33 @@ impl Controller {
                         acc
                     })
                 );
-                self.system
-                    .update_from_topology(topology)
-                    .await
-                    .unwrap_or_else(|err| warn!("Error updating system topology: {:?}", err))
+                match uuid.and_then(|uuid| self.get_mut_system_for_topology_source(&uuid)) {
+                    Some(system) => {
+                        let new = system.update_from_topology(topology, bonds);
+                        let household = system.household.clone();
+                        self.load_in_background(household, new);
+                        self.save_cache();
+                        self.prune_action_queues();
+                    }
+                    None => warn!("Received topology update from unknown subscription"),
+                }
+            }
+            // Polled variables replace only their own cached values
+            AVTransResync(Some(uuid), polled) => {
+                debug!("Got AVTransResync for {}", uuid);
+                let mut data = self
+                    .get_speakerdata_by_uuid(&uuid)
+                    .map(|sd| sd.transport_data.clone())
+                    .unwrap_or_default();
+                for (key, value) in polled {
+                    match data.iter_mut().find(|(k, _)| *k == key) {
+                        Some(entry) => entry.1 = value,
+                        None => data.push((key, value)),
+                    }
+                }
+                self.update_avtransport_data(uuid, data)
             }
+            AVTransResync(None, _) => warn!("Missing UUID for AV Transport resync"),
             AVTransUpdate(uuid, data) => {
                 let keys = [
                     "CurrentPlayMode",
@@ -256,6 +1001,42 @@ impl Controller {
                     warn!("Missing UUID for AV Transport update")
                 }
             }
+            RenderingUpdate(uuid, data) => {
+                debug!("Got RenderingUpdate for {:?}: {:?}", uuid, data);
+                if let Some(uuid) = uuid {
+                    self.update_rendering_data(&uuid, &data)
+                } else {
+                    warn!("Missing UUID for Rendering Control update")
+                }
+            }
+            AlarmUpdate(uuid, data) => {
+                debug!("Got AlarmUpdate for {:?}: {:?}", uuid, data);
+                if let Some(uuid) = uuid {
+                    self.update_alarms(&uuid, &data)
+                } else {
+                    warn!("Missing UUID for Alarm Clock update")
+                }
+            }
+            ContentUpdate(uuid, data) => {
+                debug!("Got ContentUpdate for {:?}: {:?}", uuid, data);
+                if let Some(uuid) = uuid {
+                    self.update_content(&uuid, &data)
+                } else {
+                    warn!("Missing UUID for Content Directory update")
+                }
+            }
+            GroupRenderingUpdate(uuid, data) => {
+                debug!("Got GroupRenderingUpdate for {:?}: {:?}", uuid, data);
+                if let Some(uuid) = uuid {
+                    self.update_group_rendering_data(&uuid, &data)
+                } else {
+                    warn!("Missing UUID for Group Rendering Control update")
+                }
+            }
+            ServiceResync(Some(uuid), urn) => self.forget_service_state(&uuid, &urn),
+            ServiceResync(None, urn) => warn!("Missing UUID for {} resync", urn.typ()),
+            // Services the controller doesn't keep track of
+            ServiceUpdate(_) => (),
             SubscribeError(uuid, urn) => {
                 debug!(
                     "Subscription {} on {} lost",
@@ -266,55 +1047,376 @@ impl Controller {
                 // that leads to "Indirect Structural Match" lint error
                 match urn.typ() {
                     "ZoneGroupTopology" => {
-                        // The speaker we were getting updates from may have gone offline. Try another
-                        if let Err(err) = self.system.update_topology_subscription() {
+                        let Some(system) = uuid
+                            .as_deref()
+                            .and_then(|uuid| self.get_mut_system_for_topology_source(uuid))
+                        else {
+                            return;
+                        };
+                        // The speaker we were getting updates from may have gone
+                        // offline. Try another, or have the system rediscovered
+                        if let Err(err) = system.update_topology_subscription() {
                             info!("Having trouble subscribing to topology updates: {}", err);
-                            info!("  ...attempting to rediscover system");
-                            match self.system.discover().await {
-                                Ok(_) => info!("  ...success!"),
-                                Err(err) => {
-                                    info!("  ...failed: {}", err);
-                                    self.system.topology_subscription.take();
-                                }
-                            }
+                            system.topology_subscription.take();
                         }
                     }
-                    "AVTransport" => {
-                        // The speaker we are subscribing to may have gone
-                        // offline or gotten a new IP. In case its the later,
-                        // the SpeakerInfo and Device could be out of sync
+                    "ContentDirectory" => {
                         let uuid = &uuid.unwrap();
-                        if let Some(speakerdata) = self.get_mut_speakerdata_by_uuid(uuid) {
-                            if let Ok(Some(speaker)) =
-                                Speaker::from_speaker_info(speakerdata.speaker.info()).await
-                            {
-                                // The speaker still exists! Resubscribe
-                                debug!(
-                                    "Recreating speaker {}. Did it's IP change?",
-                                    speaker.name()
-                                );
-                                match get_av_transport_subscription(&speaker).await {
-                                    Some((sub, rx)) => {
-                                        speakerdata.transport_subscription = Some(sub);
-                                        self.system.queued_event_handles.push(rx);
-                                    }
-                                    None => speakerdata.transport_subscription = None,
-                                }
-                            }
+                        if let Some(system) = self.get_mut_system_for_uuid(uuid) {
+                            system.content_lost(uuid);
+                        }
+                        self.reload_speaker(uuid, &urn);
+                    }
+                    "AVTransport" | "RenderingControl" | "GroupRenderingControl" => {
+                        self.reload_speaker(&uuid.unwrap(), &urn);
+                    }
+                    "AlarmClock" => {
+                        // Any speaker of the household will do
+                        if let Some(system) = uuid
+                            .as_deref()
+                            .and_then(|uuid| self.get_mut_system_for_uuid(uuid))
+                        {
+                            system.update_alarm_subscription();
                         }
                     }
                     _ => (),
                 }
             }
-            NoOp => (),
         };
     }
 
-    /// Handle zone actions. Deal with errors here. Only return an error if it
-    /// is unrecoverable and should break the non-event loop.
-    async fn handle_zone_action(&self, tx: ZoneActionResponder, name: String, action: ZoneAction) {
+    /// Handle zone actions. Actions run in the background, in order with
+    /// other actions on the same coordinator. Errors are dealt with by the
+    /// action, which responds to the client.
+    fn handle_zone_action(&mut self, tx: ZoneActionResponder, name: ZoneName, action: ZoneAction) {
         debug!("Handling action {:?} for zone {}", action, name);
-        action.handle_action(self, tx, name).await
+        let coordinator = match self.resolve(&name).as_slice() {
+            [(_, sd)] => Some(
+                self.get_coordinator_uuid(sd.speaker.uuid())
+                    .unwrap_or(sd.speaker.uuid())
+                    .to_owned(),
+            ),
+            _ => None,
+        };
+        let moves_position = action.moves_position();
+        let changes_queue = action.changes_queue();
+        let coordinatordata = self.get_coordinatordata_for_name(&name);
+        let tracker = coordinatordata.map(|sd| sd.position.clone());
+        let queue = coordinatordata.map(|sd| sd.queue.clone());
+        let mut task = action.into_task(self, tx, name);
+        if let (true, Some(tracker)) = (moves_position, tracker) {
+            // Ask for the position again once playback jumped
+            task = async move {
+                task.await;
+                tracker.invalidate();
+            }
+            .boxed();
+        }
+        if let (true, Some(queue)) = (changes_queue, queue) {
+            // Fetch the queue again, without waiting for the event. Even a
+            // failed action may have cleared it.
+            task = async move {
+                task.await;
+                queue.invalidate();
+            }
+            .boxed();
+        }
+        self.queue_action(coordinator, task);
+    }
+
+    /// Handle alarm actions like zone actions, in order with other alarm
+    /// actions on the same household.
+    fn handle_alarm_action(&mut self, tx: ZoneActionResponder, action: AlarmAction) {
+        debug!("Handling alarm action {:?}", action);
+        let (household, task) = action.into_task(self, tx);
+        self.queue_action(household, task);
+    }
+
+    /// Run an action on every group, queued on each coordinator like a zone
+    /// action, and answer once all are done.
+    fn handle_bulk_action(&mut self, tx: BulkResponder, action: BulkAction) {
+        debug!("Handling bulk action {:?}", action);
+        let mut tasks = Vec::new();
+        let mut results = Vec::new();
+        for system in self.systems.iter() {
+            for (coordinator_uuid, infos) in system.topology.iter() {
+                let name = infos
+                    .iter()
+                    .find(|info| info.uuid().eq_ignore_ascii_case(coordinator_uuid))
+                    .map_or(coordinator_uuid.as_str(), |info| info.name());
+                let zone = ZoneName {
+                    household: Some(system.household.clone()),
+                    name: name.to_owned(),
+                };
+                let (result_tx, result_rx) = tokio::sync::oneshot::channel();
+                match self.get_speaker_by_uuid(coordinator_uuid) {
+                    Some(coordinator) => tasks.push((
+                        coordinator_uuid.clone(),
+                        action.into_task(coordinator, result_tx),
+                    )),
+                    // Reported, so that callers don't take the group for done
+                    None => {
+                        let _ = result_tx.send(Err(Error::ZoneDoesNotExist));
+                    }
+                }
+                results.push(async move {
+                    let result = result_rx.await.unwrap_or(Err(Error::MessageRecvError));
+                    (zone, result)
+                });
+            }
+        }
+        for (coordinator_uuid, task) in tasks {
+            self.queue_action(Some(coordinator_uuid), task);
+        }
+        drop(tokio::spawn(async move {
+            let _ = tx.send(join_all(results).await);
+        }));
+    }
+
+    /// Run an action after the others with the same key, or right away
+    fn queue_action(&mut self, key: Option<String>, task: ActionTask) {
+        let after = self.house_action.clone();
+        match key {
+            Some(key) => self
+                .action_queues
+                .entry(key)
+                .or_insert_with(|| ActionQueue::new(after))
+                .push(task),
+            // Nothing to keep in order with but actions on every group
+            None => drop(tokio::spawn(async move {
+                if let Some(after) = after {
+                    after.await;
+                }
+                task.await
+            })),
+        }
+    }
+
+    /// Run an action that touches every group once the queued actions are
+    /// done. Actions queued after it wait for it, and other commands are
+    /// handled meanwhile.
+    fn queue_house_action(&mut self, task: ActionTask) {
+        let queues = self
+            .action_queues
+            .drain()
+            .map(|(_, queue)| queue.finish())
+            .collect::<Vec<_>>();
+        let previous = self.house_action.take();
+        let action = async move {
+            if let Some(previous) = previous {
+                previous.await;
+            }
+            join_all(queues).await;
+            task.await
+        }
+        .boxed()
+        .shared();
+        drop(tokio::spawn(action.clone()));
+        self.house_action = Some(action);
+    }
+
+    /// Capture every group of every household, alongside other actions
+    fn take_house_snapshot(&mut self, tx: ZoneActionResponder) {
+        let households = self
+            .systems
+            .iter()
+            .map(|system| {
+                let groups = system
+                    .topology
+                    .iter()
+                    .filter_map(|(coordinator_uuid, _)| {
+                        let coordinator = self.get_speaker_by_uuid(coordinator_uuid)?.clone();
+                        let members = self.get_group_members(coordinator_uuid);
+                        Some((
+                            coordinator,
+                            members.into_iter().cloned().collect::<Vec<_>>(),
+                        ))
+                    })
+                    .collect::<Vec<_>>();
+                (system.household.clone(), groups)
+            })
+            .collect::<Vec<_>>();
+        let task = async move {
+            let result = async {
+                let mut snapshot = HouseSnapshot::default();
+                for (household, groups) in households {
+                    let mut group_snapshots = Vec::new();
+                    for (coordinator, members) in groups {
+                        // A group that can't be reached is left out, and
+                        // left alone when the snapshot is restored
+                        match capture_group(&coordinator, &members).await {
+                            Ok(group) => group_snapshots.push(group),
+                            Err(err) => {
+                                warn!("Leaving {} out of snapshot: {}", coordinator.name(), err)
+                            }
+                        }
+                    }
+                    snapshot.households.push(HouseholdSnapshot {
+                        household,
+                        groups: group_snapshots,
+                    });
+                }
+                Ok::<_, Error>(Response::HouseSnapshot(snapshot))
+            };
+            respond(tx, result.await)
+        };
+        self.queue_action(None, task.boxed());
+    }
+
+    /// Restore a house snapshot once running actions are done. It touches
+    /// every group, so actions on groups wait until it's restored.
+    fn apply_house_snapshot(&mut self, tx: RestoreResponder, snapshot: HouseSnapshot) {
+        let speakers: HouseSpeakers = self
+            .speakerdata()
+            .map(|sd| (sd.speaker.uuid().to_ascii_uppercase(), sd.detach()))
+            .collect();
+        let task = async move {
+            let _ = tx.send(restore(&snapshot, &speakers).await);
+        };
+        self.queue_house_action(task.boxed());
+    }
+
+    /// Apply a scene once running actions are done, like a house snapshot.
+    /// The steps are planned once it runs, from the state its rooms are in.
+    fn apply_scene(&mut self, tx: SceneResponder, name: &str) {
+        let Some(scene) = self
+            .scenes
+            .iter()
+            .find(|s| s.name.eq_ignore_ascii_case(name))
+            .cloned()
+        else {
+            let _ = tx.send(Err(Error::SceneNotFound(name.to_owned())));
+            return;
+        };
+        match sceneaction::prepare(self, scene) {
+            Ok(prepared) => {
+                let task = async move {
+                    let _ = tx.send(sceneaction::apply(prepared).await);
+                };
+                self.queue_house_action(task.boxed());
+            }
+            Err(err) => {
+                let _ = tx.send(Err(err));
+            }
+        }
+    }
+
+    /// Wait for queued actions to finish
+    async fn finish_actions(&mut self) {
+        join_all(self.action_queues.drain().map(|(_, queue)| queue.finish())).await;
+        if let Some(action) = self.house_action.take() {
+            action.await;
+        }
+    }
+
+    /// Stop the action queues of coordinators that are gone. Their queued
+    /// actions still run.
+    fn prune_action_queues(&mut self) {
+        let keys = self
+            .systems
+            .iter()
+            .flat_map(|s| {
+                s.topology
+                    .iter()
+                    .map(|(uuid, _)| uuid.clone())
+                    .chain([s.household.clone()])
+            })
+            .collect::<Vec<_>>();
+        self.action_queues
+            .retain(|key, _| keys.iter().any(|k| k.eq_ignore_ascii_case(key)));
+    }
+
+    /// Handle a command from a client, or keep it for later if it needs
+    /// speakers of the cache that are still loading.
+    async fn handle_command(&mut self, cmd: Command) {
+        if self.must_wait(&cmd) {
+            debug!("Command waits for cached speakers to load");
+            self.waiting.push(cmd);
+        } else {
+            self.run_command(cmd).await;
+        }
+    }
+
+    /// Whether a command needs speakers of the cache that are still loading,
+    /// so that it finds the same zones as after discovery. Commands on the
+    /// whole house wait for every speaker.
+    fn must_wait(&self, cmd: &Command) -> bool {
+        use Command::*;
+        match cmd {
+            DoZoneAction(_, name, _) | Subscribe(_, name, _) | SetAlias(_, name, _) => {
+                self.resolve(name).is_empty() && self.is_loading(name)
+            }
+            DoAlarmAction(..)
+            | DoBulkAction(..)
+            | TakeHouseSnapshot(_)
+            | ApplyHouseSnapshot(..)
+            | ApplyScene(..) => self
+                .systems
+                .iter()
+                .any(|s| s.needs_validation || !s.loading.is_empty()),
+            _ => false,
+        }
+    }
+
+    /// Whether a zone handle could stand for a speaker of the cache that is
+    /// still loading
+    fn is_loading(&self, name: &ZoneName) -> bool {
+        let speakers = self.systems.iter().flat_map(|s| {
+            controllable(&s.topology, &s.bonds)
+                .filter(|info| s.loading.iter().any(|uuid| uuid == info.uuid()))
+                .map(move |info| (s.household.as_str(), info.uuid(), info.name(), ()))
+        });
+        !resolve_handle(
+            name,
+            speakers,
+            self.systems.iter().flat_map(|s| s.bonds.iter()),
+            &self.aliases,
+        )
+        .is_empty()
+    }
+
+    /// Run a command from a client.
+    async fn run_command(&mut self, cmd: Command) {
+        use Command::*;
+        match cmd {
+            DoZoneAction(tx, name, action) => self.handle_zone_action(tx, name, action),
+            DoAlarmAction(tx, action) => self.handle_alarm_action(tx, action),
+            DoBulkAction(tx, action) => self.handle_bulk_action(tx, action),
+            GetStatus(_sender) => todo!(),
+            GetHouseholds(tx) => {
+                let _ = tx.send(self.households());
+            }
+            GetZones(tx) => {
+                let _ = tx.send(self.zones());
+            }
+            Subscribe(tx, name, urn) => {
+                let _ = tx.send(self.subscribe(&name, &urn));
+            }
+            SetAlias(tx, name, alias) => {
+                let _ = tx.send(self.set_alias(&name, alias));
+            }
+            RemoveAlias(tx, alias) => {
+                let _ = tx.send(self.remove_alias(&alias));
+            }
+            GetDiagnostics(tx) => {
+                let _ = tx.send(self.diagnostics());
+            }
+            TakeHouseSnapshot(tx) => self.take_house_snapshot(tx),
+            ApplyHouseSnapshot(tx, snapshot) => self.apply_house_snapshot(tx, snapshot),
+            AddScene(tx, scene) => {
+                self.scenes
+                    .retain(|s| !s.name.eq_ignore_ascii_case(&scene.name));
+                self.scenes.push(scene);
+                let _ = tx.send(Response::Ok(()));
+            }
+            ApplyScene(tx, name) => self.apply_scene(tx, &name),
+            Shutdown(tx) => {
+                // Refuse new commands. The run loop ends once the commands
+                // already queued have been handled.
+                self.rx.close();
+                self.shutdown = Some(tx);
+            }
+        }
     }
 
     /// Run the event loop.
@@ -323,147 +1425,255 @@ impl Controller {
     ///   the system state up-to-date.
     /// - Rediscover system as needed
     /// - Listen for commands from clients to perform actions on zones.
-
     pub async fn run(&mut self) {
-        use Command::*;
-
         let mut event_stream = SelectAll::new();
 
+        self.start_validation();
         debug!("Listening for commands");
-        'outer: loop {
+        loop {
             event_stream.extend(
-                self.system
-                    .queued_event_handles
-                    .drain(..)
-                    .map(WatchStream::new),
+                self.systems
+                    .iter_mut()
+                    .flat_map(|s| s.queued_event_handles.drain(..))
+                    .map(ReceiverStream::new),
             );
-            if self.system.topology_subscription.is_none() {
-                let now = tokio::time::Instant::now();
-                info!("Lost system. Rediscovering...");
-                match self.init().await {
-                    Ok(_) => info!("  ...success!"),
-                    Err(err) => {
-                        info!("  ...failed: {}", err);
-                        // Handle any pending commands without awaiting
-                        'inner: loop {
-                            match self.rx.try_recv() {
-                                Ok(Command::DoZoneAction(tx, name, action)) => {
-                                    self.handle_zone_action(tx, name, action).await;
-                                }
-                                Ok(Command::GetStatus(_sender)) => {
-                                    todo!()
-                                }
-                                Err(tokio::sync::mpsc::error::TryRecvError::Empty) => break 'inner,
-                                Err(tokio::sync::mpsc::error::TryRecvError::Disconnected) => {
-                                    break 'outer
-                                }
-                            }
-                        }
-
-                        // Handle any pending events without awaiting
-                        while let Some(Some(event)) = event_stream.next().now_or_never() {
-                            self.handle_event(event).await;
-                        }
-                        tokio::time::sleep(Duration::from_secs(1).saturating_sub(now.elapsed()))
-                            .await;
-                        continue 'outer;
-                    }
-                }
+            // Commands that waited for speakers that have loaded since
+            for cmd in mem::take(&mut self.waiting) {
+                self.handle_command(cmd).await;
             }
+            let rediscover =
+                self.is_lost() && self.shutdown.is_none() && self.rediscovery.is_none();
+            let rediscovery = self.rediscovery.as_mut().map(|(_, task)| task);
             select! {
                 maybe_command = self.rx.recv() => match maybe_command {
-                    Some(cmd) => match cmd {
-                        DoZoneAction(tx,name,action)=>self.handle_zone_action(tx,name,action).await,
-                        GetStatus(_sender) => todo!(), },
+                    Some(cmd) => self.handle_command(cmd).await,
                     None => break
                 },
-                maybe_event = event_stream.next() => match maybe_event {
-                    Some(event) => self.handle_event(event).await,
+                maybe_event = event_stream.next(), if !event_stream.is_empty() => match maybe_event {
+                    Some(event) => self.handle_event(event),
                     None => info!("No active subscriptions... all devices unreachable?"),
+                },
+                _ = time::sleep_until(self.next_rediscovery), if rediscover => {
+                    self.start_rediscovery();
+                }
+                result = async { rediscovery.unwrap().await }, if rediscovery.is_some() => {
+                    let started = self.rediscovery.take().map(|(started, _)| started);
+                    let result = result.unwrap_or_else(|err| {
+                        warn!("Rediscovery stopped: {}", err);
+                        Ok(Vec::new())
+                    });
+                    self.finish_rediscovery(started.unwrap_or_else(Instant::now), result);
                 }
+                Some(loaded) = self.loaded_rx.recv() => self.finish_loading(loaded),
             }
         }
         debug!("Controller loop finished");
+        // Speakers still loading won't be waited for
+        for cmd in mem::take(&mut self.waiting) {
+            self.run_command(cmd).await;
+        }
+        self.finish_actions().await;
+        self.sleep_fades.cancel_all().await;
+        self.unsubscribe_all().await;
+        if let Some(tx) = self.shutdown.take() {
+            let _ = tx.send(());
+        }
     }
 
-    fn get_speaker_with_name(&self, name: &str) -> Option<&Speaker> {
-        self.system.speakerdata.iter().find_map(|s| {
-            match s.speaker.name().eq_ignore_ascii_case(name) {
-                true => Some(&s.speaker),
-                false => None,
+    /// Get the IDs of all households under control
+    pub fn households(&self) -> Vec<HouseholdId> {
+        self.systems.iter().map(|s| s.household.clone()).collect()
+    }
+
+    /// Get the names of all zones, qualified by household
+    pub fn zones(&self) -> Vec<ZoneName> {
+        self.systems
+            .iter()
+            .flat_map(|s| {
+                s.zone_names().into_iter().map(|name| ZoneName {
+                    household: Some(s.household.clone()),
+                    name,
+                })
+            })
+            .collect()
+    }
+
+    /// Get the households and UUIDs of the zones a handle could mean.
+    pub fn resolve_zone(&self, name: &ZoneName) -> Vec<(HouseholdId, Uuid)> {
+        self.resolve(name)
+            .into_iter()
+            .map(|(household, sd)| (household.clone(), sd.speaker.uuid().to_owned()))
+            .collect()
+    }
+
+    /// Resolve a zone handle to the speakers it could stand for. Handles are
+    /// tried as stable IDs (speaker UUIDs, satellite UUIDs and group IDs),
+    /// then as aliases and finally as room names, ignoring case.
+    fn resolve(&self, name: &ZoneName) -> Vec<(&HouseholdId, &SpeakerData)> {
+        let speakers = self.systems.iter().flat_map(|s| {
+            s.speakerdata.iter().map(move |sd| {
+                let (uuid, speaker) = (sd.speaker.uuid(), sd.speaker.name());
+                (s.household.as_str(), uuid, speaker, (&s.household, sd))
+            })
+        });
+        resolve_handle(
+            name,
+            speakers,
+            self.systems.iter().flat_map(|s| s.bonds.iter()),
+            &self.aliases,
+        )
+    }
+
+    /// Make an alias for a zone. The alias follows the zone through renames
+    /// and takes precedence over room names.
+    fn set_alias(&mut self, name: &ZoneName, alias: String) -> Response {
+        let uuid = match self.resolve_zone(name).as_slice() {
+            [(_, uuid)] => uuid.clone(),
+            [] => return Response::NotOk,
+            zones => return Response::Ambiguous(zones.iter().map(|(h, _)| h.clone()).collect()),
+        };
+        debug!("Adding alias {} for {}", alias, name);
+        self.aliases
+            .retain(|(a, _)| !a.eq_ignore_ascii_case(&alias));
+        self.aliases.push((alias, uuid));
+        self.save_cache();
+        Response::Ok(())
+    }
+
+    /// Subscribe to a service on the speaker of a zone, for a client
+    fn subscribe(&self, name: &ZoneName, urn: &URN) -> Result<ServiceSubscription> {
+        let speakerdata = match self.resolve(name).as_slice() {
+            [(_, sd)] => *sd,
+            [] => return Err(Error::ZoneDoesNotExist),
+            zones => {
+                return Err(Error::AmbiguousZoneName(
+                    zones.iter().map(|(h, _)| h.to_string()).collect(),
+                ))
+            }
+        };
+        let speaker = &speakerdata.speaker;
+        let service = speaker
+            .device()
+            .find_service(urn)
+            .ok_or_else(|| Error::Unsupported(urn.typ().to_owned()))?;
+        let mut sub = Subscriber::new(
+            service.clone(),
+            speaker.device().url().clone(),
+            Some(speaker.uuid().to_owned()),
+            &self.timings,
+            &self.callback,
+        );
+        let rx = sub.subscribe()?;
+        Ok(ServiceSubscription::new(sub, rx))
+    }
+
+    fn remove_alias(&mut self, alias: &str) -> Response {
+        let len = self.aliases.len();
+        self.aliases.retain(|(a, _)| !a.eq_ignore_ascii_case(alias));
+        match self.aliases.len() < len {
+            true => {
+                self.save_cache();
+                Response::Ok(())
             }
+            false => Response::NotOk,
+        }
+    }
+
+    fn speakerdata(&self) -> impl Iterator<Item = &SpeakerData> {
+        self.systems.iter().flat_map(|s| s.speakerdata.iter())
+    }
+
+    fn get_mut_system_for_uuid(&mut self, uuid: &str) -> Option<&mut System> {
+        self.systems.iter_mut().find(|s| {
+            s.speakerdata
+                .iter()
+                .any(|sd| sd.speaker.uuid().eq_ignore_ascii_case(uuid))
         })
     }
 
+    fn get_mut_system_for_topology_source(&mut self, uuid: &str) -> Option<&mut System> {
+        self.systems.iter_mut().find(|s| s.is_topology_source(uuid))
+    }
+
+    /// Find the speaker for a zone name, alias or ID. If the name is not
+    /// qualified by a household and matches speakers in several households,
+    /// there is no unique match.
+    fn get_speaker_with_name(&self, name: &ZoneName) -> Option<&Speaker> {
+        match self.resolve(name).as_slice() {
+            [(_, sd)] => Some(&sd.speaker),
+            [] => None,
+            _ => {
+                warn!("Zone name {} is ambiguous across households", name);
+                None
+            }
+        }
+    }
+
     fn get_speaker_by_uuid(&self, uuid: &str) -> Option<&Speaker> {
-        self.system.speakerdata.iter().find_map(|s| {
-            match s.speaker.uuid().eq_ignore_ascii_case(uuid) {
+        self.speakerdata()
+            .find_map(|s| match s.speaker.uuid().eq_ignore_ascii_case(uuid) {
                 true => Some(&s.speaker),
                 false => None,
-            }
-        })
+            })
     }
 
     fn get_speakerdata_by_uuid(&self, uuid: &str) -> Option<&SpeakerData> {
-        self.system
-            .speakerdata
-            .iter()
+        self.speakerdata()
             .find(|s| s.speaker.uuid().eq_ignore_ascii_case(uuid))
     }
 
-    fn get_mut_speakerdata_by_uuid(&mut self, uuid: &str) -> Option<&mut SpeakerData> {
-        self.system
-            .speakerdata
-            .iter_mut()
-            .find(|s| s.speaker.uuid().eq_ignore_ascii_case(uuid))
-    }
-
-    pub fn get_coordinator_for_name(&self, name: &str) -> Option<&Speaker> {
+    pub fn get_coordinator_for_name(&self, name: &ZoneName) -> Option<&Speaker> {
         let speaker = self.get_speaker_with_name(name)?;
         self.get_coordinator_for_uuid(speaker.uuid())
     }
 
-    pub fn get_coordinatordata_for_name(&self, name: &str) -> Option<&SpeakerData> {
+    pub fn get_coordinatordata_for_name(&self, name: &ZoneName) -> Option<&SpeakerData> {
         let speaker = self.get_speaker_with_name(name)?;
         self.get_coordinatordata_for_uuid(speaker.uuid())
     }
 
+    fn get_coordinator_uuid(&self, speaker_uuid: &str) -> Option<&str> {
+        self.systems.iter().find_map(|s| {
+            s.topology.iter().find_map(|(coordinator_uuid, uuids)| {
+                uuids
+                    .iter()
+                    .find(|&info| info.uuid().eq_ignore_ascii_case(speaker_uuid))
+                    .and(Some(coordinator_uuid.as_str()))
+            })
+        })
+    }
+
+    /// Speakers of the group with the given coordinator, including it
+    fn get_group_members(&self, coordinator_uuid: &str) -> Vec<&Speaker> {
+        self.systems
+            .iter()
+            .flat_map(|s| s.topology.iter())
+            .filter(|(uuid, _)| uuid.eq_ignore_ascii_case(coordinator_uuid))
+            .flat_map(|(_, infos)| infos)
+            .filter_map(|info| self.get_speaker_by_uuid(info.uuid()))
+            .collect()
+    }
+
     fn get_coordinator_for_uuid(&self, speaker_uuid: &str) -> Option<&Speaker> {
-        let coordinator_uuid =
-            self.system
-                .topology
-                .iter()
-                .find_map(|(coordinator_uuid, uuids)| {
-                    uuids
-                        .iter()
-                        .find(|&info| info.uuid().eq_ignore_ascii_case(speaker_uuid))
-                        .and(Some(coordinator_uuid))
-                })?;
-        self.get_speaker_by_uuid(coordinator_uuid)
+        self.get_speaker_by_uuid(self.get_coordinator_uuid(speaker_uuid)?)
     }
 
     fn get_coordinatordata_for_uuid(&self, speaker_uuid: &str) -> Option<&SpeakerData> {
-        let coordinator_uuid =
-            self.system
-                .topology
-                .iter()
-                .find_map(|(coordinator_uuid, uuids)| {
-                    uuids
-                        .iter()
-                        .find(|&info| info.uuid().eq_ignore_ascii_case(speaker_uuid))
-                        .and(Some(coordinator_uuid))
-                })?;
-        self.get_speakerdata_by_uuid(coordinator_uuid)
+        self.get_speakerdata_by_uuid(self.get_coordinator_uuid(speaker_uuid)?)
     }
 
     fn update_avtransport_data(&mut self, uuid: Uuid, data: Vec<(String, String)>) {
         match self
-            .system
-            .speakerdata
+            .systems
             .iter_mut()
+            .flat_map(|s| s.speakerdata.iter_mut())
             .find(|sd| sd.speaker.uuid().eq_ignore_ascii_case(&uuid))
         {
-            Some(sd) => sd.transport_data = data,
+            Some(sd) => {
+                sd.position
+                    .update(&sd.transport_data, &data, std::time::Instant::now());
+                sd.transport_data = data;
+            }
             None => warn!(
                 "Received AV Transport data for non-existant speaker {}",
                 uuid
@@ -471,21 +1681,484 @@ impl Controller {
         };
     }
 
+    /// Cache the rendering state of a speaker and tell clients if it changed
+    fn update_rendering_data(&mut self, uuid: &str, data: &RenderingStatus) {
+        let Some((household, sd)) = self.systems.iter_mut().find_map(|s| {
+            s.speakerdata
+                .iter_mut()
+                .find(|sd| sd.speaker.uuid().eq_ignore_ascii_case(uuid))
+                .map(|sd| (&s.household, sd))
+        }) else {
+            warn!(
+                "Received Rendering Control data for non-existant speaker {}",
+                uuid
+            );
+            return;
+        };
+        let old = sd.rendering_data.clone();
+        sd.rendering_data.update(data);
+        if sd.rendering_data != old {
+            let _ = self.events.send(ZoneEvent::Rendering {
+                zone: ZoneName {
+                    household: Some(household.clone()),
+                    name: sd.speaker.name().to_owned(),
+                },
+                state: sd.rendering_data.clone(),
+            });
+        }
+    }
+
+    /// Cache the group rendering state reported by a speaker and tell clients
+    /// if it changed
+    fn update_group_rendering_data(&mut self, uuid: &str, data: &AVStatus) {
+        let Some((household, sd)) = self.systems.iter_mut().find_map(|s| {
+            s.speakerdata
+                .iter_mut()
+                .find(|sd| sd.speaker.uuid().eq_ignore_ascii_case(uuid))
+                .map(|sd| (&s.household, sd))
+        }) else {
+            warn!(
+                "Received Group Rendering Control data for non-existant speaker {}",
+                uuid
+            );
+            return;
+        };
+        let old = sd.group_rendering_data.clone();
+        sd.group_rendering_data.update(data);
+        if sd.group_rendering_data != old {
+            let _ = self.events.send(ZoneEvent::GroupRendering {
+                zone: ZoneName {
+                    household: Some(household.clone()),
+                    name: sd.speaker.name().to_owned(),
+                },
+                state: sd.group_rendering_data.clone(),
+            });
+        }
+    }
+
+    /// Drop cached content whose update ID changed and tell clients about it
+    fn update_content(&mut self, uuid: &str, data: &AVStatus) {
+        let Some((system, sd)) = self.systems.iter().find_map(|s| {
+            s.speakerdata
+                .iter()
+                .find(|sd| sd.speaker.uuid().eq_ignore_ascii_case(uuid))
+                .map(|sd| (s, sd))
+        }) else {
+            warn!(
+                "Received Content Directory data for non-existant speaker {}",
+                uuid
+            );
+            return;
+        };
+        // Events of the speaker keep its caches up-to-date from now on
+        system.content.favorites.set_live(true);
+        system.content.playlists.set_live(true);
+        sd.queue.set_live(true);
+        for (source, update_id, change) in content_updates(data) {
+            let event = match change {
+                ContentChange::Favorites => system
+                    .content
+                    .favorites
+                    .update(&source, &update_id)
+                    .then(|| ZoneEvent::FavoritesChanged {
+                        household: system.household.clone(),
+                    }),
+                ContentChange::Playlists => system
+                    .content
+                    .playlists
+                    .update(&source, &update_id)
+                    .then(|| ZoneEvent::PlaylistsChanged {
+                        household: system.household.clone(),
+                    }),
+                ContentChange::Queue => {
+                    sd.queue
+                        .update(&source, &update_id)
+                        .then(|| ZoneEvent::QueueChanged {
+                            zone: ZoneName {
+                                household: Some(system.household.clone()),
+                                name: sd.speaker.name().to_owned(),
+                            },
+                        })
+                }
+            };
+            if let Some(event) = event {
+                debug!("Content changed: {:?}", event);
+                let _ = self.events.send(event);
+            }
+        }
+    }
+
+    /// Drop the cached alarms if their version changed and tell clients
+    fn update_alarms(&mut self, uuid: &str, data: &AVStatus) {
+        let Some(system) = self.systems.iter().find(|s| {
+            s.speakerdata
+                .iter()
+                .any(|sd| sd.speaker.uuid().eq_ignore_ascii_case(uuid))
+        }) else {
+            warn!(
+                "Received Alarm Clock data for non-existant speaker {}",
+                uuid
+            );
+            return;
+        };
+        system.alarms.set_live(true);
+        for (name, version) in data {
+            if name == "AlarmListVersion" && system.alarms.update(name, version) {
+                let _ = self.events.send(ZoneEvent::AlarmsChanged {
+                    household: system.household.clone(),
+                });
+            }
+        }
+    }
+
+    /// Drop what was kept from the events of a service after some were
+    /// missed, so that it is asked for again
+    fn forget_service_state(&mut self, uuid: &str, urn: &URN) {
+        debug!("Forgetting {} state of {}", urn.typ(), uuid);
+        let Some(system) = self.get_mut_system_for_uuid(uuid) else {
+            return;
+        };
+        let is_speaker = |sd: &&mut SpeakerData| sd.speaker.uuid().eq_ignore_ascii_case(uuid);
+        match urn.typ() {
+            "ContentDirectory" => {
+                system.content.favorites.invalidate();
+                system.content.playlists.invalidate();
+                if let Some(sd) = system.speakerdata.iter_mut().find(is_speaker) {
+                    sd.queue.invalidate();
+                }
+            }
+            "AVTransport" => {
+                if let Some(sd) = system.speakerdata.iter_mut().find(is_speaker) {
+                    sd.transport_data = Default::default();
+                    sd.position.invalidate();
+                }
+            }
+            "RenderingControl" => {
+                if let Some(sd) = system.speakerdata.iter_mut().find(is_speaker) {
+                    sd.rendering_data = Default::default();
+                }
+            }
+            "GroupRenderingControl" => {
+                if let Some(sd) = system.speakerdata.iter_mut().find(is_speaker) {
+                    sd.group_rendering_data = Default::default();
+                }
+            }
+            "AlarmClock" => system.alarms.invalidate(),
+            _ => (),
+        }
+    }
+
+    /// Get a sender for client events, to hand out receivers
+    pub fn events(&self) -> broadcast::Sender<ZoneEvent> {
+        self.events.clone()
+    }
+
     /// Drop a speaker for no good reason
     #[cfg(test)]
     pub fn _drop_speaker(&mut self) {
-        self.system.speakerdata.pop().unwrap();
+        self.systems[0].speakerdata.pop().unwrap();
+    }
+}
+
+/// Households found on the network, with their topology
+type Households = Vec<(HouseholdId, Topology, Bonds)>;
+
+/// Households with the speakers of their topology loaded
+type LoadedHouseholds = Vec<(HouseholdId, Topology, Bonds, Vec<Speaker>)>;
+
+/// Speakers loaded in the background, see [`Controller::finish_loading`]
+enum Loaded {
+    /// A speaker of the cached topology of a household, if it answered
+    Speaker(HouseholdId, Uuid, Option<Speaker>),
+    /// The current topology of a cached household, or none if none of its
+    /// speakers gave it
+    Validated(HouseholdId, Option<(Topology, Bonds)>),
+    /// New speakers of a household
+    Speakers(HouseholdId, Vec<Speaker>),
+    /// A speaker loaded again to subscribe to a service whose subscription
+    /// was lost
+    Resubscribe(Uuid, URN, Speaker),
+}
+
+/// Fetch the device descriptions of speakers all at once. Speakers that
+/// can't be reached are left out.
+async fn load_speakers(infos: impl Iterator<Item = SpeakerInfo>) -> Vec<Speaker> {
+    let infos = infos.collect::<Vec<_>>();
+    let speakers = join_all(infos.iter().map(Speaker::from_speaker_info)).await;
+    infos
+        .iter()
+        .zip(speakers)
+        .filter_map(|(info, speaker)| loaded_speaker(info, speaker))
+        .collect()
+}
+
+/// The speaker loaded from its info, if it could be reached
+fn loaded_speaker(info: &SpeakerInfo, speaker: sonor::Result<Option<Speaker>>) -> Option<Speaker> {
+    match speaker {
+        Ok(Some(speaker)) => Some(speaker),
+        Ok(None) => {
+            warn!("{} is not in its own topology", info.name());
+            None
+        }
+        Err(err) => {
+            warn!("Unable to reach {}: {}", info.name(), err);
+            None
+        }
+    }
+}
+
+/// Fetch the device descriptions of the speakers of households
+async fn load_households(households: Households) -> LoadedHouseholds {
+    let mut loaded = Vec::new();
+    for (household, topology, bonds) in households {
+        let speakers = load_speakers(controllable(&topology, &bonds).cloned()).await;
+        loaded.push((household, topology, bonds, speakers));
+    }
+    loaded
+}
+
+/// The speakers of a topology that can be controlled: all but the
+/// satellites of bonded sets
+fn controllable<'a>(
+    topology: &'a Topology,
+    bonds: &'a Bonds,
+) -> impl Iterator<Item = &'a SpeakerInfo> + Clone {
+    topology
+        .iter()
+        .flat_map(|(_, infos)| infos)
+        .filter(move |info| {
+            !bonds
+                .iter()
+                .any(|b| b.uuid.eq_ignore_ascii_case(info.uuid()))
+        })
+}
+
+/// Discover the sonos households on the network along with the topology of
+/// each. If a seed room is given, only the household with that room is
+/// returned. With static addresses, only the households of those speakers are
+/// returned.
+async fn discover_households(discovery: &Discovery, timeout: Duration) -> Result<Households> {
+    let speakers = match discovery {
+        Discovery::Ssdp(Some(room)) => {
+            debug!("Looking for seed: {}", room);
+            vec![Ok(find(room, timeout)
+                .await?
+                .ok_or(Error::ZoneDoesNotExist)?)]
+        }
+        Discovery::Ssdp(None) => discover(timeout).await?.collect::<Vec<_>>().await,
+        Discovery::Static(urls) => {
+            let mut speakers = Vec::with_capacity(urls.len());
+            for url in urls {
+                debug!("Contacting speaker at {}", url);
+                speakers.push(speaker_from_url(url).await);
+            }
+            speakers
+        }
+    };
+
+    let mut households: Vec<(HouseholdId, Topology, Bonds)> = Vec::new();
+    for speaker in speakers {
+        let speaker = match speaker {
+            Ok(speaker) => speaker,
+            Err(err) => {
+                warn!("Error discovering speaker: {}", err);
+                continue;
+            }
+        };
+        // Any speaker in a topology we already have belongs to a known household
+        if households.iter().any(|(_, topology, _)| {
+            topology
+                .iter()
+                .flat_map(|(_, infos)| infos)
+                .any(|info| info.uuid().eq_ignore_ascii_case(speaker.uuid()))
+        }) {
+            continue;
+        }
+        match get_household_and_topology(&speaker).await {
+            Ok((household, topology, bonds)) => {
+                debug!("Found household {} via {}", household, speaker.name());
+                households.push((household, topology, bonds));
+            }
+            Err(err) => warn!("Unable to get household from {}: {}", speaker.name(), err),
+        }
+    }
+
+    if households.is_empty() {
+        return Err(sonor::Error::NoSpeakersDetected.into());
+    }
+    Ok(households)
+}
+
+async fn speaker_from_url(url: &Uri) -> Result<Speaker, sonor::Error> {
+    let device = Device::from_url(url.clone()).await?;
+    Speaker::from_device(device)
+        .await
+        .ok_or(sonor::Error::SpeakerNotIncludedInOwnZoneGroupState)
+}
+
+async fn get_household_and_topology(speaker: &Speaker) -> Result<(HouseholdId, Topology, Bonds)> {
+    let household = speaker
+        .action(DEVICE_PROPERTIES, "GetHouseholdID", "")
+        .await?
+        .remove("CurrentHouseholdID")
+        .ok_or_else(|| Error::MissingHouseholdId(speaker.name().to_owned()))?;
+    let (topology, bonds) = get_zone_group_state(speaker).await?;
+    Ok((household, topology, bonds))
+}
+
+/// Get the topology and bonded sets from a speaker
+async fn get_zone_group_state(speaker: &Speaker) -> Result<(Topology, Bonds)> {
+    let xml = speaker
+        .action(ZONE_GROUP_TOPOLOGY, "GetZoneGroupState", "")
+        .await?
+        .remove("ZoneGroupState")
+        .ok_or(Error::ZoneActionError)?;
+    extract_zone_group_state(&xml)
+}
+
+/// Get the topology and bonded sets from the first of the speakers of a
+/// household that gives them
+pub(crate) async fn household_topology<'a>(
+    speakers: impl IntoIterator<Item = &'a Speaker>,
+) -> Option<(Topology, Bonds)> {
+    for speaker in speakers {
+        match get_zone_group_state(speaker).await {
+            Ok(state) => return Some(state),
+            Err(err) => debug!("{} did not give topology: {}", speaker.name(), err),
+        }
+    }
+    None
+}
+
+/// Turn the variables of the services the controller keeps track of into
+/// their own events. Returns `None` for events without the variables needed.
+fn typed_event(event: Event) -> Option<Event> {
+    use Event::*;
+    let ServiceUpdate(ServiceEvent {
+        uuid,
+        service,
+        vars,
+        resync,
+    }) = event
+    else {
+        return Some(event);
+    };
+    let plain =
+        |vars: Vec<StateVar>| -> AVStatus { vars.into_iter().map(|v| (v.name, v.value)).collect() };
+    // Variables of LastChange events are the ones with an instance
+    let last_change = |vars: Vec<StateVar>| {
+        vars.into_iter()
+            .filter(|v| v.instance.is_some())
+            .collect::<Vec<_>>()
+    };
+    match service.typ() {
+        "ZoneGroupTopology" => {
+            let xml = vars.into_iter().find(|v| v.name == "ZoneGroupState")?.value;
+            extract_zone_group_state(&xml)
+                .map_err(|err| warn!("Unable to extract topology: {}", err))
+                .ok()
+                .map(|(topology, bonds)| TopoUpdate(uuid, topology, bonds))
+        }
+        "AVTransport"
+        | "RenderingControl"
+        | "ContentDirectory"
+        | "GroupRenderingControl"
+        | "AlarmClock"
+            if resync && vars.is_empty() =>
+        {
+            Some(ServiceResync(uuid, service))
+        }
+        "AVTransport" if resync => Some(AVTransResync(uuid, plain(vars))),
+        "AVTransport" => {
+            let vars = last_change(vars);
+            (!vars.is_empty()).then(|| AVTransUpdate(uuid, plain(vars)))
+        }
+        "RenderingControl" => {
+            let vars = last_change(vars);
+            (!vars.is_empty()).then(|| {
+                let vars = vars.into_iter().map(|v| (v.name, v.channel, v.value));
+                RenderingUpdate(uuid, vars.collect())
+            })
+        }
+        "GroupRenderingControl" => Some(GroupRenderingUpdate(uuid, plain(vars))),
+        "ContentDirectory" => Some(ContentUpdate(uuid, plain(vars))),
+        "AlarmClock" => Some(AlarmUpdate(uuid, plain(vars))),
+        _ => Some(ServiceUpdate(ServiceEvent {
+            uuid,
+            service,
+            vars,
+            resync,
+        })),
+    }
+}
+
+/// Resolve a zone handle among speakers given as (household, UUID, name,
+/// item), to the items of the speakers it could stand for. See
+/// [`Controller::resolve`].
+fn resolve_handle<'a, T>(
+    name: &ZoneName,
+    speakers: impl Iterator<Item = (&'a str, &'a str, &'a str, T)> + Clone,
+    satellites: impl Iterator<Item = &'a Satellite> + Clone,
+    aliases: &'a [(String, Uuid)],
+) -> Vec<T> {
+    let uuids = speakers.clone().map(|(_, uuid, ..)| uuid);
+    let id = find_uuid(&name.name, uuids, satellites, aliases);
+    speakers
+        .filter(|(household, ..)| name.matches_household(household))
+        .filter(|(_, uuid, speaker, _)| is_match(&name.name, id, uuid, speaker))
+        .map(|(.., item)| item)
+        .collect()
+}
+
+/// Find the UUID of the speaker a stable ID or alias refers to, among the
+/// UUIDs of the speakers, the satellites bonded to them and the aliases
+fn find_uuid<'a>(
+    handle: &'a str,
+    speakers: impl Iterator<Item = &'a str> + Clone,
+    satellites: impl Iterator<Item = &'a Satellite> + Clone,
+    aliases: &'a [(String, Uuid)],
+) -> Option<&'a str> {
+    // Group IDs are the coordinator UUID with a suffix
+    let candidates = [Some(handle), handle.split_once(':').map(|(uuid, _)| uuid)];
+    for uuid in candidates.into_iter().flatten() {
+        if let Some(speaker) = speakers.clone().find(|s| s.eq_ignore_ascii_case(uuid)) {
+            return Some(speaker);
+        }
+        if let Some(satellite) = satellites
+            .clone()
+            .find(|s| s.uuid.eq_ignore_ascii_case(uuid))
+        {
+            return Some(&satellite.primary);
+        }
+    }
+    aliases
+        .iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(handle))
+        .map(|(_, uuid)| uuid.as_str())
+}
+
+/// Whether a speaker is what a handle stands for, given the UUID the handle
+/// resolved to, if any
+fn is_match(handle: &str, id: Option<&str>, uuid: &str, name: &str) -> bool {
+    match id {
+        Some(id) => uuid.eq_ignore_ascii_case(id),
+        None => name.eq_ignore_ascii_case(handle),
     }
 }
 
-async fn get_av_transport_subscription(
+fn get_subscription(
     new_speaker: &Speaker,
+    urn: &URN,
+    timings: &Timings,
+    callback: &Arc<CallbackServer>,
 ) -> Option<(Subscriber, EventReceiver)> {
-    if let Some(service) = new_speaker.device().find_service(AV_TRANSPORT) {
+    if let Some(service) = new_speaker.device().find_service(urn) {
         let mut device_sub = Subscriber::new(
             service.clone(),
             new_speaker.device().url().clone(),
             Some(new_speaker.uuid().to_owned()),
+            timings,
+            callback,
         );
         if let Ok(rx) = device_sub.subscribe() {
             return Some((device_sub, rx));
@@ -506,18 +2179,26 @@ mod test {
         simple_logger::init_with_level(log::Level::Debug).unwrap();
         let handle = {
             let (_tx, rx) = mpsc::channel(10);
-            let mut controller = Controller::new(rx, None);
+            let callback = CallbackServer::start(Default::default()).await?;
+            let mut controller = Controller::new(
+                rx,
+                Discovery::Ssdp(None),
+                None,
+                Vec::new(),
+                Timings::default(),
+                callback,
+            );
             controller.init().await?;
 
             log::info!("Initialized manager with devices:");
-            for device in controller.system.speakers().iter() {
+            for device in controller.systems[0].speakers().iter() {
                 log::info!("     - {}", device.name());
             }
 
             controller._drop_speaker();
 
             log::info!("Now we have:");
-            for device in controller.system.speakers().iter() {
+            for device in controller.systems[0].speakers().iter() {
                 log::info!("     - {}", device.name());
             }
 
@@ -531,4 +2212,63 @@ mod test {
         handle.await.unwrap();
         Ok(())
     }
+
+    /// Speakers of two households as (household, UUID, name)
+    const SPEAKERS: [(&str, &str, &str); 4] = [
+        ("Sonos_A", "RINCON_A1", "Kitchen"),
+        ("Sonos_A", "RINCON_A2", "Living Room"),
+        ("Sonos_B", "RINCON_B1", "Kitchen"),
+        ("Sonos_B", "RINCON_B2", "RINCON_A2"),
+    ];
+
+    /// Resolve a handle among [`SPEAKERS`] to UUIDs
+    fn resolve(
+        name: &ZoneName,
+        satellites: &[Satellite],
+        aliases: &[(String, Uuid)],
+    ) -> Vec<&'static str> {
+        let speakers = SPEAKERS
+            .iter()
+            .map(|&(household, uuid, speaker)| (household, uuid, speaker, uuid));
+        resolve_handle(name, speakers, satellites.iter(), aliases)
+    }
+
+    #[test]
+    fn test_resolve() {
+        let satellites = [Satellite {
+            uuid: "RINCON_SUB".into(),
+            primary: "RINCON_A2".into(),
+            channels: "SW".into(),
+        }];
+        let aliases = [
+            ("tv".to_owned(), "RINCON_A2".to_owned()),
+            ("Kitchen".to_owned(), "RINCON_B2".to_owned()),
+        ];
+        let names = |name: &str| resolve(&name.to_owned().into(), &satellites, &aliases);
+
+        // IDs first, then aliases, then names
+        assert_eq!(names("rincon_a2"), ["RINCON_A2"]);
+        assert_eq!(names("TV"), ["RINCON_A2"]);
+        assert_eq!(names("kitchen"), ["RINCON_B2"]);
+        assert_eq!(names("living room"), ["RINCON_A2"]);
+        // Group IDs and satellites stand for the coordinator and primary
+        assert_eq!(names("RINCON_A1:42"), ["RINCON_A1"]);
+        assert_eq!(names("RINCON_SUB"), ["RINCON_A2"]);
+        assert_eq!(names("RINCON_SUB:7"), ["RINCON_A2"]);
+        assert!(names("Bathroom").is_empty());
+        assert!(names("RINCON_C1:1").is_empty());
+
+        // Names in more than one household are ambiguous, unless the
+        // household is given
+        let names = |name: &str, household: Option<&str>| {
+            let name = ZoneName {
+                household: household.map(str::to_owned),
+                name: name.to_owned(),
+            };
+            resolve(&name, &satellites, &[])
+        };
+        assert_eq!(names("Kitchen", None), ["RINCON_A1", "RINCON_B1"]);
+        assert_eq!(names("Kitchen", Some("sonos_b")), ["RINCON_B1"]);
+        assert_eq!(names("RINCON_A1", Some("Sonos_B")), Vec::<&str>::new());
+    }
 }
@@ -0,0 +1,170 @@
+use std::sync::Arc;
+
+use futures_util::FutureExt as _;
+
+use super::{
+    zoneaction::{respond, ActionTask, Detach},
+    Controller, SpeakerData,
+};
+use crate::{
+    alarm::{create_alarm, destroy_alarm, list_alarms, update_alarm, Alarm, AlarmSource},
+    contentcache::Cached,
+    types::{HouseholdId, Response, Uuid, ZoneActionResponder, ZoneName},
+    Error,
+};
+
+#[derive(Debug)]
+pub enum AlarmAction {
+    List,
+    Create(Alarm),
+    Update(Alarm),
+    Delete(Alarm),
+}
+use AlarmAction::*;
+
+impl AlarmAction {
+    /// Look up the speakers for the action and turn it into a task. Also
+    /// returns the household the action changes, if any, so that changes to
+    /// a household run in order.
+    pub(super) fn into_task(
+        self,
+        controller: &Controller,
+        tx: ZoneActionResponder,
+    ) -> (Option<HouseholdId>, ActionTask) {
+        match self {
+            List => {
+                let systems = controller
+                    .systems
+                    .iter()
+                    .filter_map(|s| {
+                        let speaker = s.speakerdata.first()?.speaker.clone();
+                        Some((s.household.clone(), speaker, s.alarms.clone()))
+                    })
+                    .collect::<Vec<_>>();
+                let task = async move {
+                    let mut alarms = Vec::new();
+                    let (households, mut failed) = (systems.len(), Vec::new());
+                    for (household, speaker, cache) in systems {
+                        match cache
+                            .get_or_fetch(|| list_alarms(&speaker, &household))
+                            .await
+                        {
+                            Ok(list) => alarms.extend(list.iter().cloned()),
+                            // The alarms of the other households are still
+                            // listed
+                            Err(err) => {
+                                log::warn!("Unable to list alarms of {}: {}", household, err);
+                                failed.push(err);
+                            }
+                        }
+                    }
+                    // Only fails if no household could be asked
+                    match failed.pop() {
+                        Some(err) if failed.len() + 1 == households => respond(tx, Err(err)),
+                        _ => respond(tx, Ok(Response::Alarms(alarms))),
+                    }
+                };
+                (None, task.boxed())
+            }
+            Create(alarm) | Update(alarm) => {
+                let Some((household, room, speakerdata)) = resolve_room(controller, &alarm) else {
+                    log::warn!("Room {} of alarm not found", alarm.room);
+                    return (
+                        None,
+                        async move { respond(tx, Err(Error::ZoneDoesNotExist)) }.boxed(),
+                    );
+                };
+                let cache = alarm_cache(controller, &household);
+                let create = alarm.id.is_none();
+                let mut alarm = Alarm {
+                    household: Some(household.clone()),
+                    room,
+                    ..alarm
+                };
+                let task = async move {
+                    let result = async {
+                        if let AlarmSource::Media(ref media) = alarm.source {
+                            let (uri, metadata) = media
+                                .get_uri_and_metadata(&speakerdata)
+                                .await
+                                .ok_or(Error::ContentNotFound)?;
+                            alarm.source = AlarmSource::Uri { uri, metadata };
+                        }
+                        match create {
+                            true => create_alarm(&speakerdata.speaker, &alarm)
+                                .await
+                                .map(Response::AlarmId),
+                            false => update_alarm(&speakerdata.speaker, &alarm)
+                                .await
+                                .map(Response::Ok),
+                        }
+                    };
+                    let result = result.await;
+                    if result.is_ok() {
+                        cache.invalidate();
+                    }
+                    respond(tx, result)
+                };
+                (Some(household), task.boxed())
+            }
+            Delete(alarm) => {
+                // The room may be gone, but any speaker of the household will do
+                let target = resolve_room(controller, &alarm)
+                    .map(|(household, _, sd)| (household, sd.speaker))
+                    .or_else(|| {
+                        let household = alarm.household.as_deref()?;
+                        let system = controller
+                            .systems
+                            .iter()
+                            .find(|s| s.household.eq_ignore_ascii_case(household))?;
+                        let speaker = system.speakerdata.first()?.speaker.clone();
+                        Some((system.household.clone(), speaker))
+                    });
+                let (Some((household, speaker)), Some(id)) = (target, alarm.id) else {
+                    return (
+                        None,
+                        async move { respond(tx, Err(Error::ZoneDoesNotExist)) }.boxed(),
+                    );
+                };
+                let cache = alarm_cache(controller, &household);
+                let task = async move {
+                    let result = destroy_alarm(&speaker, id).await;
+                    if result.is_ok() {
+                        cache.invalidate();
+                    }
+                    respond(tx, result.map(Response::Ok))
+                };
+                (Some(household), task.boxed())
+            }
+        }
+    }
+}
+
+/// Get the alarm cache of a household, to drop it after changing alarms
+fn alarm_cache(controller: &Controller, household: &str) -> Arc<Cached<Alarm>> {
+    controller
+        .systems
+        .iter()
+        .find(|s| s.household == household)
+        .map(|s| s.alarms.clone())
+        .unwrap_or_default()
+}
+
+/// Find the household and UUID of an alarm's room, and its speaker
+fn resolve_room(
+    controller: &Controller,
+    alarm: &Alarm,
+) -> Option<(HouseholdId, Uuid, SpeakerData)> {
+    let name = ZoneName {
+        household: alarm.household.clone(),
+        name: alarm.room.clone(),
+    };
+    match controller.resolve(&name).as_slice() {
+        [(household, sd)] => Some((
+            household.to_string(),
+            sd.speaker.uuid().to_owned(),
+            sd.detach(),
+        )),
+        _ => None,
+    }
+}
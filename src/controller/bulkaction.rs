@@ -0,0 +1,94 @@
+use futures_util::FutureExt as _;
+use sonor::{
+    urns::{AV_TRANSPORT, GROUP_RENDERING_CONTROL},
+    Speaker,
+};
+use tokio::sync::oneshot;
+
+use super::zoneaction::{ActionTask, Detach};
+use crate::{Error, Result};
+
+/// An action on every group, run once on each coordinator
+#[derive(Debug, Clone, Copy)]
+pub enum BulkAction {
+    Pause,
+    Resume,
+    SetVolume(u16),
+    SetMute(bool),
+}
+use BulkAction::*;
+
+impl BulkAction {
+    /// Turn the action on one group into a task, answering on `tx`
+    pub(super) fn into_task(
+        self,
+        coordinator: &Speaker,
+        tx: oneshot::Sender<Result<()>>,
+    ) -> ActionTask {
+        let coordinator = coordinator.detach();
+        async move {
+            let _ = tx.send(self.run(&coordinator).await);
+        }
+        .boxed()
+    }
+
+    async fn run(self, coordinator: &Speaker) -> Result<()> {
+        // Asked for when the action runs, since actions queued before it may
+        // have started or paused playback
+        let state = match self {
+            Pause | Resume => Some(transport_state(coordinator).await?),
+            SetVolume(_) | SetMute(_) => None,
+        };
+        match self {
+            // Pausing a group that isn't playing fails, so only groups that
+            // are are paused
+            Pause if !matches!(state.as_deref(), Some("PLAYING" | "TRANSITIONING")) => Ok(()),
+            Pause => coordinator.pause().await.map_err(Error::from),
+            // Only paused groups are resumed. Stopped groups, line-in and
+            // empty queues would start unexpectedly, or fail.
+            Resume if state.as_deref() != Some("PAUSED_PLAYBACK") => Ok(()),
+            Resume => coordinator.play().await.map_err(Error::from),
+            SetVolume(volume) => {
+                // Keeps the volumes of the speakers in proportion
+                coordinator
+                    .action(
+                        GROUP_RENDERING_CONTROL,
+                        "SnapshotGroupVolume",
+                        "<InstanceID>0</InstanceID>",
+                    )
+                    .await?;
+                let payload = format!(
+                    "<InstanceID>0</InstanceID><DesiredVolume>{}</DesiredVolume>",
+                    volume.min(100)
+                );
+                coordinator
+                    .action(GROUP_RENDERING_CONTROL, "SetGroupVolume", &payload)
+                    .await?;
+                Ok(())
+            }
+            SetMute(mute) => {
+                let payload = format!(
+                    "<InstanceID>0</InstanceID><DesiredMute>{}</DesiredMute>",
+                    u8::from(mute)
+                );
+                coordinator
+                    .action(GROUP_RENDERING_CONTROL, "SetGroupMute", &payload)
+                    .await?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Get the transport state of a coordinator, e.g. "PLAYING"
+async fn transport_state(coordinator: &Speaker) -> Result<String> {
+    coordinator
+        .action(
+            AV_TRANSPORT,
+            "GetTransportInfo",
+            "<InstanceID>0</InstanceID>",
+        )
+        .await?
+        .remove("CurrentTransportState")
+        .ok_or(Error::ZoneActionError)
+}
@@ -0,0 +1,411 @@
+use super::{household_topology, zoneaction::Detach, Controller, SpeakerData};
+use crate::{
+    scene::Scene,
+    snapshot::{join_group, leave_group},
+    types::{Step, StepReport, Uuid, ZoneName},
+    utils::parse_play_mode,
+    Error, Result,
+};
+use sonor::urns::AV_TRANSPORT;
+
+/// What planning a scene needs to know about a speaker
+#[derive(Debug, Clone, Default)]
+pub(super) struct RoomState {
+    pub uuid: Uuid,
+    pub name: String,
+    /// Coordinator of the group the speaker is in, itself if it's alone
+    pub coordinator: Uuid,
+    pub volume: Option<u16>,
+    pub mute: Option<bool>,
+    pub play_mode: Option<String>,
+    pub transport_state: Option<String>,
+}
+
+/// A step of a scene with the speaker it acts on
+#[derive(Debug, PartialEq)]
+pub(super) struct PlannedStep {
+    step: Step,
+    uuid: Uuid,
+    /// UUID of the coordinator to join
+    coordinator: Option<Uuid>,
+}
+
+/// A scene with its rooms found, to be planned once it runs
+pub(super) struct PreparedScene {
+    scene: Scene,
+    /// UUID of each room of the scene, by the handle in the scene
+    rooms: Vec<(String, Uuid)>,
+    /// Speakers of each household with rooms in the scene
+    households: Vec<Vec<SpeakerData>>,
+}
+
+impl PreparedScene {
+    fn speaker(&self, uuid: &str) -> Option<&SpeakerData> {
+        self.households
+            .iter()
+            .flatten()
+            .find(|sd| sd.speaker.uuid().eq_ignore_ascii_case(uuid))
+    }
+}
+
+/// Find the rooms of a scene, and the speakers its steps may act on. Fails
+/// if a room can't be found.
+pub(super) fn prepare(controller: &Controller, scene: Scene) -> Result<PreparedScene> {
+    let mut rooms = Vec::new();
+    let mut households = Vec::new();
+    for room in scene.groups.iter().flat_map(|g| g.rooms.iter()) {
+        let name = ZoneName {
+            household: scene.household.clone(),
+            name: room.room.clone(),
+        };
+        match controller.resolve(&name).as_slice() {
+            [(household, sd)] => {
+                rooms.push((room.room.clone(), sd.speaker.uuid().to_owned()));
+                if !households.contains(household) {
+                    households.push(*household);
+                }
+            }
+            [] => return Err(Error::ZoneDoesNotExist),
+            zones => {
+                return Err(Error::AmbiguousZoneName(
+                    zones.iter().map(|(h, _)| h.to_string()).collect(),
+                ))
+            }
+        }
+    }
+    let households = controller
+        .systems
+        .iter()
+        .filter(|s| households.contains(&&s.household))
+        .map(|s| s.speakerdata.iter().map(Detach::detach).collect())
+        .collect();
+    Ok(PreparedScene {
+        scene,
+        rooms,
+        households,
+    })
+}
+
+/// Plan a scene from the state its rooms are in now, and run it. Actions
+/// that ran before it may have changed that state since it was applied.
+pub(super) async fn apply(prepared: PreparedScene) -> Result<StepReport> {
+    let rooms = room_states(&prepared).await?;
+    let resolve = |handle: &str| {
+        prepared
+            .rooms
+            .iter()
+            .find(|(h, _)| h == handle)
+            .map(|(_, uuid)| uuid.clone())
+            .ok_or(Error::ZoneDoesNotExist)
+    };
+    let steps = plan(&prepared.scene, &rooms, resolve)?;
+    Ok(run(steps, &prepared).await)
+}
+
+/// Ask the speakers for what planning needs: the groups of every speaker of
+/// the households, and the volumes and playback of the rooms of the scene.
+/// Values that can't be read are left out, so their steps are planned.
+async fn room_states(prepared: &PreparedScene) -> Result<Vec<RoomState>> {
+    let mut rooms = Vec::new();
+    for speakers in prepared.households.iter() {
+        let (topology, _) = household_topology(speakers.iter().map(|sd| &sd.speaker))
+            .await
+            .ok_or_else(|| Error::SceneError("Unable to get the groups of the rooms".into()))?;
+        // Satellites of bonded sets have no speaker, and are left alone
+        for (coordinator, infos) in topology {
+            for info in infos {
+                if prepared.speaker(info.uuid()).is_some() {
+                    rooms.push(RoomState {
+                        uuid: info.uuid().to_owned(),
+                        name: info.name().to_owned(),
+                        coordinator: coordinator.clone(),
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+    }
+    let instance = "<InstanceID>0</InstanceID>";
+    for room in rooms.iter_mut() {
+        let in_scene = prepared
+            .rooms
+            .iter()
+            .any(|(_, uuid)| uuid.eq_ignore_ascii_case(&room.uuid));
+        let Some(sd) = prepared.speaker(&room.uuid).filter(|_| in_scene) else {
+            continue;
+        };
+        let speaker = &sd.speaker;
+        room.volume = speaker.volume().await.ok();
+        room.mute = speaker.mute().await.ok();
+        room.play_mode = speaker
+            .action(AV_TRANSPORT, "GetTransportSettings", instance)
+            .await
+            .ok()
+            .and_then(|mut settings| settings.remove("PlayMode"));
+        room.transport_state = speaker
+            .action(AV_TRANSPORT, "GetTransportInfo", instance)
+            .await
+            .ok()
+            .and_then(|mut info| info.remove("CurrentTransportState"));
+    }
+    Ok(rooms)
+}
+
+/// Find the steps that change the current state of the rooms into the
+/// scene: grouping first, then volumes, then playback. Steps that would
+/// change nothing, like setting the volume a room already has, are left
+/// out. Fails if a room of the scene can't be found.
+fn plan(
+    scene: &Scene,
+    rooms: &[RoomState],
+    resolve: impl Fn(&str) -> Result<Uuid>,
+) -> Result<Vec<PlannedStep>> {
+    let room = |handle: &str| {
+        let uuid = resolve(handle)?;
+        rooms
+            .iter()
+            .find(|r| r.uuid.eq_ignore_ascii_case(&uuid))
+            .ok_or(Error::ZoneDoesNotExist)
+    };
+    let step = |step: Step, room: &RoomState| PlannedStep {
+        step,
+        uuid: room.uuid.clone(),
+        coordinator: None,
+    };
+
+    let mut grouping = Vec::new();
+    let mut volumes = Vec::new();
+    let mut playback = Vec::new();
+    let in_scene = scene
+        .groups
+        .iter()
+        .flat_map(|g| g.rooms.iter())
+        .map(|r| room(&r.room).map(|state| state.uuid.clone()))
+        .collect::<Result<Vec<_>>>()?;
+
+    for group in scene.groups.iter() {
+        let Some(first) = group.rooms.first() else {
+            continue;
+        };
+        let coordinator = room(&first.room)?;
+        let uuid = &coordinator.uuid;
+
+        // The coordinator leaves the group it's in, or has rooms that aren't
+        // in the scene leave its group
+        if !coordinator.coordinator.eq_ignore_ascii_case(uuid) {
+            let room = coordinator.name.clone();
+            grouping.push(step(Step::Ungroup { room }, coordinator));
+        } else {
+            for member in rooms
+                .iter()
+                .filter(|r| r.coordinator.eq_ignore_ascii_case(uuid))
+            {
+                if member.uuid.eq_ignore_ascii_case(uuid)
+                    || in_scene
+                        .iter()
+                        .any(|u| u.eq_ignore_ascii_case(&member.uuid))
+                {
+                    continue;
+                }
+                let room = member.name.clone();
+                grouping.push(step(Step::Ungroup { room }, member));
+            }
+        }
+
+        for scene_room in group.rooms.iter() {
+            let state = room(&scene_room.room)?;
+            let name = state.name.clone();
+            if !state.uuid.eq_ignore_ascii_case(uuid)
+                && !state.coordinator.eq_ignore_ascii_case(uuid)
+            {
+                grouping.push(PlannedStep {
+                    coordinator: Some(uuid.clone()),
+                    ..step(
+                        Step::Join {
+                            room: name.clone(),
+                            coordinator: coordinator.name.clone(),
+                        },
+                        state,
+                    )
+                });
+            }
+            if let Some(volume) = scene_room.volume.map(|v| v.min(100)) {
+                if state.volume != Some(volume) {
+                    let room = name.clone();
+                    volumes.push(step(Step::Volume { room, volume }, state));
+                }
+            }
+            if let Some(mute) = scene_room.mute {
+                if state.mute != Some(mute) {
+                    volumes.push(step(Step::Mute { room: name, mute }, state));
+                }
+            }
+        }
+
+        // A new source replaces the queue, so its play mode is always set
+        let room = coordinator.name.clone();
+        let current_mode = match group.source {
+            Some(_) => None,
+            None => coordinator.play_mode.as_deref().map(parse_play_mode),
+        };
+        if let Some(ref source) = group.source {
+            let source = source.clone();
+            let room = room.clone();
+            playback.push(step(Step::PlaySource { room, source }, coordinator));
+        }
+        if let Some(shuffle) = group.shuffle {
+            if current_mode.map(|(s, _)| s) != Some(shuffle) {
+                let room = room.clone();
+                playback.push(step(Step::Shuffle { room, shuffle }, coordinator));
+            }
+        }
+        if let Some(repeat) = group.repeat {
+            if current_mode.map(|(_, r)| r) != Some(repeat) {
+                let room = room.clone();
+                playback.push(step(Step::Repeat { room, repeat }, coordinator));
+            }
+        }
+        if group.play
+            && group.source.is_none()
+            && coordinator.transport_state.as_deref() != Some("PLAYING")
+        {
+            playback.push(step(Step::Play { room }, coordinator));
+        }
+    }
+    grouping.sort_by_key(|s| matches!(s.step, Step::Join { .. }));
+    grouping.extend(volumes);
+    grouping.extend(playback);
+    Ok(grouping)
+}
+
+/// Run the steps of a scene in order, carrying on past steps that fail
+async fn run(steps: Vec<PlannedStep>, prepared: &PreparedScene) -> StepReport {
+    let mut report = StepReport::default();
+    for PlannedStep {
+        step,
+        uuid,
+        coordinator,
+    } in steps
+    {
+        let Some(speakerdata) = prepared.speaker(&uuid) else {
+            report.record(step, Err(Error::ZoneDoesNotExist));
+            continue;
+        };
+        let speaker = &speakerdata.speaker;
+        let result = match step {
+            Step::Ungroup { .. } => leave_group(speaker).await,
+            Step::Join { .. } => {
+                join_group(speaker, coordinator.as_deref().unwrap_or_default()).await
+            }
+            Step::Volume { volume, .. } => speaker.set_volume(volume).await.map_err(Error::from),
+            Step::Mute { mute, .. } => speaker.set_mute(mute).await.map_err(Error::from),
+            Step::PlaySource { ref source, .. } => {
+                let result = source.play_now(speakerdata).await;
+                speakerdata.queue.invalidate();
+                result
+            }
+            Step::Shuffle { shuffle, .. } => {
+                speaker.set_shuffle(shuffle).await.map_err(Error::from)
+            }
+            Step::Repeat { repeat, .. } => {
+                speaker.set_repeat_mode(repeat).await.map_err(Error::from)
+            }
+            Step::Play { .. } => speaker.play().await.map_err(Error::from),
+            // Only snapshots restore transports
+            Step::Transport { .. } => Err(Error::ZoneActionError),
+        };
+        report.record(step, result);
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::SceneGroup;
+
+    fn room(uuid: &str, name: &str, coordinator: &str, volume: u16) -> RoomState {
+        RoomState {
+            uuid: uuid.into(),
+            name: name.into(),
+            coordinator: coordinator.into(),
+            volume: Some(volume),
+            mute: Some(false),
+            play_mode: Some("SHUFFLE_NOREPEAT".into()),
+            transport_state: Some("PAUSED_PLAYBACK".into()),
+        }
+    }
+
+    fn steps(scene: &Scene, rooms: &[RoomState]) -> Result<Vec<Step>> {
+        let resolve = |handle: &str| {
+            rooms
+                .iter()
+                .find(|r| r.name.eq_ignore_ascii_case(handle))
+                .map(|r| r.uuid.clone())
+                .ok_or(Error::ZoneDoesNotExist)
+        };
+        Ok(plan(scene, rooms, resolve)?
+            .into_iter()
+            .map(|s| s.step)
+            .collect())
+    }
+
+    #[test]
+    fn test_plan() -> Result<()> {
+        // Kitchen and Bath play together, Dining is in the Office's group
+        let rooms = [
+            room("K", "Kitchen", "K", 20),
+            room("B", "Bath", "K", 10),
+            room("D", "Dining", "O", 10),
+            room("O", "Office", "O", 30),
+        ];
+        let scene = Scene::new("Dinner").group(
+            SceneGroup::new(["Kitchen", "Dining"])
+                .volume("Kitchen", 20)
+                .volume("Dining", 15)
+                .mute("Dining", false)
+                .shuffle(true)
+                .play(),
+        );
+        let room = |name: &str| name.to_owned();
+        assert_eq!(
+            steps(&scene, &rooms)?,
+            [
+                Step::Ungroup { room: room("Bath") },
+                Step::Join {
+                    room: room("Dining"),
+                    coordinator: room("Kitchen")
+                },
+                Step::Volume {
+                    room: room("Dining"),
+                    volume: 15
+                },
+                Step::Play {
+                    room: room("Kitchen")
+                },
+            ]
+        );
+
+        // A coordinator in another group leaves it before rooms join
+        let scene = Scene::new("Study").group(SceneGroup::new(["Dining", "Bath"]));
+        assert_eq!(
+            steps(&scene, &rooms)?,
+            [
+                Step::Ungroup {
+                    room: room("Dining")
+                },
+                Step::Join {
+                    room: room("Bath"),
+                    coordinator: room("Dining")
+                },
+            ]
+        );
+
+        let scene = Scene::new("Away").group(SceneGroup::new(["Kitchen", "Garage"]));
+        assert!(matches!(
+            steps(&scene, &rooms),
+            Err(Error::ZoneDoesNotExist)
+        ));
+        Ok(())
+    }
+}
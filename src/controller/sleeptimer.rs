@@ -0,0 +1,271 @@
+//! Sleep timers of the speakers, with an optional fade-out run by the manager
+
+use super::{
+    zoneaction::{respond, ActionTask},
+    Controller,
+};
+use crate::{
+    types::{Response, Uuid, ZoneActionResponder, ZoneName},
+    utils::{format_time, parse_time},
+    Error, Result,
+};
+
+use futures_util::FutureExt as _;
+use sonor::{urns::AV_TRANSPORT, Speaker};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Time between volume steps of a fade-out
+const FADE_STEP: Duration = Duration::from_secs(5);
+
+/// Start, or with `None` cancel, the sleep timer of a coordinator
+async fn configure_sleep_timer(speaker: &Speaker, duration: Option<Duration>) -> Result<()> {
+    let payload = format!(
+        "<InstanceID>0</InstanceID><NewSleepTimerDuration>{}</NewSleepTimerDuration>",
+        duration.map(format_time).unwrap_or_default()
+    );
+    speaker
+        .action(AV_TRANSPORT, "ConfigureSleepTimer", &payload)
+        .await?;
+    Ok(())
+}
+
+/// Time left on the sleep timer of a coordinator, if one is running
+async fn remaining_sleep_timer(speaker: &Speaker) -> Result<Option<Duration>> {
+    let remaining = speaker
+        .action(
+            AV_TRANSPORT,
+            "GetRemainingSleepTimerDuration",
+            "<InstanceID>0</InstanceID>",
+        )
+        .await?
+        .remove("RemainingSleepTimerDuration")
+        .ok_or(Error::ZoneActionError)?;
+    Ok(parse_time(&remaining))
+}
+
+/// Volume of a speaker after a step of a fade-out
+fn faded_volume(volume: u16, step: u32, steps: u32) -> u16 {
+    (u32::from(volume) * steps.saturating_sub(step) / steps.max(1)) as u16
+}
+
+#[derive(Debug)]
+struct Fade {
+    /// Tells this fade-out from a later one of the same group
+    id: u64,
+    handle: JoinHandle<()>,
+    /// Volumes of the group before the fade-out started, to restore
+    volumes: Arc<Mutex<Vec<(Speaker, u16)>>>,
+}
+
+/// Fade-outs waiting for or running before the end of sleep timers, by
+/// coordinator.
+#[derive(Debug, Default)]
+pub(super) struct SleepFades {
+    fades: Mutex<HashMap<Uuid, Fade>>,
+    next_id: AtomicU64,
+}
+
+impl SleepFades {
+    /// Ramp the volume of a group down over the last `fade` of `duration`,
+    /// then pause the group and restore its volume.
+    fn start(
+        self: &Arc<Self>,
+        coordinator: Speaker,
+        members: Vec<Speaker>,
+        duration: Duration,
+        fade: Duration,
+    ) {
+        let volumes = Arc::new(Mutex::new(Vec::new()));
+        let uuid = coordinator.uuid().to_owned();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        // Hold the task back until its entry is in place, so it can't remove
+        // the entry before it was added
+        let mut fades = self.lock();
+        let task = fade_out(
+            coordinator,
+            members,
+            duration.saturating_sub(fade),
+            fade.min(duration),
+            volumes.clone(),
+        );
+        let this = self.clone();
+        let key = uuid.clone();
+        let handle = tokio::spawn(async move {
+            task.await;
+            let mut fades = this.lock();
+            if fades.get(&key).is_some_and(|fade| fade.id == id) {
+                fades.remove(&key);
+            }
+        });
+        let fade = Fade {
+            id,
+            handle,
+            volumes,
+        };
+        if let Some(previous) = fades.insert(uuid, fade) {
+            previous.handle.abort();
+        }
+    }
+
+    /// Stop the fade-out of a group, restoring its volume if it had started
+    async fn cancel(&self, coordinator: &str) {
+        let fade = self.lock().remove(coordinator);
+        if let Some(fade) = fade {
+            fade.handle.abort();
+            restore_volumes(&fade.volumes).await;
+        }
+    }
+
+    /// Stop all fade-outs, e.g. when shutting down
+    pub async fn cancel_all(&self) {
+        let fades = self
+            .lock()
+            .drain()
+            .map(|(_, fade)| fade)
+            .collect::<Vec<_>>();
+        for fade in fades {
+            fade.handle.abort();
+            restore_volumes(&fade.volumes).await;
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<Uuid, Fade>> {
+        self.fades.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+async fn fade_out(
+    coordinator: Speaker,
+    members: Vec<Speaker>,
+    delay: Duration,
+    fade: Duration,
+    restore: Arc<Mutex<Vec<(Speaker, u16)>>>,
+) {
+    let mut delay = delay;
+    loop {
+        tokio::time::sleep(delay).await;
+        // The timer may have been changed or cancelled elsewhere, e.g. in the
+        // Sonos app
+        match remaining_sleep_timer(&coordinator).await {
+            Ok(None) => {
+                log::debug!("Sleep timer of {} is gone", coordinator.name());
+                return;
+            }
+            Ok(Some(left)) if left > fade + FADE_STEP => delay = left - fade,
+            Ok(_) => break,
+            Err(err) => {
+                log::debug!("Fading out {} anyway: {}", coordinator.name(), err);
+                break;
+            }
+        }
+    }
+    let mut volumes = Vec::new();
+    for speaker in members {
+        match speaker.volume().await {
+            Ok(volume) => volumes.push((speaker, volume)),
+            Err(err) => log::warn!("Not fading out {}: {}", speaker.name(), err),
+        }
+    }
+    *restore.lock().unwrap_or_else(|e| e.into_inner()) = volumes.clone();
+
+    log::debug!("Fading out {} over {:?}", coordinator.name(), fade);
+    let steps = (fade.as_secs() / FADE_STEP.as_secs()).max(1) as u32;
+    for step in 1..=steps {
+        tokio::time::sleep(fade / steps).await;
+        for (speaker, volume) in volumes.iter() {
+            let _ = speaker.set_volume(faded_volume(*volume, step, steps)).await;
+        }
+    }
+    // The sleep timer pauses too, but might not have run out yet. A timer
+    // that is gone or far from its end was cancelled or changed meanwhile.
+    match remaining_sleep_timer(&coordinator).await {
+        Ok(Some(left)) if left > FADE_STEP => {
+            log::debug!("Sleep timer of {} was changed", coordinator.name())
+        }
+        Ok(None) => log::debug!("Sleep timer of {} is gone", coordinator.name()),
+        _ => {
+            if let Err(err) = coordinator.pause().await {
+                log::debug!("Could not pause {}: {}", coordinator.name(), err);
+            }
+        }
+    }
+    restore_volumes(&restore).await;
+}
+
+async fn restore_volumes(volumes: &Mutex<Vec<(Speaker, u16)>>) {
+    let volumes = std::mem::take(&mut *volumes.lock().unwrap_or_else(|e| e.into_inner()));
+    for (speaker, volume) in volumes {
+        if let Err(err) = speaker.set_volume(volume).await {
+            log::warn!("Could not restore volume of {}: {}", speaker.name(), err);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(super) enum SleepTimerAction {
+    Set(Duration, Option<Duration>),
+    Cancel,
+    Remaining,
+}
+
+impl SleepTimerAction {
+    /// Sleep timers belong to the coordinator and act on the whole group
+    pub(super) fn into_task(
+        self,
+        controller: &Controller,
+        tx: ZoneActionResponder,
+        name: ZoneName,
+    ) -> ActionTask {
+        let Some(coordinator) = controller.get_coordinator_for_name(&name).cloned() else {
+            return async move { respond(tx, Err(Error::ZoneDoesNotExist)) }.boxed();
+        };
+        let members = controller
+            .get_group_members(coordinator.uuid())
+            .into_iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        let fades = controller.sleep_fades.clone();
+        async move {
+            let result = match self {
+                SleepTimerAction::Set(duration, fade) => {
+                    fades.cancel(coordinator.uuid()).await;
+                    let result = configure_sleep_timer(&coordinator, Some(duration)).await;
+                    if let (Ok(_), Some(fade)) = (&result, fade) {
+                        fades.start(coordinator, members, duration, fade);
+                    }
+                    result.map(Response::Ok)
+                }
+                SleepTimerAction::Cancel => {
+                    fades.cancel(coordinator.uuid()).await;
+                    configure_sleep_timer(&coordinator, None)
+                        .await
+                        .map(Response::Ok)
+                }
+                SleepTimerAction::Remaining => remaining_sleep_timer(&coordinator)
+                    .await
+                    .map(Response::SleepTimer),
+            };
+            respond(tx, result)
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_faded_volume() {
+        let steps = 4;
+        let volumes = (0..=steps)
+            .map(|step| faded_volume(30, step, steps))
+            .collect::<Vec<_>>();
+        assert_eq!(volumes, [30, 22, 15, 7, 0]);
+        assert_eq!(faded_volume(30, 1, 0), 0);
+    }
+}
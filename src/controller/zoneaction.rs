@@ -1,11 +1,14 @@
 use std::convert::TryInto;
+use std::time::Duration;
 
-use sonor::{RepeatMode, Snapshot, Speaker};
+use futures_util::{future::BoxFuture, FutureExt as _};
+use sonor::{urns::RENDERING_CONTROL, RepeatMode, Snapshot, Speaker, URN};
 
-use super::Controller;
+use super::{sleeptimer::SleepTimerAction, Controller};
 use crate::{
     controller::SpeakerData,
-    types::{Response, ZoneActionResponder},
+    nowplaying::now_playing,
+    types::{Channel, Response, ZoneActionResponder, ZoneName},
     Error, MediaSource, Result,
 };
 
@@ -31,49 +34,159 @@ pub enum ZoneAction {
     TakeSnapshot,
     ApplySnapshot(Snapshot),
     SetRelVolume(i32),
+    GetBass,
+    SetBass(i8),
+    GetTreble,
+    SetTreble(i8),
+    GetLoudness,
+    SetLoudness(bool),
+    GetBalance,
+    SetBalance(i8),
+    GetChannelVolume(Channel),
+    SetChannelVolume(Channel, u16),
+    GetNightMode,
+    SetNightMode(bool),
+    GetSpeechEnhancement,
+    SetSpeechEnhancement(bool),
+    GetSubGain,
+    SetSubGain(i8),
+    GetSurroundLevel,
+    SetSurroundLevel(i8),
+    GetSurroundEnabled,
+    SetSurroundEnabled(bool),
+    GetRenderingState,
+    GetGroupRenderingState,
+    GetNowPlaying,
+    GetPosition,
+    /// Pause after a duration, optionally fading out over the end of it
+    SetSleepTimer(Duration, Option<Duration>),
+    CancelSleepTimer,
+    GetSleepTimer,
 }
 use ZoneAction::*;
 
+/// Service only found on speakers with home theater capability
+const HT_CONTROL: &URN = &URN::service("schemas-upnp-org", "HTControl", 1);
+
+/// An action ready to run against the speakers, independent of the controller
+pub(super) type ActionTask = BoxFuture<'static, ()>;
+
+/// Owned copies of what actions need, so they can run while the controller
+/// keeps handling events and commands.
+pub(super) trait Detach {
+    type Owned;
+    fn detach(&self) -> Self::Owned;
+}
+
+impl Detach for Speaker {
+    type Owned = Speaker;
+    fn detach(&self) -> Speaker {
+        self.clone()
+    }
+}
+
+impl Detach for SpeakerData {
+    type Owned = SpeakerData;
+    fn detach(&self) -> SpeakerData {
+        SpeakerData {
+            speaker: self.speaker.clone(),
+            transport_subscription: None,
+            transport_data: self.transport_data.clone(),
+            rendering_subscription: None,
+            rendering_data: self.rendering_data.clone(),
+            group_rendering_subscription: None,
+            group_rendering_data: self.group_rendering_data.clone(),
+            content_subscription: None,
+            lost_subscriptions: Default::default(),
+            content: self.content.clone(),
+            queue: self.queue.clone(),
+            position: self.position.clone(),
+        }
+    }
+}
+
 impl ZoneAction {
-    pub(super) async fn handle_action(
+    /// Whether the action makes playback jump, so the position has to be
+    /// asked for again
+    pub(super) fn moves_position(&self) -> bool {
+        matches!(
+            self,
+            PlayNow(_)
+                | NextTrack
+                | PreviousTrack
+                | SeekTime(_)
+                | SeekTrack(_)
+                | SeekRelTrack(_)
+                | ClearQueue
+                | ApplySnapshot(_)
+        )
+    }
+
+    /// Whether the action changes the queue, so the cached queue is stale
+    pub(super) fn changes_queue(&self) -> bool {
+        matches!(self, PlayNow(_) | QueueAsNext(_) | ClearQueue)
+    }
+
+    /// Look up the speakers for the action and turn it into a task. Lookups
+    /// happen now, against the current topology; the speakers are contacted
+    /// when the task runs.
+    pub(super) fn into_task(
         self,
         controller: &Controller,
         tx: ZoneActionResponder,
-        name: String,
-    ) {
+        name: ZoneName,
+    ) -> ActionTask {
         macro_rules! data_action {
             ($data:ident.$method:ident($payload:ident: $letmethod:ident) -> $res:ident($returnval:ident) ) => {{
-                if let Some($payload) = controller.$letmethod(&name) {
-                    log::debug!(
-                        "Attempting to {:?} with {:?} in {:?}",
-                        stringify!($method),
-                        $data,
-                        name
-                    );
-                    match $data.$method($payload).await {
-                        Ok($returnval) => {
-                            let _ = tx.send(Response::$res($returnval));
-                            return;
+                let $payload = controller.$letmethod(&name).map(Detach::detach);
+                async move {
+                    if let Some($payload) = $payload {
+                        log::debug!(
+                            "Attempting to {:?} with {:?} in {:?}",
+                            stringify!($method),
+                            $data,
+                            name
+                        );
+                        match $data.$method(&$payload).await {
+                            Ok($returnval) => {
+                                let _ = tx.send(Response::$res($returnval));
+                                return;
+                            }
+                            Err(Error::Unsupported(what)) => {
+                                log::info!("{} is not supported in {}", what, name);
+                                let _ = tx.send(Response::Unsupported(what));
+                                return;
+                            }
+                            Err(e) => log::warn!("Error: {}", e),
                         }
-                        Err(e) => log::warn!("Error: {}", e),
                     }
+                    let _ = tx.send(Response::NotOk).ok();
                 }
-                let _ = tx.send(Response::NotOk).ok();
+                .boxed()
             }};
         }
         macro_rules! controller_action {
             ($payload:ident.$method:ident($($data:ident),*) : $letmethod:ident -> $res:ident($returnval:ident) ) => {{
-                if let Some($payload) = controller.$letmethod(&name) {
-                    log::debug!("Attempting to {:#?} in {}", stringify!($method), name);
-                    match $payload.$method($($data),*).await {
-                        Ok($returnval) => {
-                            let _ = tx.send(Response::$res($returnval));
-                            return;
+                let $payload = controller.$letmethod(&name).map(Detach::detach);
+                async move {
+                    if let Some($payload) = $payload {
+                        log::debug!("Attempting to {:#?} in {}", stringify!($method), name);
+                        match $payload.$method($($data),*).await.map_err(Error::from) {
+                            Ok($returnval) => {
+                                let _ = tx.send(Response::$res($returnval));
+                                return;
+                            }
+                            Err(Error::Unsupported(what)) => {
+                                log::info!("{} is not supported in {}", what, name);
+                                let _ = tx.send(Response::Unsupported(what));
+                                return;
+                            }
+                            Err(e) => log::warn!("Error: {}", e),
                         }
-                        Err(e) => log::warn!("Error: {}", e),
                     }
+                    let _ = tx.send(Response::NotOk).ok();
                 }
-                let _ = tx.send(Response::NotOk).ok();
+                .boxed()
             }};
         }
 
@@ -121,7 +234,7 @@ impl ZoneAction {
                 controller_action!( coordinator.clear_queue(): get_coordinator_for_name -> Ok(__) )
             }
             GetQueue => {
-                controller_action!( coordinator.queue(): get_coordinator_for_name -> Queue(queue) )
+                controller_action!( coordinatordata.queue(): get_coordinatordata_for_name -> Queue(queue) )
             }
             ApplySnapshot(snapshot) => {
                 controller_action!( coordinator.apply(snapshot): get_coordinator_for_name -> Ok(__) )
@@ -130,24 +243,129 @@ impl ZoneAction {
                 controller_action!( coordinator.snapshot(): get_coordinator_for_name -> Snapshot(snapshot) )
             }
             Exists => {
-                if controller
-                    .system
-                    .speakerdata
-                    .iter()
-                    .any(|s| s.speaker.name() == name)
-                {
-                    tx.send(Response::Ok(())).unwrap_or(());
-                } else {
-                    tx.send(Response::NotOk).unwrap_or(());
-                }
+                let response = match controller.resolve_zone(&name).as_slice() {
+                    [] => Response::NotOk,
+                    [(_, uuid)] => Response::Zone(uuid.clone()),
+                    zones => Response::Ambiguous(zones.iter().map(|(h, _)| h.clone()).collect()),
+                };
+                async move { tx.send(response).unwrap_or(()) }.boxed()
+            }
+            GetNowPlaying => {
+                let response = controller
+                    .get_coordinatordata_for_name(&name)
+                    .map(|sd| {
+                        let base_url = sd
+                            .speaker
+                            .device()
+                            .url()
+                            .authority()
+                            .map(|authority| format!("http://{}", authority))
+                            .unwrap_or_default();
+                        Response::NowPlaying(now_playing(&sd.transport_data, &base_url))
+                    })
+                    .unwrap_or(Response::NotOk);
+                async move { tx.send(response).unwrap_or(()) }.boxed()
+            }
+            GetPosition => {
+                controller_action!( coordinatordata.position(): get_coordinatordata_for_name -> Position(position) )
             }
+            GetRenderingState => {
+                let response = match controller.resolve(&name).as_slice() {
+                    [(_, sd)] => Response::Rendering(sd.rendering_data.clone()),
+                    _ => Response::NotOk,
+                };
+                async move { tx.send(response).unwrap_or(()) }.boxed()
+            }
+            GetGroupRenderingState => {
+                let response = controller
+                    .get_coordinatordata_for_name(&name)
+                    .map(|sd| Response::GroupRendering(sd.group_rendering_data.clone()))
+                    .unwrap_or(Response::NotOk);
+                async move { tx.send(response).unwrap_or(()) }.boxed()
+            }
+            SetSleepTimer(duration, fade) => {
+                SleepTimerAction::Set(duration, fade).into_task(controller, tx, name)
+            }
+            CancelSleepTimer => SleepTimerAction::Cancel.into_task(controller, tx, name),
+            GetSleepTimer => SleepTimerAction::Remaining.into_task(controller, tx, name),
             SetRelVolume(number) => {
                 data_action!( number.set_rel_volume(coordinator: get_coordinator_for_name) -> Ok(__) )
             }
+            GetBass => controller_action!( speaker.bass(): get_speaker_with_name -> Level(level) ),
+            SetBass(level) => {
+                data_action!( level.set_bass(speaker: get_speaker_with_name) -> Ok(__) )
+            }
+            GetTreble => {
+                controller_action!( speaker.treble(): get_speaker_with_name -> Level(level) )
+            }
+            SetTreble(level) => {
+                data_action!( level.set_treble(speaker: get_speaker_with_name) -> Ok(__) )
+            }
+            GetLoudness => {
+                controller_action!( speaker.loudness(): get_speaker_with_name -> Bool(state) )
+            }
+            SetLoudness(state) => {
+                data_action!( state.set_loudness(speaker: get_speaker_with_name) -> Ok(__) )
+            }
+            GetBalance => {
+                controller_action!( speaker.balance(): get_speaker_with_name -> Level(balance) )
+            }
+            SetBalance(balance) => {
+                controller_action!( speaker.set_balance(balance): get_speaker_with_name -> Ok(__) )
+            }
+            GetChannelVolume(channel) => {
+                controller_action!( speaker.channel_volume(channel): get_speaker_with_name -> Volume(volume) )
+            }
+            SetChannelVolume(channel, volume) => {
+                controller_action!( speaker.set_channel_volume(channel, volume): get_speaker_with_name -> Ok(__) )
+            }
+            GetNightMode => {
+                controller_action!( speaker.night_mode(): get_speaker_with_name -> Bool(state) )
+            }
+            SetNightMode(state) => {
+                controller_action!( speaker.set_night_mode(state): get_speaker_with_name -> Ok(__) )
+            }
+            GetSpeechEnhancement => {
+                controller_action!( speaker.speech_enhancement(): get_speaker_with_name -> Bool(state) )
+            }
+            SetSpeechEnhancement(state) => {
+                controller_action!( speaker.set_speech_enhancement(state): get_speaker_with_name -> Ok(__) )
+            }
+            GetSubGain => {
+                controller_action!( speaker.sub_gain(): get_speaker_with_name -> Level(level) )
+            }
+            SetSubGain(level) => {
+                controller_action!( speaker.set_sub_gain(level): get_speaker_with_name -> Ok(__) )
+            }
+            GetSurroundLevel => {
+                controller_action!( speaker.surround_level(): get_speaker_with_name -> Level(level) )
+            }
+            SetSurroundLevel(level) => {
+                controller_action!( speaker.set_surround_level(level): get_speaker_with_name -> Ok(__) )
+            }
+            GetSurroundEnabled => {
+                controller_action!( speaker.surround_enabled(): get_speaker_with_name -> Bool(state) )
+            }
+            SetSurroundEnabled(state) => {
+                controller_action!( speaker.set_surround_enabled(state): get_speaker_with_name -> Ok(__) )
+            }
         }
     }
 }
 
+/// Answer an action with its result, reporting unsupported capabilities
+pub(super) fn respond(tx: ZoneActionResponder, result: Result<Response>) {
+    let response = match result {
+        Ok(response) => response,
+        Err(Error::Unsupported(what)) => Response::Unsupported(what),
+        Err(err) => {
+            log::warn!("Error: {}", err);
+            Response::NotOk
+        }
+    };
+    let _ = tx.send(response);
+}
+
 trait ZoneActionRepeatModeExt {
     async fn set(self, coordinator: &Speaker) -> Result<()>;
 }
@@ -161,6 +379,7 @@ impl ZoneActionRepeatModeExt for RepeatMode {
 trait ZoneActionBoolExt {
     async fn set_shuffle(self, speaker: &Speaker) -> Result<()>;
     async fn set_crossfade(self, speaker: &Speaker) -> Result<()>;
+    async fn set_loudness(self, speaker: &Speaker) -> Result<()>;
 }
 
 impl ZoneActionBoolExt for bool {
@@ -170,6 +389,75 @@ impl ZoneActionBoolExt for bool {
     async fn set_crossfade(self, speaker: &Speaker) -> Result<()> {
         speaker.set_crossfade(self).await.map_err(Error::from)
     }
+    async fn set_loudness(self, speaker: &Speaker) -> Result<()> {
+        speaker.set_loudness(self).await.map_err(Error::from)
+    }
+}
+
+trait ZoneActionLevelExt {
+    async fn set_bass(self, speaker: &Speaker) -> Result<()>;
+    async fn set_treble(self, speaker: &Speaker) -> Result<()>;
+}
+
+impl ZoneActionLevelExt for i8 {
+    async fn set_bass(self, speaker: &Speaker) -> Result<()> {
+        speaker.set_bass(self).await.map_err(Error::from)
+    }
+    async fn set_treble(self, speaker: &Speaker) -> Result<()> {
+        speaker.set_treble(self).await.map_err(Error::from)
+    }
+}
+
+trait ZoneActionSpeakerExt {
+    async fn channel_volume(&self, channel: Channel) -> Result<u16>;
+    async fn set_channel_volume(&self, channel: Channel, volume: u16) -> Result<()>;
+    async fn balance(&self) -> Result<i8>;
+    async fn set_balance(&self, balance: i8) -> Result<()>;
+}
+
+impl ZoneActionSpeakerExt for Speaker {
+    async fn channel_volume(&self, channel: Channel) -> Result<u16> {
+        let payload = format!(
+            "<InstanceID>0</InstanceID><Channel>{}</Channel>",
+            channel.as_str()
+        );
+        self.action(RENDERING_CONTROL, "GetVolume", &payload)
+            .await?
+            .get("CurrentVolume")
+            .and_then(|volume| volume.parse().ok())
+            .ok_or(Error::ZoneActionError)
+    }
+
+    async fn set_channel_volume(&self, channel: Channel, volume: u16) -> Result<()> {
+        let payload = format!(
+            "<InstanceID>0</InstanceID><Channel>{}</Channel><DesiredVolume>{}</DesiredVolume>",
+            channel.as_str(),
+            volume.min(100)
+        );
+        self.action(RENDERING_CONTROL, "SetVolume", &payload)
+            .await
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    /// Balance from -100 (left only) to 100 (right only), derived from the
+    /// left and right channel volumes like the sonos app does.
+    async fn balance(&self) -> Result<i8> {
+        let left = self.channel_volume(Channel::Left).await?.min(100) as i8;
+        let right = self.channel_volume(Channel::Right).await?.min(100) as i8;
+        Ok(right - left)
+    }
+
+    async fn set_balance(&self, balance: i8) -> Result<()> {
+        let balance = balance.clamp(-100, 100);
+        let (left, right) = if balance < 0 {
+            (100, 100 + balance)
+        } else {
+            (100 - balance, 100)
+        };
+        self.set_channel_volume(Channel::Left, left as u16).await?;
+        self.set_channel_volume(Channel::Right, right as u16).await
+    }
 }
 
 trait ZoneActionUnsignedNExt {
@@ -219,3 +507,85 @@ impl ZoneActionSignedNExt for i32 {
             .map_err(Error::from)
     }
 }
+
+trait ZoneActionHomeTheaterExt {
+    fn is_home_theater(&self) -> bool;
+    async fn ht_eq(&self, eq_type: &str) -> Result<i16>;
+    async fn set_ht_eq(&self, eq_type: &str, value: i16) -> Result<()>;
+    async fn night_mode(&self) -> Result<bool>;
+    async fn set_night_mode(&self, state: bool) -> Result<()>;
+    async fn speech_enhancement(&self) -> Result<bool>;
+    async fn set_speech_enhancement(&self, state: bool) -> Result<()>;
+    async fn sub_gain(&self) -> Result<i8>;
+    async fn set_sub_gain(&self, level: i8) -> Result<()>;
+    async fn surround_level(&self) -> Result<i8>;
+    async fn set_surround_level(&self, level: i8) -> Result<()>;
+    async fn surround_enabled(&self) -> Result<bool>;
+    async fn set_surround_enabled(&self, state: bool) -> Result<()>;
+}
+
+impl ZoneActionHomeTheaterExt for Speaker {
+    fn is_home_theater(&self) -> bool {
+        self.device().find_service(HT_CONTROL).is_some()
+    }
+
+    /// Get a home theater EQ value from RenderingControl
+    async fn ht_eq(&self, eq_type: &str) -> Result<i16> {
+        if !self.is_home_theater() {
+            return Err(Error::Unsupported(eq_type.to_owned()));
+        }
+        let payload = format!("<InstanceID>0</InstanceID><EQType>{}</EQType>", eq_type);
+        self.action(RENDERING_CONTROL, "GetEQ", &payload)
+            .await?
+            .get("CurrentValue")
+            .and_then(|value| value.parse().ok())
+            .ok_or(Error::ZoneActionError)
+    }
+
+    /// Set a home theater EQ value with RenderingControl
+    async fn set_ht_eq(&self, eq_type: &str, value: i16) -> Result<()> {
+        if !self.is_home_theater() {
+            return Err(Error::Unsupported(eq_type.to_owned()));
+        }
+        let payload = format!(
+            "<InstanceID>0</InstanceID><EQType>{}</EQType><DesiredValue>{}</DesiredValue>",
+            eq_type, value
+        );
+        self.action(RENDERING_CONTROL, "SetEQ", &payload)
+            .await
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    async fn night_mode(&self) -> Result<bool> {
+        Ok(self.ht_eq("NightMode").await? != 0)
+    }
+    async fn set_night_mode(&self, state: bool) -> Result<()> {
+        self.set_ht_eq("NightMode", state.into()).await
+    }
+    async fn speech_enhancement(&self) -> Result<bool> {
+        Ok(self.ht_eq("DialogLevel").await? != 0)
+    }
+    async fn set_speech_enhancement(&self, state: bool) -> Result<()> {
+        self.set_ht_eq("DialogLevel", state.into()).await
+    }
+    async fn sub_gain(&self) -> Result<i8> {
+        Ok(self.ht_eq("SubGain").await?.clamp(-15, 15) as i8)
+    }
+    async fn set_sub_gain(&self, level: i8) -> Result<()> {
+        self.set_ht_eq("SubGain", level.clamp(-15, 15).into()).await
+    }
+    async fn surround_level(&self) -> Result<i8> {
+        Ok(self.ht_eq("SurroundLevel").await?.clamp(-15, 15) as i8)
+    }
+    async fn set_surround_level(&self, level: i8) -> Result<()> {
+        self.set_ht_eq("SurroundLevel", level.clamp(-15, 15).into())
+            .await
+    }
+    async fn surround_enabled(&self) -> Result<bool> {
+        Ok(self.ht_eq("SurroundEnable").await? != 0)
+    }
+    async fn set_surround_enabled(&self, state: bool) -> Result<()> {
+        self.set_ht_eq("SurroundEnable", state.into()).await
+    }
+}
@@ -21,10 +21,44 @@ pub enum Error {
     /// Zone does not exist
     #[error("The requested zone name is not valid")]
     ZoneDoesNotExist,
+    /// Zone name exists in more than one household
+    #[error("The requested zone name exists in several households: {0:?}")]
+    AmbiguousZoneName(Vec<String>),
+    /// A known household could not be found on the network
+    #[error("Could not find household {0}")]
+    HouseholdNotFound(String),
+    /// A speaker did not report the household it belongs to
+    #[error("Speaker {0} did not report a household ID")]
+    MissingHouseholdId(String),
     /// Error encountered on zone action
     #[error("Error encountered performing zone action")]
     ZoneActionError,
+    /// Could not make a speaker URL from an address
+    #[error("Invalid speaker address: {0:?}")]
+    InvalidAddress(String),
+    /// Could not read or write the topology cache
+    #[error("Topology cache error: {0}")]
+    CacheError(String),
+    /// The speaker does not have the capability for an action
+    #[error("{0} is not supported by this speaker")]
+    Unsupported(String),
     /// Could not parse content
     #[error("Could not find the requested content")]
     ContentNotFound,
+    /// Streams such as radio stations, and albums started at a track, can
+    /// only be played, not queued
+    #[error("The requested content cannot be added to the queue")]
+    NotQueueable,
+    /// A house snapshot could not be read, or doesn't match the system
+    #[error("Invalid house snapshot: {0}")]
+    SnapshotError(String),
+    /// An alarm can't be saved as it is
+    #[error("Invalid alarm: {0}")]
+    InvalidAlarm(String),
+    /// No scene with this name was added
+    #[error("Scene {0:?} not found")]
+    SceneNotFound(String),
+    /// Scenes could not be loaded
+    #[error("Could not load scenes: {0}")]
+    SceneError(String),
 }
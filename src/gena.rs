@@ -0,0 +1,778 @@
+//! GENA event subscriptions: a listener for the NOTIFY requests of speakers,
+//! and the SUBSCRIBE, renewal and UNSUBSCRIBE requests that point speakers
+//! at it.
+
+use crate::{builder::CallbackConfig, Error::SubscriberError, Result};
+use log::{debug, warn};
+use roxmltree::Document;
+use sonor::rupnp::http::Uri;
+use std::collections::HashMap;
+use std::mem;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpListener, TcpStream};
+use tokio::sync::{mpsc, OnceCell};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+
+/// How long speakers get to answer subscription requests, and to send a
+/// NOTIFY once they started
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Largest NOTIFY accepted. Topology of big households runs to a few 100 kB.
+const MAX_BODY: usize = 4 << 20;
+const MAX_HEADERS: usize = 16 << 10;
+/// Events of a subscription waiting to be read. Beyond this, speakers wait
+/// for the answer to their NOTIFY until there is room.
+const NOTIFY_QUEUE_SIZE: usize = 32;
+/// How long an event that came early waits for the ones before it
+const REORDER_WAIT: Duration = Duration::from_secs(1);
+
+/// An event from a speaker
+#[derive(Debug, Clone)]
+pub(crate) struct Notification {
+    pub sid: String,
+    /// Event key, 0 for the initial event of a subscription
+    pub seq: Option<u32>,
+    pub vars: HashMap<String, String>,
+}
+
+/// Keeps track of the event keys of a subscription, to notice missed events
+#[derive(Debug, Default)]
+pub(crate) struct Sequence {
+    next: Option<u32>,
+}
+
+/// How an event key fits the ones before
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum SeqCheck {
+    InOrder,
+    /// This many events were skipped
+    Missed(u32),
+    /// The keys started over, e.g. because the speaker restarted
+    Restarted,
+}
+
+impl Sequence {
+    /// Check the key of the next event
+    pub fn check(&mut self, seq: u32) -> SeqCheck {
+        let check = match self.next {
+            None if seq == 0 => SeqCheck::InOrder,
+            None => SeqCheck::Missed(seq),
+            Some(next) if seq == next => SeqCheck::InOrder,
+            Some(next) if seq > next => SeqCheck::Missed(seq - next),
+            Some(_) => SeqCheck::Restarted,
+        };
+        // Keys wrap around to 1, since 0 is for the initial event
+        self.next = Some(seq.checked_add(1).unwrap_or(1));
+        check
+    }
+
+    /// Start over for a new subscription
+    pub fn reset(&mut self) {
+        self.next = None;
+    }
+}
+
+/// Puts the events of a subscription back in order. Each NOTIFY comes on a
+/// connection of its own, so one can overtake the one sent before it. An
+/// event after a gap is held until the missing ones arrive, or until
+/// [`REORDER_WAIT`] passes and they count as missed.
+#[derive(Debug, Default)]
+pub(crate) struct Reorder {
+    next: Option<u32>,
+    held: Vec<Notification>,
+    /// When the oldest held event should be let through anyway
+    deadline: Option<Instant>,
+}
+
+impl Reorder {
+    /// Take the next event, and get the events that are now in order
+    pub fn push(&mut self, notification: Notification) -> Vec<Notification> {
+        let Some(seq) = notification.seq else {
+            return vec![notification];
+        };
+        // The initial event of a subscription always goes through
+        if let Some(next) = self.next.filter(|&next| seq != next && seq != 0) {
+            if seq.wrapping_sub(next) > u32::MAX / 2 {
+                // Overtaken by later events that were let through
+                debug!("Dropping late event {} of {}", seq, notification.sid);
+            } else {
+                self.held.push(notification);
+                self.deadline
+                    .get_or_insert_with(|| Instant::now() + REORDER_WAIT);
+            }
+            return Vec::new();
+        }
+        let mut ready = vec![notification];
+        self.advance(seq, &mut ready);
+        ready
+    }
+
+    /// When held events should be let through, if any are held
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Let the held events through, in order, after waiting too long for the
+    /// ones before them
+    pub fn flush(&mut self) -> Vec<Notification> {
+        let Some(next) = self.next else {
+            return Vec::new();
+        };
+        let mut ready = mem::take(&mut self.held);
+        ready.sort_by_key(|n| n.seq.unwrap_or_default().wrapping_sub(next));
+        if let Some(seq) = ready.last().and_then(|n| n.seq) {
+            self.advance(seq, &mut ready);
+        }
+        ready
+    }
+
+    /// Start over for a new subscription
+    pub fn reset(&mut self) {
+        *self = Reorder::default();
+    }
+
+    /// Move past `seq`, along with any held events that follow it
+    fn advance(&mut self, mut seq: u32, ready: &mut Vec<Notification>) {
+        loop {
+            // Keys wrap around to 1, since 0 is for the initial event
+            let next = seq.checked_add(1).unwrap_or(1);
+            self.next = Some(next);
+            match self.held.iter().position(|n| n.seq == Some(next)) {
+                Some(i) => {
+                    ready.push(self.held.remove(i));
+                    seq = next;
+                }
+                None => break,
+            }
+        }
+        self.deadline = if self.held.is_empty() {
+            None
+        } else {
+            Some(Instant::now() + REORDER_WAIT)
+        };
+    }
+}
+
+type Routes = Mutex<HashMap<String, mpsc::Sender<Notification>>>;
+/// Event URLs of the services of a device, by service type
+type EventUrls = HashMap<String, Uri>;
+
+/// Listens for the events of all subscriptions and hands each to the
+/// subscription it was sent for, by the path of its callback URL.
+#[derive(Debug)]
+pub(crate) struct CallbackServer {
+    config: CallbackConfig,
+    port: u16,
+    routes: Arc<Routes>,
+    next_path: AtomicU64,
+    /// Event URLs of every device subscribed to, by the URL of its
+    /// description, so that each description is fetched once
+    event_urls: Mutex<HashMap<String, Arc<OnceCell<EventUrls>>>>,
+    task: JoinHandle<()>,
+}
+
+impl CallbackServer {
+    /// Bind the listener to the first free port of the configured range and
+    /// start accepting events.
+    pub async fn start(config: CallbackConfig) -> Result<Arc<CallbackServer>> {
+        let mut listener = None;
+        for port in config.ports.clone() {
+            match TcpListener::bind((config.bind, port)).await {
+                Ok(l) => {
+                    listener = Some(l);
+                    break;
+                }
+                Err(err) => debug!("Unable to listen on {}:{}: {}", config.bind, port, err),
+            }
+        }
+        let listener = listener.ok_or_else(|| {
+            SubscriberError(format!(
+                "No free port for the event listener on {} in {:?}",
+                config.bind, config.ports
+            ))
+        })?;
+        let port = listener.local_addr().map_err(io_error)?.port();
+        debug!("Listening for events on {}:{}", config.bind, port);
+
+        let routes = Arc::new(Routes::default());
+        let task = tokio::spawn(accept(listener, routes.clone()));
+        Ok(Arc::new(CallbackServer {
+            config,
+            port,
+            routes,
+            next_path: AtomicU64::new(1),
+            event_urls: Mutex::default(),
+            task,
+        }))
+    }
+
+    /// Make a callback URL for a speaker to send the events of one
+    /// subscription to. Events arrive on the receiver of the registration
+    /// until it is dropped.
+    pub async fn register(&self, device_url: &Uri) -> Result<Registration> {
+        let host = match self.config.host {
+            Some(ref host) => host.clone(),
+            None => match self.config.bind {
+                ip if !ip.is_unspecified() => url_host(ip),
+                _ => {
+                    let local_ip = match device_url.host() {
+                        Some(host) => local_ip_towards(host).await,
+                        None => None,
+                    };
+                    local_ip.map(url_host).ok_or_else(|| {
+                        SubscriberError(format!("No local address reaches {}", device_url))
+                    })?
+                }
+            },
+        };
+        let port = self.config.port.unwrap_or(self.port);
+        let path = format!("/{}", self.next_path.fetch_add(1, Ordering::Relaxed));
+        let (tx, rx) = mpsc::channel(NOTIFY_QUEUE_SIZE);
+        lock(&self.routes).insert(path.clone(), tx);
+        Ok(Registration {
+            callback: format!("http://{}:{}{}", host, port, path),
+            path,
+            routes: self.routes.clone(),
+            rx,
+        })
+    }
+
+    /// Get the event subscription URL of a service of the device at
+    /// `device_url`. The description of the device is only fetched for the
+    /// first of its subscriptions; resubscriptions reuse what it said.
+    pub async fn event_url(&self, device_url: &Uri, service_type: &str) -> Result<Uri> {
+        let urls = lock(&self.event_urls)
+            .entry(device_url.to_string())
+            .or_default()
+            .clone();
+        urls.get_or_try_init(|| event_urls(device_url))
+            .await?
+            .get(service_type)
+            .cloned()
+            .ok_or_else(|| SubscriberError(format!("No eventSubURL for {}", service_type)))
+    }
+
+    /// The event URLs known so far, as (service type, URL), by the URL of
+    /// the description of their device
+    pub fn known_event_urls(&self) -> Vec<(String, Vec<(String, String)>)> {
+        lock(&self.event_urls)
+            .iter()
+            .filter_map(|(device_url, urls)| {
+                let urls = urls
+                    .get()?
+                    .iter()
+                    .map(|(typ, url)| (typ.clone(), url.to_string()))
+                    .collect();
+                Some((device_url.clone(), urls))
+            })
+            .collect()
+    }
+
+    /// Take the event URLs of a device from a previous run, so its
+    /// description isn't fetched again for them. URLs that don't parse are
+    /// left out.
+    pub fn add_event_urls(&self, device_url: String, urls: Vec<(String, String)>) {
+        let urls = urls
+            .into_iter()
+            .filter_map(|(typ, url)| Some((typ, url.parse().ok()?)))
+            .collect::<EventUrls>();
+        lock(&self.event_urls)
+            .entry(device_url)
+            .or_insert_with(|| Arc::new(OnceCell::from(urls)));
+    }
+}
+
+impl Drop for CallbackServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Where one subscription receives its events
+#[derive(Debug)]
+pub(crate) struct Registration {
+    /// URL that speakers are asked to send events to
+    pub callback: String,
+    path: String,
+    routes: Arc<Routes>,
+    pub rx: mpsc::Receiver<Notification>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        lock(&self.routes).remove(&self.path);
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Host as written in a URL
+fn url_host(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{}]", ip),
+    }
+}
+
+/// Local address of the interface that routes to a host, which may be a
+/// name. Connecting a UDP socket sends nothing, it only picks the route.
+async fn local_ip_towards(host: &str) -> Option<IpAddr> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let target = lookup_host((host, 1400)).await.ok()?.next()?;
+    let unspecified: IpAddr = match target {
+        SocketAddr::V4(_) => [0, 0, 0, 0].into(),
+        SocketAddr::V6(_) => [0u16; 8].into(),
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(unspecified, 0)).ok()?;
+    socket.connect(target).ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
+
+async fn accept(listener: TcpListener, routes: Arc<Routes>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let routes = routes.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = handle_notify(stream, &routes).await {
+                        debug!("Bad event from {}: {}", peer, err);
+                    }
+                });
+            }
+            Err(err) => {
+                warn!("Unable to accept event connection: {}", err);
+                time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+/// Read one NOTIFY request, pass it on and answer it
+async fn handle_notify(mut stream: TcpStream, routes: &Routes) -> Result<()> {
+    let result = time::timeout(REQUEST_TIMEOUT, read_message(&mut stream))
+        .await
+        .map_err(|_| SubscriberError("Timed out reading event".into()))
+        .and_then(|r| r);
+    let status = match result {
+        Ok((start, headers, body)) => {
+            let mut parts = start.split_whitespace();
+            let (method, path) = (parts.next(), parts.next().unwrap_or_default());
+            let route = lock(routes).get(path).cloned();
+            match (method, route, header(&headers, "SID")) {
+                (Some("NOTIFY"), Some(tx), Some(sid)) => {
+                    match parse_propertyset(&String::from_utf8_lossy(&body)) {
+                        Ok(vars) => {
+                            let sid = sid.to_owned();
+                            let seq = header(&headers, "SEQ").and_then(|s| s.parse().ok());
+                            let notification = Notification { sid, seq, vars };
+                            // Waits for room, holding off the speaker, so no
+                            // event is dropped
+                            match tx.send(notification).await {
+                                Ok(_) => "200 OK",
+                                Err(_) => "412 Precondition Failed",
+                            }
+                        }
+                        Err(err) => {
+                            debug!("{}", err);
+                            "400 Bad Request"
+                        }
+                    }
+                }
+                (Some("NOTIFY"), ..) => "412 Precondition Failed",
+                _ => "405 Method Not Allowed",
+            }
+        }
+        Err(err) => {
+            debug!("{}", err);
+            "400 Bad Request"
+        }
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status
+    );
+    stream
+        .write_all(response.as_bytes())
+        .await
+        .map_err(io_error)?;
+    stream.shutdown().await.ok();
+    Ok(())
+}
+
+type Headers = Vec<(String, String)>;
+
+fn header<'a>(headers: &'a Headers, name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+/// Read an HTTP message into its start line, headers and body
+async fn read_message(stream: &mut (impl AsyncRead + Unpin)) -> Result<(String, Headers, Vec<u8>)> {
+    let mut buf = Vec::new();
+    let head_len = loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i;
+        }
+        if buf.len() > MAX_HEADERS || read_some(stream, &mut buf).await? == 0 {
+            return Err(SubscriberError("Incomplete HTTP headers".into()));
+        }
+    };
+    let head = String::from_utf8_lossy(&buf[..head_len]).into_owned();
+    let mut body = buf.split_off(head_len + 4);
+    let mut lines = head.split("\r\n");
+    let start = lines.next().unwrap_or_default().to_owned();
+    let headers: Headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_owned(), value.trim().to_owned()))
+        .collect();
+
+    if let Some(len) = header(&headers, "Content-Length") {
+        let len: usize = len
+            .parse()
+            .map_err(|_| SubscriberError(format!("Bad Content-Length {:?}", len)))?;
+        if len > MAX_BODY {
+            return Err(SubscriberError(format!(
+                "Body of {} bytes is too large",
+                len
+            )));
+        }
+        while body.len() < len {
+            if read_some(stream, &mut body).await? == 0 {
+                return Err(SubscriberError("Incomplete HTTP body".into()));
+            }
+        }
+        body.truncate(len);
+    } else if header(&headers, "Transfer-Encoding").is_some_and(|te| te.contains("chunked")) {
+        body = loop {
+            if let Some(body) = dechunk(&body)? {
+                break body;
+            }
+            if body.len() > MAX_BODY || read_some(stream, &mut body).await? == 0 {
+                return Err(SubscriberError("Incomplete chunked HTTP body".into()));
+            }
+        };
+    } else if !start.starts_with("HTTP/") {
+        // A request without length has no body
+        body.clear();
+    } else {
+        while read_some(stream, &mut body).await? > 0 {
+            if body.len() > MAX_BODY {
+                return Err(SubscriberError("HTTP body is too large".into()));
+            }
+        }
+    }
+    Ok((start, headers, body))
+}
+
+async fn read_some(stream: &mut (impl AsyncRead + Unpin), buf: &mut Vec<u8>) -> Result<usize> {
+    let mut chunk = [0; 8192];
+    let n = stream.read(&mut chunk).await.map_err(io_error)?;
+    buf.extend_from_slice(&chunk[..n]);
+    Ok(n)
+}
+
+/// Decode a chunked body, or `None` if it isn't complete yet
+fn dechunk(mut data: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut body = Vec::new();
+    loop {
+        let Some(i) = data.windows(2).position(|w| w == b"\r\n") else {
+            return Ok(None);
+        };
+        let size = String::from_utf8_lossy(&data[..i]);
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| SubscriberError(format!("Bad chunk size {:?}", size)))?;
+        data = &data[i + 2..];
+        if size == 0 {
+            return Ok(Some(body));
+        }
+        if data.len() < size + 2 {
+            return Ok(None);
+        }
+        body.extend_from_slice(&data[..size]);
+        data = &data[size + 2..];
+    }
+}
+
+/// The state variables of a NOTIFY body
+pub(crate) fn parse_propertyset(xml: &str) -> Result<HashMap<String, String>> {
+    let doc = Document::parse(xml).map_err(|e| SubscriberError(e.to_string()))?;
+    Ok(doc
+        .descendants()
+        .filter(|n| n.tag_name().name() == "property")
+        .flat_map(|property| property.children().filter(|n| n.is_element()))
+        .map(|var| {
+            let value = var.text().unwrap_or_default().to_owned();
+            (var.tag_name().name().to_owned(), value)
+        })
+        .collect())
+}
+
+/// Get the event subscription URLs of the services of the device at
+/// `device_url`, from its description.
+async fn event_urls(device_url: &Uri) -> Result<EventUrls> {
+    let (_, body) = request(device_url, "GET", &[]).await?;
+    event_sub_paths(&String::from_utf8_lossy(&body))?
+        .into_iter()
+        .map(|(typ, path)| Ok((typ, join_url(device_url, &path)?)))
+        .collect()
+}
+
+/// The eventSubURL of every service in a device description, by service
+/// type. Services that more than one embedded device offers, like the
+/// ConnectionManager of Sonos speakers, are taken from the first device, as
+/// rupnp finds them.
+fn event_sub_paths(xml: &str) -> Result<HashMap<String, String>> {
+    fn child_text<'a>(node: roxmltree::Node<'a, '_>, name: &str) -> Option<&'a str> {
+        node.children()
+            .find(|n| n.tag_name().name() == name)
+            .and_then(|n| n.text())
+            .map(str::trim)
+    }
+    let doc = Document::parse(xml).map_err(|e| SubscriberError(e.to_string()))?;
+    let mut paths = HashMap::new();
+    for service in doc
+        .descendants()
+        .filter(|n| n.tag_name().name() == "service")
+    {
+        // urn:schemas-upnp-org:service:AVTransport:1
+        let typ = child_text(service, "serviceType").and_then(|urn| urn.split(':').nth(3));
+        if let (Some(typ), Some(path)) = (typ, child_text(service, "eventSubURL")) {
+            paths
+                .entry(typ.to_owned())
+                .or_insert_with(|| path.to_owned());
+        }
+    }
+    Ok(paths)
+}
+
+/// Resolve a URL of a device description against the description's URL
+fn join_url(base: &Uri, path: &str) -> Result<Uri> {
+    let url = match path {
+        absolute if absolute.contains("://") => absolute.to_owned(),
+        path => {
+            let authority = base.authority().map(|a| a.as_str()).unwrap_or_default();
+            let slash = if path.starts_with('/') { "" } else { "/" };
+            format!(
+                "{}://{}{}{}",
+                base.scheme_str().unwrap_or("http"),
+                authority,
+                slash,
+                path
+            )
+        }
+    };
+    url.parse()
+        .map_err(|_| SubscriberError(format!("Bad event URL {}", url)))
+}
+
+/// Ask a speaker to send the events of a service to a callback URL. Returns
+/// the subscription ID.
+pub(crate) async fn subscribe(event_url: &Uri, callback: &str, timeout_sec: u32) -> Result<String> {
+    let callback = format!("<{}>", callback);
+    let timeout = format!("Second-{}", timeout_sec);
+    let headers = [
+        ("CALLBACK", callback.as_str()),
+        ("NT", "upnp:event"),
+        ("TIMEOUT", timeout.as_str()),
+    ];
+    let (response, _) = request(event_url, "SUBSCRIBE", &headers).await?;
+    header(&response, "SID")
+        .map(str::to_owned)
+        .ok_or_else(|| SubscriberError("No SID in subscription response".into()))
+}
+
+/// Extend a subscription before it times out
+pub(crate) async fn renew(event_url: &Uri, sid: &str, timeout_sec: u32) -> Result<()> {
+    let timeout = format!("Second-{}", timeout_sec);
+    let headers = [("SID", sid), ("TIMEOUT", timeout.as_str())];
+    request(event_url, "SUBSCRIBE", &headers).await?;
+    Ok(())
+}
+
+pub(crate) async fn unsubscribe(event_url: &Uri, sid: &str) -> Result<()> {
+    request(event_url, "UNSUBSCRIBE", &[("SID", sid)]).await?;
+    Ok(())
+}
+
+/// Send a request without body to a speaker, returning the response headers
+/// and body
+async fn request(url: &Uri, method: &str, headers: &[(&str, &str)]) -> Result<(Headers, Vec<u8>)> {
+    let host = url
+        .host()
+        .ok_or_else(|| SubscriberError(format!("No host in {}", url)))?;
+    let port = url.port_u16().unwrap_or(1400);
+    let path = url.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let mut message = format!(
+        "{} {} HTTP/1.1\r\nHOST: {}:{}\r\n",
+        method, path, host, port
+    );
+    for (name, value) in headers {
+        message += &format!("{}: {}\r\n", name, value);
+    }
+    message += "Content-Length: 0\r\nConnection: close\r\n\r\n";
+
+    let exchange = async {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let mut stream = TcpStream::connect((host, port)).await.map_err(io_error)?;
+        stream
+            .write_all(message.as_bytes())
+            .await
+            .map_err(io_error)?;
+        read_message(&mut stream).await
+    };
+    let (status, headers, body) = time::timeout(REQUEST_TIMEOUT, exchange)
+        .await
+        .map_err(|_| SubscriberError(format!("{} to {} timed out", method, url)))??;
+    match status.split_whitespace().nth(1) {
+        Some("200") => Ok((headers, body)),
+        _ => Err(SubscriberError(format!(
+            "{} {} failed: {}",
+            method, url, status
+        ))),
+    }
+}
+
+fn io_error(err: std::io::Error) -> crate::Error {
+    SubscriberError(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_propertyset() {
+        let xml = r#"<e:propertyset xmlns:e="urn:schemas-upnp-org:event-1-0"><e:property><LastChange>&lt;Event&gt;&lt;/Event&gt;</LastChange></e:property><e:property><GroupVolume>12</GroupVolume></e:property></e:propertyset>"#;
+        let vars = parse_propertyset(xml).unwrap();
+        assert_eq!(vars["LastChange"], "<Event></Event>");
+        assert_eq!(vars["GroupVolume"], "12");
+        assert_eq!(
+            dechunk(b"4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n").unwrap(),
+            Some(b"Wikipedia".to_vec())
+        );
+        assert_eq!(dechunk(b"4\r\nWi").unwrap(), None);
+    }
+
+    #[test]
+    fn test_event_sub_path() {
+        let xml = r#"<root xmlns="urn:schemas-upnp-org:device-1-0"><device>
+            <serviceList><service><serviceType>urn:schemas-upnp-org:service:AlarmClock:1</serviceType><eventSubURL>/AlarmClock/Event</eventSubURL></service></serviceList>
+            <deviceList>
+              <device><serviceList>
+                <service><serviceType>urn:schemas-upnp-org:service:ContentDirectory:1</serviceType><eventSubURL>/MediaServer/ContentDirectory/Event</eventSubURL></service>
+                <service><serviceType>urn:schemas-upnp-org:service:ConnectionManager:1</serviceType><eventSubURL>/MediaServer/ConnectionManager/Event</eventSubURL></service>
+              </serviceList></device>
+              <device><serviceList>
+                <service><serviceType>urn:schemas-upnp-org:service:ConnectionManager:1</serviceType><eventSubURL>/MediaRenderer/ConnectionManager/Event</eventSubURL></service>
+                <service><serviceType>urn:schemas-upnp-org:service:AVTransport:1</serviceType><eventSubURL>/MediaRenderer/AVTransport/Event</eventSubURL></service>
+              </serviceList></device>
+            </deviceList>
+        </device></root>"#;
+        let paths = event_sub_paths(xml).unwrap();
+        let path = |typ| paths.get(typ).map(String::as_str);
+        assert_eq!(path("AlarmClock"), Some("/AlarmClock/Event"));
+        assert_eq!(
+            path("ConnectionManager"),
+            Some("/MediaServer/ConnectionManager/Event")
+        );
+        assert_eq!(
+            path("AVTransport"),
+            Some("/MediaRenderer/AVTransport/Event")
+        );
+        assert_eq!(path("Queue"), None);
+
+        let base: Uri = "http://kitchen.local:1400/xml/device_description.xml"
+            .parse()
+            .unwrap();
+        let url = join_url(&base, "MediaRenderer/AVTransport/Event").unwrap();
+        assert_eq!(
+            url.to_string(),
+            "http://kitchen.local:1400/MediaRenderer/AVTransport/Event"
+        );
+    }
+
+    #[test]
+    fn test_sequence() {
+        let mut sequence = Sequence::default();
+        assert_eq!(sequence.check(0), SeqCheck::InOrder);
+        assert_eq!(sequence.check(1), SeqCheck::InOrder);
+        assert_eq!(sequence.check(4), SeqCheck::Missed(2));
+        assert_eq!(sequence.check(0), SeqCheck::Restarted);
+        assert_eq!(sequence.check(1), SeqCheck::InOrder);
+        assert_eq!(sequence.check(1), SeqCheck::Restarted);
+        sequence.check(u32::MAX);
+        assert_eq!(sequence.check(1), SeqCheck::InOrder);
+        sequence.reset();
+        assert_eq!(sequence.check(3), SeqCheck::Missed(3));
+    }
+
+    #[test]
+    fn test_reorder() {
+        let notification = |seq| Notification {
+            sid: "uuid:sub-1".into(),
+            seq: Some(seq),
+            vars: HashMap::new(),
+        };
+        let seqs = |ready: Vec<Notification>| ready.iter().map(|n| n.seq).collect::<Vec<_>>();
+        let mut reorder = Reorder::default();
+        assert_eq!(seqs(reorder.push(notification(0))), [Some(0)]);
+        // 2 overtook 1, and waits for it
+        assert!(reorder.push(notification(2)).is_empty());
+        assert!(reorder.deadline().is_some());
+        assert_eq!(seqs(reorder.push(notification(1))), [Some(1), Some(2)]);
+        assert!(reorder.deadline().is_none());
+        // 3 never comes, so 5 and 4 are let through in order
+        assert!(reorder.push(notification(5)).is_empty());
+        assert!(reorder.push(notification(4)).is_empty());
+        assert_eq!(seqs(reorder.flush()), [Some(4), Some(5)]);
+        assert!(reorder.push(notification(3)).is_empty());
+        assert_eq!(seqs(reorder.push(notification(6))), [Some(6)]);
+    }
+
+    #[tokio::test]
+    async fn test_callback_server() {
+        let config = CallbackConfig {
+            bind: [127, 0, 0, 1].into(),
+            ..Default::default()
+        };
+        let server = CallbackServer::start(config).await.unwrap();
+        let mut registration = server
+            .register(
+                &"http://127.0.0.1:1400/xml/device_description.xml"
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let url: Uri = registration.callback.parse().unwrap();
+        assert_eq!(url.port_u16(), Some(server.port));
+
+        let body = r#"<e:propertyset xmlns:e="urn:schemas-upnp-org:event-1-0"><e:property><GroupMute>1</GroupMute></e:property></e:propertyset>"#;
+        let notify = format!(
+            "NOTIFY {} HTTP/1.1\r\nSID: uuid:sub-1\r\nSEQ: 0\r\nNT: upnp:event\r\nContent-Length: {}\r\n\r\n{}",
+            url.path(),
+            body.len(),
+            body
+        );
+        let mut stream = TcpStream::connect(("127.0.0.1", server.port))
+            .await
+            .unwrap();
+        stream.write_all(notify.as_bytes()).await.unwrap();
+        let (status, ..) = read_message(&mut stream).await.unwrap();
+        assert_eq!(status, "HTTP/1.1 200 OK");
+
+        let notification = registration.rx.recv().await.unwrap();
+        assert_eq!(notification.sid, "uuid:sub-1");
+        assert_eq!(notification.seq, Some(0));
+        assert_eq!(notification.vars["GroupMute"], "1");
+    }
+}
@@ -3,36 +3,68 @@
 //! A user-friendly API for controlling sonos systems similar to the
 //! controller app, with room-by-room (or group-by-group) controls.
 
+mod alarm;
+mod builder;
+mod cache;
+mod contentcache;
 mod controller;
 mod error;
+mod gena;
 mod mediasource;
 mod metadata;
+mod nowplaying;
+mod position;
+mod resync;
+mod scene;
+mod snapshot;
 mod subscriber;
 mod types;
 pub mod utils;
 
-use controller::{Controller, SpeakerData};
-use sonor::{Snapshot, Track};
-use std::fmt::Write as _;
-use tokio::sync::mpsc;
-use tokio::{sync::oneshot, task::JoinHandle};
-use types::{CmdSender, Response, ZoneActionResponder, ZoneName};
-use types::{Result, StatusResponder};
+use controller::SpeakerData;
+use sonor::{Snapshot, Track, URN};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::{
+    sync::{broadcast, oneshot},
+    task::JoinHandle,
+};
+use types::{BulkResponder, CmdSender, Response, SubscriptionResponder, ZoneActionResponder};
+use types::{
+    DiagnosticsResponder, HouseholdsResponder, RestoreResponder, Result, SceneResponder,
+    ShutdownResponder,
+};
+use types::{StatusResponder, ZonesResponder};
 
+pub use alarm::{Alarm, AlarmSource, Recurrence};
+pub use builder::ManagerBuilder;
+use controller::alarmaction::AlarmAction;
+use controller::bulkaction::BulkAction;
 use controller::zoneaction::ZoneAction;
 pub use error::Error;
 pub use mediasource::MediaSource;
+pub use nowplaying::{NowPlaying, PlaybackSource};
+pub use scene::{Scene, SceneGroup, SceneRoom};
+pub use snapshot::{
+    GroupSnapshot, HouseSnapshot, HouseholdSnapshot, MemberSnapshot, QueuedTrack, TransportSnapshot,
+};
+pub use subscriber::ServiceSubscription;
+pub use types::{
+    Channel, Diagnostics, GroupRenderingState, HouseholdId, Position, RenderingState, ServiceEvent,
+    StateVar, Step, StepReport, SubscriptionStatus, ZoneEvent, ZoneName,
+};
 
 #[derive(Debug)]
 pub struct Manager {
     controller_handle: JoinHandle<()>,
     tx: CmdSender,
+    events: broadcast::Sender<ZoneEvent>,
 }
 
 #[derive(Debug)]
 pub struct Zone<'a> {
     manager: &'a Manager,
-    name: String,
+    name: ZoneName,
 }
 
 macro_rules! action {
@@ -41,6 +73,7 @@ macro_rules! action {
             use ZoneAction::*;
             match self.action($action$(($($invar),+))?).await? {
                 Response::$resp($outvar) => Ok($outvar),
+                Response::Unsupported(what) => Err(Error::Unsupported(what)),
                 _ => Err(Error::ZoneActionError)
             }
         }
@@ -48,6 +81,42 @@ macro_rules! action {
 }
 
 impl<'a> Zone<'a> {
+    /// The stable ID of the zone: the UUID of the speaker that stands for it.
+    /// A zone found by name keeps working when the room is renamed.
+    pub fn id(&self) -> &str {
+        &self.name.name
+    }
+
+    /// Add an alias for this zone, which can be used in place of the room
+    /// name with [`Manager::get_zone`]. Aliases take precedence over names,
+    /// and are kept in the cache, see [`ManagerBuilder::cache`].
+    pub async fn add_alias(&self, alias: String) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.manager
+            .tx
+            .send(Command::SetAlias(tx, self.name.clone(), alias))
+            .await
+            .map_err(|_| Error::ControllerOffline)?;
+        match rx.await.map_err(|_| Error::MessageRecvError)? {
+            Response::Ok(_) => Ok(()),
+            Response::Ambiguous(households) => Err(Error::AmbiguousZoneName(households)),
+            _ => Err(Error::ZoneDoesNotExist),
+        }
+    }
+
+    /// Subscribe to events of any service on the speaker of this zone, e.g.
+    /// `sonor::urns::QUEUE`. Events carry the changed state variables, with
+    /// LastChange events expanded.
+    pub async fn subscribe(&self, urn: &URN) -> Result<ServiceSubscription> {
+        let (tx, rx) = oneshot::channel();
+        self.manager
+            .tx
+            .send(Command::Subscribe(tx, self.name.clone(), urn.clone()))
+            .await
+            .map_err(|_| Error::ControllerOffline)?;
+        rx.await.map_err(|_| Error::MessageRecvError)?
+    }
+
     pub async fn action(&self, action: ZoneAction) -> Result<Response> {
         let (tx, rx) = oneshot::channel();
         self.manager
@@ -77,53 +146,375 @@ impl<'a> Zone<'a> {
     action!(take_snapshot: TakeSnapshot => Snapshot(snap: Snapshot));
     action!(apply_snapshot: ApplySnapshot(snap: Snapshot) => Ok(__: ()));
     action!(set_rel_volume: SetRelVolume(number: i32) => Ok(__: ()));
+
+    // Speaker settings apply to the named room only, not its whole group
+    action!(bass: GetBass => Level(level: i8));
+    action!(set_bass: SetBass(level: i8) => Ok(__: ()));
+    action!(treble: GetTreble => Level(level: i8));
+    action!(set_treble: SetTreble(level: i8) => Ok(__: ()));
+    action!(loudness: GetLoudness => Bool(state: bool));
+    action!(set_loudness: SetLoudness(state: bool) => Ok(__: ()));
+    action!(balance: GetBalance => Level(balance: i8));
+    action!(set_balance: SetBalance(balance: i8) => Ok(__: ()));
+    action!(channel_volume: GetChannelVolume(channel: Channel) => Volume(volume: u16));
+    action!(set_channel_volume: SetChannelVolume(channel: Channel, volume: u16) => Ok(__: ()));
+
+    // Home theater settings fail with `Error::Unsupported` on other speakers
+    action!(night_mode: GetNightMode => Bool(state: bool));
+    action!(set_night_mode: SetNightMode(state: bool) => Ok(__: ()));
+    action!(speech_enhancement: GetSpeechEnhancement => Bool(state: bool));
+    action!(set_speech_enhancement: SetSpeechEnhancement(state: bool) => Ok(__: ()));
+    action!(sub_gain: GetSubGain => Level(level: i8));
+    action!(set_sub_gain: SetSubGain(level: i8) => Ok(__: ()));
+    action!(surround_level: GetSurroundLevel => Level(level: i8));
+    action!(set_surround_level: SetSurroundLevel(level: i8) => Ok(__: ()));
+    action!(surround_enabled: GetSurroundEnabled => Bool(state: bool));
+    action!(set_surround_enabled: SetSurroundEnabled(state: bool) => Ok(__: ()));
+
+    // Cached from events, so these don't contact the speakers
+    action!(rendering_state: GetRenderingState => Rendering(state: RenderingState));
+    action!(group_rendering_state: GetGroupRenderingState => GroupRendering(state: GroupRenderingState));
+    action!(now_playing: GetNowPlaying => NowPlaying(now_playing: NowPlaying));
+
+    // Asked for once, then extrapolated until the track changes or playback jumps
+    action!(position: GetPosition => Position(position: Position));
+
+    /// Pause the zone's group after a duration, using the sleep timer of the
+    /// speakers. Replaces a running sleep timer.
+    pub async fn set_sleep_timer(&self, duration: Duration) -> Result<()> {
+        self.sleep_timer(duration, None).await
+    }
+
+    /// Like [`Zone::set_sleep_timer`], but also ramp the volume of the group
+    /// down to zero over the last `fade` of the duration. The volume is
+    /// restored once the group paused, or when the timer is cancelled. The
+    /// fade-out is run by the manager, so it needs to keep running.
+    pub async fn set_sleep_timer_with_fade(
+        &self,
+        duration: Duration,
+        fade: Duration,
+    ) -> Result<()> {
+        self.sleep_timer(duration, Some(fade)).await
+    }
+
+    async fn sleep_timer(&self, duration: Duration, fade: Option<Duration>) -> Result<()> {
+        match self
+            .action(ZoneAction::SetSleepTimer(duration, fade))
+            .await?
+        {
+            Response::Ok(_) => Ok(()),
+            _ => Err(Error::ZoneActionError),
+        }
+    }
+
+    action!(cancel_sleep_timer: CancelSleepTimer => Ok(__: ()));
+    action!(sleep_timer_remaining: GetSleepTimer => SleepTimer(remaining: Option<Duration>));
 }
 
 impl Manager {
-    /// Try to create a new manager to control the first sonos system found on
-    /// the network. If a system cannot be found, an error is returned.
+    /// Configure a new manager, e.g. to tune discovery and subscription
+    /// timings.
+    pub fn builder() -> ManagerBuilder {
+        ManagerBuilder::default()
+    }
+
+    /// Try to create a new manager to control every sonos household found on
+    /// the network. If no system can be found, an error is returned.
     pub async fn try_new() -> Result<Manager> {
         Self::try_new_with_room(None).await
     }
 
     /// Try to create a new manager to control a sonos system that has a
-    /// speaker with a certain room name. If the room name does not match any
-    /// existing system, an error is returned.
+    /// speaker with a certain room name, or every household on the network if
+    /// no room is given. If the room name does not match any existing system,
+    /// an error is returned.
     pub async fn try_new_with_room(room: Option<String>) -> Result<Manager> {
-        let (tx, rx) = mpsc::channel(32);
-        let mut controller = Controller::new(rx, room);
-        controller.init().await?;
-        log::debug!(
-            "Initialized controller with devices:\n{}",
-            controller
-                .system
-                .speakers()
-                .iter()
-                .fold(String::new(), |mut acc, device| {
-                    let _ = writeln!(acc, "     - {}", device.name());
-                    acc
-                })
-        );
-
-        let controller_handle = tokio::spawn(async move { controller.run().await });
-
-        Ok(Manager {
-            controller_handle,
-            tx,
-        })
+        match room {
+            Some(room) => Self::builder().room(room).build().await,
+            None => Self::builder().build().await,
+        }
+    }
+
+    /// Try to create a new manager like [`Manager::try_new`], but start from
+    /// the topology cached in a file on a previous run, if there is one. The
+    /// cache is validated in the background, falling back to discovery if it
+    /// is stale, and kept up-to-date as the topology changes.
+    pub async fn try_new_with_cache(cache_path: impl Into<PathBuf>) -> Result<Manager> {
+        Self::builder().cache(cache_path).build().await
     }
 
-    /// Get a zone by name. If the zone does not exist, an error is returned.
+    /// Try to create a new manager to control the sonos system(s) of speakers
+    /// at known addresses, without using SSDP discovery. Addresses may be IPs,
+    /// host names or device description URLs. Rediscovery also only contacts
+    /// these speakers, so at least one of them needs to stay online.
+    pub async fn try_new_with_ips<I, S>(addresses: I) -> Result<Manager>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        Self::builder().ips(addresses).build().await
+    }
+
+    /// Get a zone by room name, alias or ID (speaker UUID or group ID). If the
+    /// zone does not exist, or the name is used in more than one household,
+    /// an error is returned.
     pub async fn get_zone(&self, room_name: String) -> Result<Zone<'_>> {
-        let zone = Zone {
-            manager: self,
+        self.zone(room_name.into()).await
+    }
+
+    /// Get a zone by name within a particular household. If the zone does not
+    /// exist in that household, an error is returned.
+    pub async fn get_zone_in_household(
+        &self,
+        household: HouseholdId,
+        room_name: String,
+    ) -> Result<Zone<'_>> {
+        self.zone(ZoneName {
+            household: Some(household),
             name: room_name,
+        })
+        .await
+    }
+
+    async fn zone(&self, name: ZoneName) -> Result<Zone<'_>> {
+        let mut zone = Zone {
+            manager: self,
+            name,
         };
         match zone.action(ZoneAction::Exists).await? {
-            Response::Ok(_) => Ok(zone),
+            Response::Zone(uuid) => {
+                // Address the zone by ID from now on
+                zone.name.name = uuid;
+                Ok(zone)
+            }
+            Response::Ambiguous(households) => Err(Error::AmbiguousZoneName(households)),
             _ => Err(Error::ZoneDoesNotExist),
         }
     }
+
+    /// Get the names of all zones in every household. Bonded sets, like
+    /// stereo pairs or home theaters with surrounds, are listed once.
+    pub async fn zones(&self) -> Result<Vec<ZoneName>> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(Command::GetZones(tx))
+            .await
+            .map_err(|_| Error::ControllerOffline)?;
+        rx.await.map_err(|_| Error::MessageRecvError)
+    }
+
+    /// Remove an alias added with [`Zone::add_alias`].
+    pub async fn remove_alias(&self, alias: String) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(Command::RemoveAlias(tx, alias))
+            .await
+            .map_err(|_| Error::ControllerOffline)?;
+        match rx.await.map_err(|_| Error::MessageRecvError)? {
+            Response::Ok(_) => Ok(()),
+            _ => Err(Error::ZoneDoesNotExist),
+        }
+    }
+
+    /// Get the state of every event subscription and how often the system
+    /// had to be rediscovered, to find out why events stop arriving.
+    pub async fn diagnostics(&self) -> Result<Diagnostics> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(Command::GetDiagnostics(tx))
+            .await
+            .map_err(|_| Error::ControllerOffline)?;
+        rx.await.map_err(|_| Error::MessageRecvError)
+    }
+
+    /// Receive changes in the sonos system(s) as they happen. Receivers that
+    /// fall behind miss the oldest events.
+    pub fn events(&self) -> broadcast::Receiver<ZoneEvent> {
+        self.events.subscribe()
+    }
+
+    /// Get the alarms of every household, from the cache if they haven't
+    /// changed. Households that can't be asked are left out; this fails only
+    /// if none could be.
+    pub async fn alarms(&self) -> Result<Vec<Alarm>> {
+        match self.alarm_action(AlarmAction::List).await? {
+            Response::Alarms(alarms) => Ok(alarms),
+            _ => Err(Error::ZoneActionError),
+        }
+    }
+
+    /// Create an alarm, returning its ID. Media sources are looked up once,
+    /// when the alarm is created.
+    pub async fn create_alarm(&self, mut alarm: Alarm) -> Result<u32> {
+        alarm.id = None;
+        match self.alarm_action(AlarmAction::Create(alarm)).await? {
+            Response::AlarmId(id) => Ok(id),
+            _ => Err(Error::ZoneActionError),
+        }
+    }
+
+    /// Replace the alarm with the same ID.
+    pub async fn update_alarm(&self, alarm: Alarm) -> Result<()> {
+        if alarm.id.is_none() {
+            return Err(Error::ZoneActionError);
+        }
+        match self.alarm_action(AlarmAction::Update(alarm)).await? {
+            Response::Ok(_) => Ok(()),
+            _ => Err(Error::ZoneActionError),
+        }
+    }
+
+    /// Enable or disable an alarm.
+    pub async fn set_alarm_enabled(&self, alarm: &Alarm, enabled: bool) -> Result<()> {
+        self.update_alarm(Alarm {
+            enabled,
+            ..alarm.clone()
+        })
+        .await
+    }
+
+    /// Delete an alarm.
+    pub async fn delete_alarm(&self, alarm: &Alarm) -> Result<()> {
+        match self
+            .alarm_action(AlarmAction::Delete(alarm.clone()))
+            .await?
+        {
+            Response::Ok(_) => Ok(()),
+            _ => Err(Error::ZoneActionError),
+        }
+    }
+
+    /// Capture the grouping of every household, what every group is playing
+    /// and the volume and mute of every speaker. Unlike
+    /// [`Zone::take_snapshot`], the snapshot can be saved with
+    /// [`HouseSnapshot::to_json`]. Groups that can't be reached are left
+    /// out.
+    pub async fn take_house_snapshot(&self) -> Result<HouseSnapshot> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(Command::TakeHouseSnapshot(tx))
+            .await
+            .map_err(|_| Error::ControllerOffline)?;
+        match rx.await.map_err(|_| Error::MessageRecvError)? {
+            Response::HouseSnapshot(snapshot) => Ok(snapshot),
+            _ => Err(Error::ZoneActionError),
+        }
+    }
+
+    /// Restore a house snapshot: speakers are regrouped first, then volumes
+    /// and playback are restored. Actions on zones wait until it's done.
+    /// Steps that fail don't stop the others; the report tells which failed.
+    /// Fails without changing anything if a speaker of the snapshot is not
+    /// found.
+    pub async fn apply_house_snapshot(&self, snapshot: &HouseSnapshot) -> Result<StepReport> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(Command::ApplyHouseSnapshot(tx, snapshot.clone()))
+            .await
+            .map_err(|_| Error::ControllerOffline)?;
+        rx.await.map_err(|_| Error::MessageRecvError)?
+    }
+
+    /// Add a scene to apply with [`Manager::apply_scene`], replacing the
+    /// scene with the same name.
+    pub async fn add_scene(&self, scene: Scene) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(Command::AddScene(tx, scene))
+            .await
+            .map_err(|_| Error::ControllerOffline)?;
+        rx.await.map_err(|_| Error::MessageRecvError).map(|_| ())
+    }
+
+    /// Add the scenes of a JSON file, see [`Scene::load`].
+    pub async fn load_scenes(&self, path: impl Into<PathBuf>) -> Result<()> {
+        for scene in Scene::load(path.into())? {
+            self.add_scene(scene).await?;
+        }
+        Ok(())
+    }
+
+    /// Apply a scene, changing only what differs from the current state.
+    /// Actions on zones wait until it's done. Steps that fail don't stop the
+    /// others; the report tells which failed. Fails without changing
+    /// anything if a room of the scene is not found.
+    pub async fn apply_scene(&self, name: &str) -> Result<StepReport> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(Command::ApplyScene(tx, name.to_owned()))
+            .await
+            .map_err(|_| Error::ControllerOffline)?;
+        rx.await.map_err(|_| Error::MessageRecvError)?
+    }
+
+    async fn alarm_action(&self, action: AlarmAction) -> Result<Response> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(Command::DoAlarmAction(tx, action))
+            .await
+            .map_err(|_| Error::ControllerOffline)?;
+        rx.await.map_err(|_| Error::MessageRecvError)
+    }
+
+    /// Pause every group that is playing. Returns the result for each group,
+    /// named after its coordinator.
+    pub async fn pause_all(&self) -> Result<Vec<(ZoneName, Result<()>)>> {
+        self.bulk_action(BulkAction::Pause).await
+    }
+
+    /// Resume playback in every paused group. Stopped groups are left alone.
+    pub async fn resume_all(&self) -> Result<Vec<(ZoneName, Result<()>)>> {
+        self.bulk_action(BulkAction::Resume).await
+    }
+
+    /// Set the volume of every group, keeping the volumes of the speakers of
+    /// a group in proportion.
+    pub async fn set_all_volume(&self, volume: u16) -> Result<Vec<(ZoneName, Result<()>)>> {
+        self.bulk_action(BulkAction::SetVolume(volume)).await
+    }
+
+    /// Mute or unmute every group.
+    pub async fn mute_all(&self, mute: bool) -> Result<Vec<(ZoneName, Result<()>)>> {
+        self.bulk_action(BulkAction::SetMute(mute)).await
+    }
+
+    /// Run an action once on each coordinator, concurrently
+    async fn bulk_action(&self, action: BulkAction) -> Result<Vec<(ZoneName, Result<()>)>> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(Command::DoBulkAction(tx, action))
+            .await
+            .map_err(|_| Error::ControllerOffline)?;
+        rx.await.map_err(|_| Error::MessageRecvError)
+    }
+
+    /// Get the IDs of the households under control.
+    pub async fn households(&self) -> Result<Vec<HouseholdId>> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(Command::GetHouseholds(tx))
+            .await
+            .map_err(|_| Error::ControllerOffline)?;
+        rx.await.map_err(|_| Error::MessageRecvError)
+    }
+}
+
+impl Manager {
+    /// Stop the controller gracefully. New commands are refused, commands
+    /// already sent are completed, and every event subscription is cancelled
+    /// so speakers stop sending events before the controller is joined.
+    /// Dropping the manager instead stops the controller right away.
+    pub async fn shutdown(mut self) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(Command::Shutdown(tx))
+            .await
+            .map_err(|_| Error::ControllerOffline)?;
+        rx.await.map_err(|_| Error::MessageRecvError)?;
+        (&mut self.controller_handle)
+            .await
+            .map_err(|_| Error::ControllerOffline)
+    }
 }
 
 impl Drop for Manager {
@@ -136,8 +527,20 @@ impl Drop for Manager {
 #[derive(Debug)]
 pub enum Command {
     DoZoneAction(ZoneActionResponder, ZoneName, ZoneAction),
+    DoAlarmAction(ZoneActionResponder, AlarmAction),
+    DoBulkAction(BulkResponder, BulkAction),
     GetStatus(StatusResponder),
+    GetHouseholds(HouseholdsResponder),
+    GetZones(ZonesResponder),
+    Subscribe(SubscriptionResponder, ZoneName, URN),
+    SetAlias(ZoneActionResponder, ZoneName, String),
+    RemoveAlias(ZoneActionResponder, String),
+    GetDiagnostics(DiagnosticsResponder),
+    TakeHouseSnapshot(ZoneActionResponder),
+    ApplyHouseSnapshot(RestoreResponder, HouseSnapshot),
+    AddScene(ZoneActionResponder, Scene),
+    ApplyScene(SceneResponder, String),
+    Shutdown(ShutdownResponder),
     // Browse or search media
-    // Subscribe to events
     // Management of controller?
 }
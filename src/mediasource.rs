@@ -1,14 +1,20 @@
 use super::{
-    metadata::{apple_uri_and_metadata, spotify_uri_and_metadata},
+    contentcache::browse,
+    metadata::{apple_uri_and_metadata, is_stream_uri, spotify_uri_and_metadata},
     Error, Result, SpeakerData,
 };
+use serde::{Deserialize, Serialize};
 use sonor::utils::escape_str_pcdata;
-use sonor::Speaker;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 /// Definitions for media that can be played and queued.
 pub enum MediaSource {
     Apple(String),
+    /// An Apple Music album (`album:` or `libraryalbum:`), with playback
+    /// starting at the given track number. Tracks are numbered from 1, and
+    /// playback starts at the first track if the album is shorter. It can
+    /// only be played, not queued.
+    AppleAlbumAt(String, u32),
     Spotify(String),
     SonosPlaylist(String),
     SonosFavorite(String),
@@ -16,31 +22,56 @@ pub enum MediaSource {
 
 use MediaSource::*;
 impl MediaSource {
-    async fn get_uri_and_metadata(&self, speaker: &Speaker) -> Option<(String, String)> {
+    pub(crate) async fn get_uri_and_metadata(
+        &self,
+        speakerdata: &SpeakerData,
+    ) -> Option<(String, String)> {
+        let speaker = &speakerdata.speaker;
         match self {
             Apple(item) => apple_uri_and_metadata(item),
+            // Turned down here, before `play_now` replaces the queue. Only
+            // albums have tracks to start at, and tracks start at 1.
+            AppleAlbumAt(_, 0) => None,
+            AppleAlbumAt(item, _) => match item.split_once(':')? {
+                ("album" | "libraryalbum", _) => apple_uri_and_metadata(item),
+                _ => None,
+            },
             Spotify(item) => spotify_uri_and_metadata(item),
             SonosPlaylist(item) => {
-                let playlists = speaker.browse("SQ:", 0, 0).await.ok()?;
+                let playlists = speakerdata
+                    .content
+                    .playlists
+                    .get_or_fetch(|| browse(speaker, "SQ:"))
+                    .await
+                    .ok()?;
                 let playlist = playlists
                     .iter()
-                    .find(|&p| p.title().eq_ignore_ascii_case(item))?;
-                log::debug!("Found playlist {}", playlist.title());
-                Some((playlist.uri()?.into(), "".into()))
+                    .find(|&p| p.title.eq_ignore_ascii_case(item))?;
+                log::debug!("Found playlist {}", playlist.title);
+                Some((playlist.uri.clone()?, "".into()))
             }
             SonosFavorite(item) => {
-                let favorites = speaker.browse("FV:2", 0, 0).await.ok()?;
+                let favorites = speakerdata
+                    .content
+                    .favorites
+                    .get_or_fetch(|| browse(speaker, "FV:2"))
+                    .await
+                    .ok()?;
                 let favorite = favorites
                     .iter()
-                    .find(|&f| f.title().eq_ignore_ascii_case(item))?;
+                    .find(|&f| f.title.eq_ignore_ascii_case(item))?;
                 log::debug!("Found favorite {:?}", favorite);
-                Some((favorite.uri()?.into(), favorite.metadata()?.into()))
+                Some((favorite.uri.clone()?, favorite.metadata.clone()?))
             }
         }
     }
 
     /// Add the media to the end of the queue.
     pub(crate) async fn queue_as_next(&self, coordinator_data: &SpeakerData) -> Result<()> {
+        // A track to start at means nothing in the queue
+        if let AppleAlbumAt(..) = self {
+            return Err(Error::NotQueueable);
+        }
         let speaker = &coordinator_data.speaker;
         let cur_track_no = coordinator_data
             .get_current_track_no()
@@ -51,9 +82,12 @@ impl MediaSource {
             })
             .unwrap_or(0);
         let (uri, metadata) = self
-            .get_uri_and_metadata(speaker)
+            .get_uri_and_metadata(coordinator_data)
             .await
             .ok_or(Error::ContentNotFound)?;
+        if is_stream_uri(&uri) {
+            return Err(Error::NotQueueable);
+        }
         speaker
             .queue_next(&uri, &escape_str_pcdata(&metadata), Some(cur_track_no + 1))
             .await?;
@@ -63,9 +97,16 @@ impl MediaSource {
     pub(crate) async fn play_now(&self, coordinator_data: &SpeakerData) -> Result<()> {
         let coordinator = &coordinator_data.speaker;
         let (uri, metadata) = self
-            .get_uri_and_metadata(coordinator)
+            .get_uri_and_metadata(coordinator_data)
             .await
             .ok_or(Error::ContentNotFound)?;
+        // Streams replace the transport URI; they don't go through the queue
+        if is_stream_uri(&uri) {
+            coordinator
+                .set_transport_uri(&uri, &escape_str_pcdata(&metadata))
+                .await?;
+            return coordinator.play().await.map_err(Error::from);
+        }
         coordinator.clear_queue().await?;
         coordinator
             .queue_next(&uri, &escape_str_pcdata(&metadata), Some(1))
@@ -73,6 +114,13 @@ impl MediaSource {
         // Turn on queue mode
         let queue_uri = format!("x-rincon-queue:{}#0", coordinator.uuid());
         coordinator.set_transport_uri(&queue_uri, "").await?;
+        if let AppleAlbumAt(_, track_no) = self {
+            // The queue is already replaced, so the album plays from the
+            // start rather than failing
+            if let Err(err) = coordinator.seek_track(*track_no).await {
+                log::warn!("Unable to start at track {}: {}", track_no, err);
+            }
+        }
         coordinator.play().await.map_err(Error::from)
     }
 }
@@ -54,6 +54,7 @@ fn get_metadata(id: &str, parent_id: &str, upnp_class: &str, cdudn: &str) -> Str
 pub(crate) fn apple_uri_and_metadata(item: &str) -> Option<(String, String)> {
     let (kind, id) = match item.split_once(':')? {
         ("track" , id) => ("song", id),
+        ("station", id) => ("radio", id),
         (kind, id) => (kind, id)
     };
     log::debug!("Got Apple {}: {}",  kind, id);
@@ -88,10 +89,27 @@ pub(crate) fn apple_uri_and_metadata(item: &str) -> Option<(String, String)> {
                 &cdudn
             )
          )),
+         "radio" => Some((
+            format!(r"x-sonosapi-radio:{}?sid=204&flags=8300", item),
+            get_metadata(
+                &format!(r"10004020{}", item),
+                r"00020000radio%3A",
+                r"object.item.audioItem.audioBroadcast",
+                &cdudn
+            )
+         )),
          _ => None
     }
 }
 
+/// Streams (radio stations and the like) are played by setting the transport
+/// URI directly. They cannot be added to the queue.
+pub(crate) fn is_stream_uri(uri: &str) -> bool {
+    ["x-sonosapi-radio:", "x-sonosapi-stream:", "x-sonosapi-hls:", "x-rincon-mp3radio:", "aac:"]
+        .iter()
+        .any(|prefix| uri.starts_with(prefix))
+}
+
 #[cfg(test)]
 mod tests{
     use super::*;
@@ -124,4 +142,16 @@ mod tests{
         assert_eq!(target_metadata, metadata);
         Ok(())
     }
+
+    #[test]
+    fn test_apple_station() -> Result<(), Box<dyn Error>> {
+        let target_uri = "x-sonosapi-radio:radio%3Ara.978194965?sid=204&flags=8300";
+        let target_metadata = r#"<DIDL-Lite xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:upnp="urn:schemas-upnp-org:metadata-1-0/upnp/" xmlns:r="urn:schemas-rinconnetworks-com:metadata-1-0/" xmlns="urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/"><item id="10004020radio%3Ara.978194965" restricted="true" parentID="00020000radio%3A"><upnp:class>object.item.audioItem.audioBroadcast</upnp:class><desc id="cdudn" nameSpace="urn:schemas-rinconnetworks-com:metadata-1-0/">SA_RINCON52231_X_#Svc52231-0-Token</desc></item></DIDL-Lite>"#;
+        let (uri, metadata) = apple_uri_and_metadata(r"station:ra.978194965").ok_or("unable to parse item")?;
+        assert_eq!(target_uri, uri);
+        assert_eq!(target_metadata, metadata);
+        assert!(is_stream_uri(&uri));
+        assert!(!is_stream_uri(r"x-rincon-cpcontainer:0004206calbum%3A1025210938?sid=204"));
+        Ok(())
+    }
 }
\ No newline at end of file
@@ -0,0 +1,175 @@
+//! What a zone is playing, from the cached AVTransport state
+
+use crate::{metadata::is_stream_uri, types::AVStatus};
+use roxmltree::Document;
+
+/// What a zone plays, from [`crate::Zone::now_playing`]. Fields the source
+/// doesn't provide are `None`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NowPlaying {
+    pub source: PlaybackSource,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    /// Absolute URL of the album art
+    pub album_art: Option<String>,
+    /// Name of the radio station or stream
+    pub station: Option<String>,
+    /// What a radio station says it's playing, often "Artist - Title"
+    pub stream_content: Option<String>,
+}
+
+/// Kind of source a zone plays from
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum PlaybackSource {
+    #[default]
+    Nothing,
+    Queue,
+    /// Radio stations and other streams
+    Stream,
+    LineIn,
+    Tv,
+    /// Controlled by another app, e.g. AirPlay or Spotify Connect
+    External,
+    /// Any other transport URI
+    Other(String),
+}
+
+impl PlaybackSource {
+    fn from_uri(uri: &str) -> PlaybackSource {
+        match uri {
+            "" => PlaybackSource::Nothing,
+            _ if uri.starts_with("x-rincon-queue:") => PlaybackSource::Queue,
+            _ if is_stream_uri(uri) => PlaybackSource::Stream,
+            _ if uri.starts_with("x-rincon-stream:") => PlaybackSource::LineIn,
+            _ if uri.starts_with("x-sonos-htastream:") => PlaybackSource::Tv,
+            _ if uri.starts_with("x-sonos-vli:") || uri.starts_with("x-sonos-airplay:") => {
+                PlaybackSource::External
+            }
+            _ => PlaybackSource::Other(uri.to_owned()),
+        }
+    }
+}
+
+/// Fields of DIDL-Lite metadata, by tag name without namespace
+struct Didl(Vec<(String, String)>);
+
+impl Didl {
+    fn parse(xml: &str) -> Didl {
+        let fields = match Document::parse(xml) {
+            Ok(doc) => doc
+                .descendants()
+                .filter(|n| n.is_element())
+                .filter_map(|n| {
+                    let text = n.text()?.trim();
+                    (!text.is_empty()).then(|| (n.tag_name().name().to_owned(), text.to_owned()))
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        Didl(fields)
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        self.0
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.clone())
+    }
+}
+
+/// Build what a coordinator plays from its AVTransport state. Relative album
+/// art URLs are made absolute with the base URL of the speaker, e.g.
+/// "http://192.168.1.10:1400".
+pub(crate) fn now_playing(transport_data: &AVStatus, base_url: &str) -> NowPlaying {
+    let get = |key: &str| {
+        transport_data
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+            .filter(|v| !v.is_empty() && *v != "NOT_IMPLEMENTED")
+    };
+    let source = PlaybackSource::from_uri(get("AVTransportURI").unwrap_or_default());
+    let track = Didl::parse(get("CurrentTrackMetaData").unwrap_or_default());
+    let enqueued = Didl::parse(get("EnqueuedTransportURIMetaData").unwrap_or_default());
+
+    let album_art = track
+        .get("albumArtURI")
+        .or_else(|| enqueued.get("albumArtURI"))
+        .map(|art| match art.starts_with('/') {
+            true => format!("{}{}", base_url.trim_end_matches('/'), art),
+            false => art,
+        });
+    let mut now_playing = NowPlaying {
+        title: track.get("title"),
+        artist: track.get("creator"),
+        album: track.get("album"),
+        album_art,
+        stream_content: track.get("streamContent"),
+        ..Default::default()
+    };
+    if source == PlaybackSource::Stream {
+        now_playing.station = enqueued.get("title");
+        // Streams report their URL or station as the track title
+        if now_playing.title == now_playing.station
+            || now_playing
+                .title
+                .as_deref()
+                .is_some_and(|t| is_stream_uri(t) || t.contains("://"))
+        {
+            now_playing.title = None;
+        }
+    }
+    now_playing.source = source;
+    now_playing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> AVStatus {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_now_playing() {
+        let track = r#"<DIDL-Lite xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:upnp="urn:schemas-upnp-org:metadata-1-0/upnp/" xmlns:r="urn:schemas-rinconnetworks-com:metadata-1-0/" xmlns="urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/"><item id="-1" parentID="-1"><dc:title>So What</dc:title><dc:creator>Miles Davis</dc:creator><upnp:album>Kind of Blue</upnp:album><upnp:albumArtURI>/getaa?s=1&amp;u=x</upnp:albumArtURI></item></DIDL-Lite>"#;
+        let playing = now_playing(
+            &vars(&[
+                ("AVTransportURI", "x-rincon-queue:RINCON_1#0"),
+                ("CurrentTrackMetaData", track),
+            ]),
+            "http://192.168.1.10:1400",
+        );
+        assert_eq!(playing.source, PlaybackSource::Queue);
+        assert_eq!(playing.title.as_deref(), Some("So What"));
+        assert_eq!(playing.artist.as_deref(), Some("Miles Davis"));
+        assert_eq!(playing.album.as_deref(), Some("Kind of Blue"));
+        assert_eq!(
+            playing.album_art.as_deref(),
+            Some("http://192.168.1.10:1400/getaa?s=1&u=x")
+        );
+
+        let track = r#"<DIDL-Lite xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:r="urn:schemas-rinconnetworks-com:metadata-1-0/"><item><dc:title>x-sonosapi-stream:s1234?sid=254</dc:title><r:streamContent>Nina Simone - Feeling Good</r:streamContent></item></DIDL-Lite>"#;
+        let station = r#"<DIDL-Lite xmlns:dc="http://purl.org/dc/elements/1.1/"><item><dc:title>Jazz FM</dc:title></item></DIDL-Lite>"#;
+        let playing = now_playing(
+            &vars(&[
+                ("AVTransportURI", "x-sonosapi-stream:s1234?sid=254"),
+                ("CurrentTrackMetaData", track),
+                ("EnqueuedTransportURIMetaData", station),
+            ]),
+            "http://192.168.1.10:1400",
+        );
+        assert_eq!(playing.source, PlaybackSource::Stream);
+        assert_eq!(playing.title, None);
+        assert_eq!(playing.station.as_deref(), Some("Jazz FM"));
+        assert_eq!(
+            playing.stream_content.as_deref(),
+            Some("Nina Simone - Feeling Good")
+        );
+        assert_eq!(now_playing(&vec![], ""), NowPlaying::default());
+    }
+}
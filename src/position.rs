@@ -0,0 +1,207 @@
+//! Playback position, synced from the speakers now and then and extrapolated
+//! in between
+
+use crate::{
+    types::{AVStatus, Position},
+    utils::parse_time,
+    Error, Result,
+};
+use sonor::{urns::AV_TRANSPORT, Speaker};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Position in the current track of a speaker. AVTransport events don't
+/// carry the position, so it is asked for once and then extrapolated while
+/// the speaker plays, until the track changes or playback jumps.
+#[derive(Debug, Default)]
+pub(crate) struct PositionTracker {
+    state: Mutex<TrackerState>,
+}
+
+#[derive(Debug, Default)]
+struct TrackerState {
+    /// Whether the speaker is playing, from the last event
+    playing: Option<bool>,
+    sync: Option<PositionSync>,
+    /// Bumped whenever the sync is dropped, so that position requests that
+    /// raced with a seek don't keep their stale answer
+    generation: u64,
+}
+
+/// Position known at an instant
+#[derive(Debug, Clone, Copy)]
+struct PositionSync {
+    elapsed: Duration,
+    duration: Option<Duration>,
+    at: Instant,
+}
+
+impl PositionSync {
+    fn position(&self, playing: bool, now: Instant) -> Position {
+        let mut elapsed = self.elapsed;
+        if playing {
+            elapsed += now.saturating_duration_since(self.at);
+        }
+        if let Some(duration) = self.duration {
+            elapsed = elapsed.min(duration);
+        }
+        Position {
+            elapsed,
+            duration: self.duration,
+        }
+    }
+
+    /// The same position, known at a later instant
+    fn rebase(&self, playing: bool, now: Instant) -> PositionSync {
+        PositionSync {
+            elapsed: self.position(playing, now).elapsed,
+            at: now,
+            ..*self
+        }
+    }
+}
+
+impl PositionTracker {
+    /// Get the position, asking the speaker if it isn't known
+    pub async fn position(&self, speaker: &Speaker) -> Result<Position> {
+        let (generation, playing) = {
+            let state = self.lock();
+            if let Some(sync) = state.sync {
+                return Ok(sync.position(state.playing == Some(true), Instant::now()));
+            }
+            (state.generation, state.playing)
+        };
+        let playing = match playing {
+            Some(playing) => playing,
+            None => is_playing(speaker).await?,
+        };
+        let sync = position_info(speaker).await?;
+        let mut state = self.lock();
+        if state.generation == generation {
+            state.sync = Some(sync);
+            state.playing.get_or_insert(playing);
+        }
+        Ok(sync.position(playing, Instant::now()))
+    }
+
+    /// Forget the position, e.g. after a seek, so it is asked for again
+    pub fn invalidate(&self) {
+        let mut state = self.lock();
+        state.sync = None;
+        state.generation += 1;
+    }
+
+    /// Follow an AVTransport event, received at `now`, that replaces the
+    /// variables `old`.
+    pub fn update(&self, old: &AVStatus, new: &AVStatus, now: Instant) {
+        let get = |vars: &AVStatus, key: &str| {
+            vars.iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.to_owned())
+        };
+        let track_changed = ["CurrentTrack", "CurrentTrackURI"].iter().any(|&key| {
+            let value = get(new, key);
+            value.is_some() && value != get(old, key)
+        });
+        match get(new, "TransportState").as_deref() {
+            // Seeking or changing tracks
+            Some("TRANSITIONING") => self.invalidate(),
+            Some(transport_state) => {
+                let playing = transport_state == "PLAYING";
+                let mut state = self.lock();
+                let was_playing = state.playing == Some(true);
+                if playing != was_playing {
+                    state.sync = state.sync.map(|sync| sync.rebase(was_playing, now));
+                }
+                state.playing = Some(playing);
+            }
+            None => (),
+        }
+        if track_changed {
+            self.invalidate();
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, TrackerState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+async fn is_playing(speaker: &Speaker) -> Result<bool> {
+    let info = speaker
+        .action(
+            AV_TRANSPORT,
+            "GetTransportInfo",
+            "<InstanceID>0</InstanceID>",
+        )
+        .await?;
+    Ok(info.get("CurrentTransportState").map(String::as_str) == Some("PLAYING"))
+}
+
+async fn position_info(speaker: &Speaker) -> Result<PositionSync> {
+    let info = speaker
+        .action(
+            AV_TRANSPORT,
+            "GetPositionInfo",
+            "<InstanceID>0</InstanceID>",
+        )
+        .await?;
+    let at = Instant::now();
+    let elapsed = info
+        .get("RelTime")
+        .and_then(|t| parse_time(t))
+        .ok_or(Error::ZoneActionError)?;
+    // Streams have no duration, reported as 0:00:00 or NOT_IMPLEMENTED
+    let duration = info
+        .get("TrackDuration")
+        .and_then(|t| parse_time(t))
+        .filter(|d| !d.is_zero());
+    Ok(PositionSync {
+        elapsed,
+        duration,
+        at,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(state: &str, track: &str) -> AVStatus {
+        vec![
+            ("TransportState".to_owned(), state.to_owned()),
+            ("CurrentTrack".to_owned(), track.to_owned()),
+        ]
+    }
+
+    #[test]
+    fn test_position_tracking() {
+        let tracker = PositionTracker::default();
+        let start = Instant::now();
+        let secs = Duration::from_secs;
+        let at = |s| start + secs(s);
+        let position = |now| {
+            let state = tracker.lock();
+            let sync = state.sync?;
+            Some(sync.position(state.playing == Some(true), now).elapsed)
+        };
+
+        tracker.update(&vec![], &vars("PLAYING", "1"), start);
+        tracker.lock().sync = Some(PositionSync {
+            elapsed: secs(10),
+            duration: Some(secs(100)),
+            at: start,
+        });
+        assert_eq!(position(at(5)), Some(secs(15)));
+        // Paused after 20 seconds: the position stays
+        tracker.update(&vars("PLAYING", "1"), &vars("PAUSED_PLAYBACK", "1"), at(20));
+        assert_eq!(position(at(50)), Some(secs(30)));
+        // Playing again, up to the end of the track
+        tracker.update(&vars("PAUSED_PLAYBACK", "1"), &vars("PLAYING", "1"), at(60));
+        assert_eq!(position(at(70)), Some(secs(40)));
+        assert_eq!(position(at(1000)), Some(secs(100)));
+        // Next track: the position has to be asked for again
+        tracker.update(&vars("PLAYING", "1"), &vars("PLAYING", "2"), at(80));
+        assert_eq!(position(at(80)), None);
+    }
+}
@@ -0,0 +1,177 @@
+//! State of services polled from the speakers, for when events were missed
+
+use crate::{types::StateVar, Result};
+use sonor::rupnp::{http::Uri, Service};
+
+/// Actions that get the state of an AVTransport, with the arguments of their
+/// responses and the event variables they stand for
+const AV_TRANSPORT_STATE: &[(&str, &[(&str, &str)])] = &[
+    (
+        "GetTransportInfo",
+        &[
+            ("CurrentTransportState", "TransportState"),
+            ("CurrentTransportStatus", "TransportStatus"),
+            ("CurrentSpeed", "TransportPlaySpeed"),
+        ],
+    ),
+    ("GetTransportSettings", &[("PlayMode", "CurrentPlayMode")]),
+    (
+        "GetMediaInfo",
+        &[
+            ("NrTracks", "NumberOfTracks"),
+            ("MediaDuration", "CurrentMediaDuration"),
+            ("CurrentURI", "AVTransportURI"),
+            ("CurrentURIMetaData", "AVTransportURIMetaData"),
+            ("NextURI", "NextAVTransportURI"),
+            ("NextURIMetaData", "NextAVTransportURIMetaData"),
+        ],
+    ),
+    (
+        "GetPositionInfo",
+        &[
+            ("Track", "CurrentTrack"),
+            ("TrackDuration", "CurrentTrackDuration"),
+            ("TrackURI", "CurrentTrackURI"),
+            ("TrackMetaData", "CurrentTrackMetaData"),
+        ],
+    ),
+];
+
+/// Actions that get the state of a RenderingControl, as (action, arguments
+/// after the instance, response argument, event variable, channel), for
+/// everything [`crate::RenderingState`] keeps
+const RENDERING_CONTROL_STATE: &[(&str, &str, &str, &str, Option<&str>)] = &[
+    (
+        "GetVolume",
+        "<Channel>Master</Channel>",
+        "CurrentVolume",
+        "Volume",
+        Some("Master"),
+    ),
+    (
+        "GetVolume",
+        "<Channel>LF</Channel>",
+        "CurrentVolume",
+        "Volume",
+        Some("LF"),
+    ),
+    (
+        "GetVolume",
+        "<Channel>RF</Channel>",
+        "CurrentVolume",
+        "Volume",
+        Some("RF"),
+    ),
+    (
+        "GetMute",
+        "<Channel>Master</Channel>",
+        "CurrentMute",
+        "Mute",
+        Some("Master"),
+    ),
+    (
+        "GetLoudness",
+        "<Channel>Master</Channel>",
+        "CurrentLoudness",
+        "Loudness",
+        Some("Master"),
+    ),
+    ("GetBass", "", "CurrentBass", "Bass", None),
+    ("GetTreble", "", "CurrentTreble", "Treble", None),
+    (
+        "GetEQ",
+        "<EQType>NightMode</EQType>",
+        "CurrentValue",
+        "NightMode",
+        None,
+    ),
+    (
+        "GetEQ",
+        "<EQType>DialogLevel</EQType>",
+        "CurrentValue",
+        "DialogLevel",
+        None,
+    ),
+    (
+        "GetEQ",
+        "<EQType>SubGain</EQType>",
+        "CurrentValue",
+        "SubGain",
+        None,
+    ),
+    (
+        "GetEQ",
+        "<EQType>SurroundLevel</EQType>",
+        "CurrentValue",
+        "SurroundLevel",
+        None,
+    ),
+    (
+        "GetEQ",
+        "<EQType>SurroundEnable</EQType>",
+        "CurrentValue",
+        "SurroundEnable",
+        None,
+    ),
+];
+
+/// Ask a speaker for the current state of a service, as the variables its
+/// events carry. Returns `None` for services whose state can't be polled,
+/// like ContentDirectory and AlarmClock, whose events only tell what changed.
+pub(crate) async fn poll_state(service: &Service, url: &Uri) -> Result<Option<Vec<StateVar>>> {
+    let var = |name: &str, value: String, instance: Option<&str>| StateVar {
+        name: name.to_owned(),
+        value,
+        instance: instance.map(str::to_owned),
+        channel: None,
+    };
+    match service.service_type().typ() {
+        "AVTransport" => {
+            let mut vars = Vec::new();
+            for (action, args) in AV_TRANSPORT_STATE {
+                let mut response = service
+                    .action(url, action, "<InstanceID>0</InstanceID>")
+                    .await
+                    .map_err(sonor::Error::UPnP)?;
+                for (arg, name) in args.iter() {
+                    if let Some(value) = response.remove(*arg) {
+                        // As if from a LastChange event of instance 0
+                        vars.push(var(name, value, Some("0")));
+                    }
+                }
+            }
+            Ok(Some(vars))
+        }
+        "RenderingControl" => {
+            let mut vars = Vec::new();
+            for (action, args, arg, name, channel) in RENDERING_CONTROL_STATE {
+                let response = service
+                    .action(url, action, &format!("<InstanceID>0</InstanceID>{}", args))
+                    .await;
+                let value = match response {
+                    Ok(mut response) => response.remove(*arg),
+                    // Only home theater speakers have these
+                    Err(_) if *action == "GetEQ" => None,
+                    Err(err) => return Err(sonor::Error::UPnP(err).into()),
+                };
+                if let Some(value) = value {
+                    // As if from a LastChange event of instance 0
+                    vars.push(StateVar {
+                        channel: channel.map(str::to_owned),
+                        ..var(name, value, Some("0"))
+                    });
+                }
+            }
+            Ok(Some(vars))
+        }
+        "ZoneGroupTopology" => {
+            let state = service
+                .action(url, "GetZoneGroupState", "")
+                .await
+                .map_err(sonor::Error::UPnP)?
+                .remove("ZoneGroupState");
+            Ok(state.map(|xml| vec![var("ZoneGroupState", xml, None)]))
+        }
+        _ => Ok(None),
+    }
+}
@@ -0,0 +1,216 @@
+//! Scenes: named presets of groups, sources and volumes
+
+use crate::{types::HouseholdId, Error, MediaSource, Result};
+use serde::{Deserialize, Serialize};
+use sonor::RepeatMode;
+use std::path::Path;
+
+/// A named preset of groups, what they play and their volumes, applied with
+/// [`crate::Manager::apply_scene`]. Rooms not in the scene are left alone.
+///
+/// ```
+/// use sonos_manager::{MediaSource, Scene, SceneGroup};
+///
+/// let dinner = Scene::new("Dinner").group(
+///     SceneGroup::new(["Kitchen", "Dining"])
+///         .source(MediaSource::SonosFavorite("Jazz".into()))
+///         .volume("Kitchen", 20)
+///         .volume("Dining", 15)
+///         .shuffle(true),
+/// );
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Scene {
+    pub name: String,
+    /// Household of the rooms, if their names are ambiguous
+    #[serde(default)]
+    pub household: Option<HouseholdId>,
+    pub groups: Vec<SceneGroup>,
+}
+
+/// A group of a scene. The first room coordinates the group.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SceneGroup {
+    pub rooms: Vec<SceneRoom>,
+    /// Replace what the group plays with this
+    #[serde(default)]
+    pub source: Option<MediaSource>,
+    #[serde(default)]
+    pub shuffle: Option<bool>,
+    #[serde(default, with = "repeat_mode")]
+    pub repeat: Option<RepeatMode>,
+    /// Start playback if the group isn't playing. Playing a source always
+    /// starts playback.
+    #[serde(default)]
+    pub play: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SceneRoom {
+    /// Room name, alias or ID
+    pub room: String,
+    #[serde(default)]
+    pub volume: Option<u16>,
+    #[serde(default)]
+    pub mute: Option<bool>,
+}
+
+impl Scene {
+    pub fn new(name: impl Into<String>) -> Scene {
+        Scene {
+            name: name.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn group(mut self, group: SceneGroup) -> Self {
+        self.groups.push(group);
+        self
+    }
+
+    /// Read scenes from a JSON file with a list of scenes
+    pub fn load(path: impl AsRef<Path>) -> Result<Vec<Scene>> {
+        let json = std::fs::read_to_string(path.as_ref())
+            .map_err(|e| Error::SceneError(format!("{}: {}", path.as_ref().display(), e)))?;
+        serde_json::from_str(&json).map_err(|e| Error::SceneError(e.to_string()))
+    }
+}
+
+impl SceneGroup {
+    /// A group of rooms, coordinated by the first
+    pub fn new<I, S>(rooms: I) -> SceneGroup
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        SceneGroup {
+            rooms: rooms
+                .into_iter()
+                .map(|room| SceneRoom {
+                    room: room.into(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn source(mut self, source: MediaSource) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// Set the volume of a room of the group, adding the room to the group
+    /// if it isn't in it
+    pub fn volume(mut self, room: &str, volume: u16) -> Self {
+        self.room_mut(room).volume = Some(volume);
+        self
+    }
+
+    /// Mute or unmute a room of the group, adding the room to the group if
+    /// it isn't in it
+    pub fn mute(mut self, room: &str, mute: bool) -> Self {
+        self.room_mut(room).mute = Some(mute);
+        self
+    }
+
+    pub fn shuffle(mut self, shuffle: bool) -> Self {
+        self.shuffle = Some(shuffle);
+        self
+    }
+
+    pub fn repeat(mut self, repeat: RepeatMode) -> Self {
+        self.repeat = Some(repeat);
+        self
+    }
+
+    pub fn play(mut self) -> Self {
+        self.play = true;
+        self
+    }
+
+    fn room_mut(&mut self, room: &str) -> &mut SceneRoom {
+        let i = match self
+            .rooms
+            .iter()
+            .position(|r| r.room.eq_ignore_ascii_case(room))
+        {
+            Some(i) => i,
+            None => {
+                self.rooms.push(SceneRoom {
+                    room: room.to_owned(),
+                    ..Default::default()
+                });
+                self.rooms.len() - 1
+            }
+        };
+        &mut self.rooms[i]
+    }
+}
+
+/// Repeat modes as "none", "one" or "all"
+mod repeat_mode {
+    use serde::{de::Error as _, Deserialize, Deserializer, Serializer};
+    use sonor::RepeatMode;
+
+    pub fn serialize<S: Serializer>(mode: &Option<RepeatMode>, s: S) -> Result<S::Ok, S::Error> {
+        match mode {
+            Some(RepeatMode::None) => s.serialize_str("none"),
+            Some(RepeatMode::One) => s.serialize_str("one"),
+            Some(RepeatMode::All) => s.serialize_str("all"),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<RepeatMode>, D::Error> {
+        match Option::<String>::deserialize(d)?.as_deref() {
+            None => Ok(None),
+            Some("none") => Ok(Some(RepeatMode::None)),
+            Some("one") => Ok(Some(RepeatMode::One)),
+            Some("all") => Ok(Some(RepeatMode::All)),
+            Some(other) => Err(D::Error::custom(format!("invalid repeat mode {}", other))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scene_json() {
+        let json = r#"[{
+            "name": "Dinner",
+            "groups": [{
+                "rooms": [{"room": "Kitchen", "volume": 20}, {"room": "Dining", "volume": 15}],
+                "source": {"SonosFavorite": "Jazz"},
+                "shuffle": true,
+                "repeat": "all"
+            }]
+        }]"#;
+        let scenes: Vec<Scene> = serde_json::from_str(json).unwrap();
+        let group = &scenes[0].groups[0];
+        assert_eq!(group.rooms[1].room, "Dining");
+        assert_eq!(group.rooms[1].volume, Some(15));
+        assert!(matches!(group.source, Some(MediaSource::SonosFavorite(ref f)) if f == "Jazz"));
+        assert_eq!(group.repeat, Some(RepeatMode::All));
+        assert!(!group.play);
+    }
+
+    #[test]
+    fn test_scene_group_rooms() {
+        let group = SceneGroup::new(["Kitchen"])
+            .volume("kitchen", 20)
+            .mute("Garage", true)
+            .volume("Garage", 5);
+        let rooms = group
+            .rooms
+            .iter()
+            .map(|r| (r.room.as_str(), r.volume, r.mute))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rooms,
+            [("Kitchen", Some(20), None), ("Garage", Some(5), Some(true))]
+        );
+    }
+}
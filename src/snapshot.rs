@@ -0,0 +1,395 @@
+//! Snapshots of the grouping, playback and volumes of whole households, which
+//! can be saved as JSON and restored later.
+
+use crate::{
+    contentcache::browse,
+    controller::household_topology,
+    types::{HouseholdId, Step, StepReport, Uuid},
+    utils::{format_time, parse_time},
+    Error, Result, SpeakerData,
+};
+use serde::{Deserialize, Serialize};
+use sonor::{urns::AV_TRANSPORT, utils::escape_str_pcdata, Speaker};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Grouping, playback and volumes of every household under control, from
+/// [`crate::Manager::take_house_snapshot`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HouseSnapshot {
+    pub households: Vec<HouseholdSnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HouseholdSnapshot {
+    pub household: HouseholdId,
+    pub groups: Vec<GroupSnapshot>,
+}
+
+/// A group of zones and what it was playing
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupSnapshot {
+    pub coordinator: Uuid,
+    /// Every speaker of the group, the coordinator included
+    pub members: Vec<MemberSnapshot>,
+    pub transport: TransportSnapshot,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemberSnapshot {
+    pub uuid: Uuid,
+    /// Room name, for reference only
+    pub name: String,
+    pub volume: u16,
+    pub mute: bool,
+}
+
+/// Playback state of a group's coordinator
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransportSnapshot {
+    /// Transport URI, e.g. the queue or a radio stream. Empty if nothing was
+    /// loaded.
+    pub uri: String,
+    pub metadata: String,
+    /// Track number in the queue
+    pub track: u32,
+    /// Position in the track
+    pub position: Duration,
+    pub play_mode: String,
+    pub playing: bool,
+    /// Tracks of the queue, if the queue was playing
+    #[serde(default)]
+    pub queue: Vec<QueuedTrack>,
+}
+
+/// A track of a queue, as needed to add it again
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueuedTrack {
+    pub uri: String,
+    pub metadata: String,
+}
+
+impl HouseSnapshot {
+    /// Serialize the snapshot, to restore it in another run
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).map_err(|e| Error::SnapshotError(e.to_string()))
+    }
+
+    pub fn from_json(json: &str) -> Result<HouseSnapshot> {
+        serde_json::from_str(json).map_err(|e| Error::SnapshotError(e.to_string()))
+    }
+
+    fn groups(&self) -> impl Iterator<Item = &GroupSnapshot> {
+        self.households.iter().flat_map(|h| h.groups.iter())
+    }
+}
+
+/// The speakers a snapshot can be restored on, by UUID in upper case
+pub(crate) type HouseSpeakers = HashMap<Uuid, SpeakerData>;
+
+/// Capture a group from its coordinator and the speakers in it
+pub(crate) async fn capture_group(
+    coordinator: &Speaker,
+    members: &[Speaker],
+) -> Result<GroupSnapshot> {
+    let mut member_snapshots = Vec::new();
+    for speaker in members {
+        member_snapshots.push(MemberSnapshot {
+            uuid: speaker.uuid().to_owned(),
+            name: speaker.name().to_owned(),
+            volume: speaker.volume().await?,
+            mute: speaker.mute().await?,
+        });
+    }
+    Ok(GroupSnapshot {
+        coordinator: coordinator.uuid().to_owned(),
+        members: member_snapshots,
+        transport: capture_transport(coordinator).await?,
+    })
+}
+
+async fn capture_transport(coordinator: &Speaker) -> Result<TransportSnapshot> {
+    let instance = "<InstanceID>0</InstanceID>";
+    let mut media = coordinator
+        .action(AV_TRANSPORT, "GetMediaInfo", instance)
+        .await?;
+    let mut position = coordinator
+        .action(AV_TRANSPORT, "GetPositionInfo", instance)
+        .await?;
+    let mut settings = coordinator
+        .action(AV_TRANSPORT, "GetTransportSettings", instance)
+        .await?;
+    let info = coordinator
+        .action(AV_TRANSPORT, "GetTransportInfo", instance)
+        .await?;
+    let uri = media.remove("CurrentURI").unwrap_or_default();
+    let queue = match uri.starts_with("x-rincon-queue:") {
+        true => browse(coordinator, "Q:0")
+            .await?
+            .into_iter()
+            .filter_map(|item| {
+                Some(QueuedTrack {
+                    uri: item.uri?,
+                    metadata: item.metadata.unwrap_or_default(),
+                })
+            })
+            .collect(),
+        false => Vec::new(),
+    };
+    Ok(TransportSnapshot {
+        uri,
+        metadata: media.remove("CurrentURIMetaData").unwrap_or_default(),
+        track: position
+            .get("Track")
+            .and_then(|t| t.parse().ok())
+            .unwrap_or_default(),
+        position: position
+            .remove("RelTime")
+            .and_then(|t| parse_time(&t))
+            .unwrap_or_default(),
+        play_mode: settings.remove("PlayMode").unwrap_or_default(),
+        playing: matches!(
+            info.get("CurrentTransportState").map(String::as_str),
+            Some("PLAYING" | "TRANSITIONING")
+        ),
+        queue,
+    })
+}
+
+/// Take a speaker out of its group, leaving it on its own
+pub(crate) async fn leave_group(speaker: &Speaker) -> Result<()> {
+    speaker
+        .action(
+            AV_TRANSPORT,
+            "BecomeCoordinatorOfStandaloneGroup",
+            "<InstanceID>0</InstanceID>",
+        )
+        .await?;
+    Ok(())
+}
+
+/// Add a speaker to the group of a coordinator
+pub(crate) async fn join_group(speaker: &Speaker, coordinator: &str) -> Result<()> {
+    let uri = format!("x-rincon:{}", coordinator);
+    speaker.set_transport_uri(&uri, "").await?;
+    Ok(())
+}
+
+/// Restore a snapshot: regroup the speakers first, then set their volumes
+/// and restart playback. Nothing is changed unless every speaker of the
+/// snapshot is known. Speakers not in the snapshot are left alone. Steps
+/// that fail don't stop the others.
+pub(crate) async fn restore(
+    snapshot: &HouseSnapshot,
+    speakers: &HouseSpeakers,
+) -> Result<StepReport> {
+    let speaker = |uuid: &str| {
+        speakers
+            .get(&uuid.to_ascii_uppercase())
+            .ok_or_else(|| Error::SnapshotError(format!("Speaker {} not found", uuid)))
+    };
+    for group in snapshot.groups() {
+        speaker(&group.coordinator)?;
+        for member in group.members.iter() {
+            speaker(&member.uuid)?;
+        }
+    }
+
+    let mut report = StepReport::default();
+
+    // The groups are asked for now rather than taken from the controller,
+    // since the actions that ran before may have changed them
+    let mut coordinators = current_coordinators(snapshot, speakers).await?;
+
+    // Coordinators leave the groups they're in now, then members join them
+    for group in snapshot.groups() {
+        if !in_group(&coordinators, &group.coordinator, &group.coordinator) {
+            let coordinator = &speaker(&group.coordinator)?.speaker;
+            let room = coordinator.name().to_owned();
+            report.record(Step::Ungroup { room }, leave_group(coordinator).await);
+        }
+    }
+    // Leaving a group can change the coordinator of the rooms left behind
+    if !report.applied.is_empty() {
+        match current_coordinators(snapshot, speakers).await {
+            Ok(regrouped) => coordinators = regrouped,
+            Err(err) => log::warn!("Unable to read groups after ungrouping: {}", err),
+        }
+    }
+    for group in snapshot.groups() {
+        let coordinator = &speaker(&group.coordinator)?.speaker;
+        for member in group.members.iter() {
+            if in_group(&coordinators, &member.uuid, &group.coordinator) {
+                continue;
+            }
+            let speaker = &speaker(&member.uuid)?.speaker;
+            let step = Step::Join {
+                room: speaker.name().to_owned(),
+                coordinator: coordinator.name().to_owned(),
+            };
+            report.record(step, join_group(speaker, &group.coordinator).await);
+        }
+    }
+
+    for member in snapshot.groups().flat_map(|g| g.members.iter()) {
+        let speaker = &speaker(&member.uuid)?.speaker;
+        let room = speaker.name().to_owned();
+        let result = speaker.set_volume(member.volume).await;
+        let step = Step::Volume {
+            room: room.clone(),
+            volume: member.volume,
+        };
+        report.record(step, result.map_err(Error::from));
+        let result = speaker.set_mute(member.mute).await;
+        let step = Step::Mute {
+            room,
+            mute: member.mute,
+        };
+        report.record(step, result.map_err(Error::from));
+    }
+
+    for group in snapshot.groups() {
+        let coordinator = speaker(&group.coordinator)?;
+        if group.transport.uri.is_empty() {
+            continue;
+        }
+        let room = coordinator.speaker.name().to_owned();
+        let result = restore_transport(coordinator, &group.transport).await;
+        report.record(Step::Transport { room }, result);
+    }
+    Ok(report)
+}
+
+/// Get the coordinator of every speaker of the households of a snapshot,
+/// by UUID in upper case. The speakers of a household share its topology,
+/// so the first that answers is asked.
+async fn current_coordinators(
+    snapshot: &HouseSnapshot,
+    speakers: &HouseSpeakers,
+) -> Result<HashMap<Uuid, Uuid>> {
+    let mut coordinators = HashMap::new();
+    for household in snapshot.households.iter() {
+        let members = household.groups.iter().flat_map(|g| g.members.iter());
+        let members = members
+            .filter_map(|m| speakers.get(&m.uuid.to_ascii_uppercase()))
+            .map(|sd| &sd.speaker);
+        let Some((topology, _)) = household_topology(members).await else {
+            // Nothing to regroup in a household without groups
+            if household.groups.is_empty() {
+                continue;
+            }
+            return Err(Error::SnapshotError(format!(
+                "Unable to get the groups of {}",
+                household.household
+            )));
+        };
+        for (coordinator, infos) in topology {
+            for info in infos {
+                coordinators.insert(info.uuid().to_ascii_uppercase(), coordinator.clone());
+            }
+        }
+    }
+    Ok(coordinators)
+}
+
+/// Whether a speaker is in the group of a coordinator
+fn in_group(coordinators: &HashMap<Uuid, Uuid>, uuid: &str, coordinator: &str) -> bool {
+    coordinators
+        .get(&uuid.to_ascii_uppercase())
+        .is_some_and(|c| c.eq_ignore_ascii_case(coordinator))
+}
+
+async fn restore_transport(
+    coordinatordata: &SpeakerData,
+    transport: &TransportSnapshot,
+) -> Result<()> {
+    let coordinator = &coordinatordata.speaker;
+    let is_queue = transport.uri.starts_with("x-rincon-queue:");
+    // The queue may have changed since, so it is filled again first
+    if is_queue && !transport.queue.is_empty() {
+        let refilled = async {
+            coordinator.clear_queue().await?;
+            for track in transport.queue.iter() {
+                coordinator
+                    .queue_end(&track.uri, &escape_str_pcdata(&track.metadata))
+                    .await?;
+            }
+            Ok::<_, Error>(())
+        };
+        let refilled = refilled.await;
+        // Even if refilling failed partway, the cached queue is stale
+        coordinatordata.queue.invalidate();
+        refilled?;
+    }
+    coordinator
+        .set_transport_uri(&transport.uri, &escape_str_pcdata(&transport.metadata))
+        .await?;
+    if !transport.play_mode.is_empty() {
+        let payload = format!(
+            "<InstanceID>0</InstanceID><NewPlayMode>{}</NewPlayMode>",
+            transport.play_mode
+        );
+        coordinator
+            .action(AV_TRANSPORT, "SetPlayMode", &payload)
+            .await?;
+    }
+    // Only the queue can be seeked; streams just start again
+    if is_queue && transport.track > 0 {
+        coordinator.seek_track(transport.track).await?;
+        let payload = format!(
+            "<InstanceID>0</InstanceID><Unit>REL_TIME</Unit><Target>{}</Target>",
+            format_time(transport.position)
+        );
+        coordinator.action(AV_TRANSPORT, "Seek", &payload).await?;
+    }
+    if transport.playing {
+        coordinator.play().await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_house_snapshot_json() -> Result<()> {
+        let snapshot = HouseSnapshot {
+            households: vec![HouseholdSnapshot {
+                household: "Sonos_1".into(),
+                groups: vec![GroupSnapshot {
+                    coordinator: "RINCON_1".into(),
+                    members: vec![MemberSnapshot {
+                        uuid: "RINCON_1".into(),
+                        name: "Kitchen".into(),
+                        volume: 12,
+                        mute: false,
+                    }],
+                    transport: TransportSnapshot {
+                        uri: "x-rincon-queue:RINCON_1#0".into(),
+                        track: 3,
+                        position: Duration::from_secs(95),
+                        play_mode: "SHUFFLE".into(),
+                        playing: true,
+                        queue: vec![QueuedTrack {
+                            uri: "x-sonos-spotify:track".into(),
+                            metadata: "<DIDL-Lite/>".into(),
+                        }],
+                        ..Default::default()
+                    },
+                }],
+            }],
+        };
+        assert_eq!(HouseSnapshot::from_json(&snapshot.to_json()?)?, snapshot);
+        assert!(HouseSnapshot::from_json("{}").is_err());
+        // Snapshots saved before queues were captured still load
+        let mut json: serde_json::Value = serde_json::from_str(&snapshot.to_json()?).unwrap();
+        json["households"][0]["groups"][0]["transport"]
+            .as_object_mut()
+            .unwrap()
+            .remove("queue");
+        let old = HouseSnapshot::from_json(&json.to_string())?;
+        assert!(old.households[0].groups[0].transport.queue.is_empty());
+        Ok(())
+    }
+}
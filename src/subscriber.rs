@@ -1,34 +1,50 @@
 #![allow(missing_docs)]
 
-use futures_util::stream::StreamExt;
-
 use log::{debug, error, info, warn};
-use sonor::{extract_zone_topology, urns::AV_TRANSPORT};
-use std::time::Duration;
-use tokio::{self, sync::watch, task::JoinHandle, time};
+use sonor::urns::ZONE_GROUP_TOPOLOGY;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+use tokio::{
+    self,
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinHandle,
+    time::{self, Instant},
+};
 
 use super::{
-    types::{Event, EventReceiver, Uuid},
-    utils::extract_av_transport_last_change,
+    builder::Timings,
+    gena::{self, CallbackServer, Notification, Reorder, SeqCheck, Sequence},
+    resync::poll_state,
+    types::{Event, EventReceiver, ServiceEvent, SubscriptionStatus, Uuid},
+    utils::expand_state_vars,
     Error::SubscriberError,
     Result,
 };
 
-const TIMEOUT_SEC: u32 = 300;
-const RENEW_SEC: u64 = 60;
+type Sender = mpsc::Sender<Event>;
 
-type Sender = tokio::sync::watch::Sender<Event>;
+/// Events of a subscription that can wait for the controller. Beyond this,
+/// the subscription waits too.
+const EVENT_QUEUE_SIZE: usize = 32;
 
-/// Manages subscriptions to services. Returns a `tokio::sync::watch::Receiver`
-/// that will carry the latest data. Will handle resubscribing as long as there
-/// are still receiver handles. Once all receiver handles are dropped, the
-/// subscriber will shutdown and will need to be recreated.
+/// Manages subscriptions to services. Returns a `tokio::sync::mpsc::Receiver`
+/// that will carry every event, in order. Will handle resubscribing as long
+/// as the receiver is alive. Once the receiver is dropped, the subscriber
+/// will shutdown and will need to be recreated.
 #[derive(Debug)]
 pub struct Subscriber {
     service: sonor::rupnp::Service,
     url: sonor::rupnp::http::Uri,
+    /// Where subscription requests go, kept to unsubscribe when dropped
+    event_url: Arc<OnceLock<sonor::rupnp::http::Uri>>,
     pub uuid: Option<Uuid>,
     task_handle: Option<JoinHandle<Result<Sender>>>,
+    status: Arc<Mutex<SubscriptionStatus>>,
+    timeout_sec: u32,
+    renewal_interval: Duration,
+    initial_notify_timeout: Duration,
+    /// Listener that receives the events
+    callback: Arc<CallbackServer>,
 }
 
 impl Subscriber {
@@ -36,26 +52,81 @@ impl Subscriber {
         service: sonor::rupnp::Service,
         url: sonor::rupnp::http::Uri,
         uuid: Option<Uuid>,
+        timings: &Timings,
+        callback: &Arc<CallbackServer>,
     ) -> Subscriber {
+        let status = SubscriptionStatus {
+            service: service.service_type().typ().to_owned(),
+            uuid: uuid.clone(),
+            ..Default::default()
+        };
         Subscriber {
             service,
             url,
+            event_url: Arc::default(),
             uuid,
             task_handle: None,
+            status: Arc::new(Mutex::new(status)),
+            timeout_sec: timings
+                .subscription_timeout
+                .as_secs()
+                .try_into()
+                .unwrap_or(u32::MAX),
+            renewal_interval: timings.renewal_period(),
+            initial_notify_timeout: timings.initial_notify_timeout,
+            callback: callback.clone(),
         }
     }
 
-    pub fn subscribe(&mut self) -> Result<EventReceiver> {
-        if self.service.service_type() == AV_TRANSPORT && self.uuid.is_none() {
-            return Err(SubscriberError(
-                "Need UUID for AV_TRANSPORT Subscriptions!".into(),
-            ));
+    /// Get the current health of the subscription
+    pub fn status(&self) -> SubscriptionStatus {
+        self.status
+            .lock()
+            .map(|status| status.clone())
+            .unwrap_or_default()
+    }
+
+    /// Keep counting from the status of the subscription this one replaces
+    pub fn carry_over(&self, previous: &SubscriptionStatus) {
+        record(&self.status, |s| {
+            s.resubscribes += previous.resubscribes + 1;
+            s.renewal_failures += previous.renewal_failures;
+        })
+    }
+
+    /// Stop the subscription task and cancel the subscription with the
+    /// speaker, so it stops sending events.
+    pub async fn unsubscribe(&mut self) {
+        if let Some(handle) = self.task_handle.take() {
+            handle.abort();
         }
+        let sid = self.status.lock().ok().and_then(|mut s| {
+            s.subscribed = false;
+            s.sid.take()
+        });
+        if let (Some(sid), Some(event_url)) = (sid, self.event_url.get()) {
+            debug!(
+                "Unsubscribing from {} on {}",
+                self.status().service,
+                self.uuid.as_deref().unwrap_or("unknown UUID")
+            );
+            if let Err(err) = gena::unsubscribe(event_url, &sid).await {
+                debug!("Unable to unsubscribe: {}", err);
+            }
+        }
+    }
 
-        // Create the notification channel
-        let (tx, mut rx) = watch::channel(Event::NoOp);
-        rx.borrow_and_update(); // Mark NoOp as read
+    pub fn subscribe(&mut self) -> Result<EventReceiver> {
+        // Events of speaker services are only useful if we know the speaker
+        if self.service.service_type() != ZONE_GROUP_TOPOLOGY && self.uuid.is_none() {
+            return Err(SubscriberError(format!(
+                "Need UUID for {} Subscriptions!",
+                self.service.service_type().typ()
+            )));
+        }
 
+        // Create the notification queue
+        let (tx, rx) = mpsc::channel(EVENT_QUEUE_SIZE);
         self.spawn_task(tx)?;
         Ok(rx)
     }
@@ -70,84 +141,136 @@ impl Subscriber {
         use Event::*;
         let service = self.service.clone();
         let url = self.url.clone();
+        let event_url = self.event_url.clone();
         let uuid = self.uuid.clone();
+        let status = self.status.clone();
+        let callback = self.callback.clone();
+        let timeout_sec = self.timeout_sec;
+        let renewal_interval = self.renewal_interval;
+        let initial_notify_timeout = self.initial_notify_timeout;
 
         let task_handle = tokio::spawn(async move {
             let service_type = service.service_type();
-            let (mut sid, mut stream) =
-                service.subscribe(&url, TIMEOUT_SEC).await.map_err(|err| {
-                    tx.send(SubscribeError(uuid.clone(), service_type.to_owned()))
-                        .ok();
-                    sonor::Error::UPnP(err)
-                })?;
-            let mut interval = time::interval(Duration::from_secs(RENEW_SEC));
-            loop {
-                // Select over reading from the subscription stream, aborting
-                // due to no more subscribers, and resubscription timer
-                tokio::select! {
-                    maybe_state_vars = &mut stream.next() => match maybe_state_vars {
-                        Some(Ok(mut state_vars)) => match service_type.typ() {
-                            "ZoneGroupTopology" => {
-                                state_vars
-                                    .remove("ZoneGroupState")
-                                    .and_then(|xml| extract_zone_topology(&xml)
-                                        .map_err(|err| warn!("Unable to extract topology: {}", err))
-                                        .ok())
-                                    .and_then(|topology| tx.send(TopoUpdate(uuid.clone(), topology)).ok());
+            let typ = service_type.typ();
+            let result: Result<()> = async {
+                let mut registration = callback.register(&url).await?;
+                record(&status, |s| s.callback = Some(registration.callback.clone()));
+                let event_url = match event_url.get() {
+                    Some(event_url) => event_url.clone(),
+                    None => {
+                        let found = callback.event_url(&url, typ).await?;
+                        event_url.get_or_init(|| found).clone()
+                    }
+                };
+                let mut sid =
+                    gena::subscribe(&event_url, &registration.callback, timeout_sec).await?;
+                record(&status, |s| {
+                    s.subscribed = true;
+                    s.sid = Some(sid.clone());
+                });
+                // Speakers send the current state right after subscribing. If it
+                // doesn't come, the speaker can't reach the callback URL.
+                let mut initial_deadline = Some(Instant::now() + initial_notify_timeout);
+                let mut sequence = Sequence::default();
+                let mut reorder = Reorder::default();
+                let mut interval = time::interval(renewal_interval);
+                loop {
+                    // Events that are in order, to pass on
+                    let mut ready = Vec::new();
+                    // Select over reading from the subscription stream, aborting
+                    // due to no more subscribers, and resubscription timer
+                    tokio::select! {
+                        maybe_notification = registration.rx.recv() => match maybe_notification {
+                            Some(notification) if notification.sid != sid => {
+                                debug!("Ignoring event of old subscription {}", notification.sid);
                             }
-                            "AVTransport" => {
-                                state_vars
-                                    .remove("LastChange")
-                                    .and_then(|xml| extract_av_transport_last_change(&xml)
-                                        .map_err(|err| warn!("Unable to extract last change: {}", err))
-                                        .ok())
-                                    .and_then(|last_change| tx.send(AVTransUpdate(uuid.clone(), last_change)).ok());
+                            Some(notification) => {
+                                initial_deadline = None;
+                                record(&status, |s| {
+                                    s.last_notify = Some(SystemTime::now());
+                                    s.notifications += 1;
+                                });
+                                ready = reorder.push(notification);
                             }
-                            _ => ()
-
+                            None => {
+                                error!("Event listener stopped?!?");
+                                return Err(SubscriberError("Event listener stopped".into()));
+                            }
+                        },
+                        _ = time::sleep_until(reorder.deadline().unwrap_or_else(Instant::now)), if reorder.deadline().is_some() => {
+                            ready = reorder.flush();
                         }
-                        Some(Err(err)) => {
-                            // There is an error from the TCP socket. Probably best to resubscribe.
-                            warn!("TCP socket error: {}", err);
-                            let new_sub = service.subscribe(&url, TIMEOUT_SEC)
-                                .await
-                                .map_err(|err| {
-                                    tx.send(SubscribeError(uuid.clone(), service_type.to_owned())).ok();
-                                    sonor::Error::UPnP(err)})?;
-                            sid = new_sub.0;
-                            stream = new_sub.1;
+                        _ = time::sleep_until(initial_deadline.unwrap_or_else(Instant::now)), if initial_deadline.is_some() => {
+                            initial_deadline = None;
+                            let err = format!(
+                                "No initial event within {:?}. Is {} reachable from the speaker?",
+                                initial_notify_timeout, registration.callback
+                            );
+                            warn!("{} on {}: {}", typ, uuid.as_deref().unwrap_or("unknown UUID"), err);
+                            record(&status, |s| s.last_error = Some(err));
                         }
-                        _ => {
-                            error!("Subscription stream terminated?!?");
-                            let new_sub = service.subscribe(&url, TIMEOUT_SEC)
-                                .await
-                                .map_err(|err| {
-                                    tx.send(SubscribeError(uuid.clone(), service_type.to_owned())).ok();
-                                    sonor::Error::UPnP(err)})?;
-                            sid = new_sub.0;
-                            stream = new_sub.1;
+                        _ = tx.closed() => {
+                            info!("No more subscribers. Shutting down {} on {}", typ, uuid.as_deref().unwrap_or("unknown UUID"));
+                            gena::unsubscribe(&event_url, &sid).await.unwrap_or(());
+                            record(&status, |s| {
+                                s.subscribed = false;
+                                s.sid = None;
+                            });
+                            break
+                        },
+                        _ = interval.tick() => {
+                            debug!("Attempting resubscribe to {} on {}...", typ, uuid.as_deref().unwrap_or("unknown UUID"));
+                            if let Err(err) = gena::renew(&event_url, &sid, timeout_sec).await {
+                                info!("{} while resubscribing. Attempting new subscription", err);
+                                record(&status, |s| {
+                                    s.renewal_failures += 1;
+                                    s.resubscribes += 1;
+                                    s.last_error = Some(err.to_string());
+                                });
+                                sid = gena::subscribe(&event_url, &registration.callback, timeout_sec).await?;
+                                record(&status, |s| s.sid = Some(sid.clone()));
+                                initial_deadline = Some(Instant::now() + initial_notify_timeout);
+                                sequence.reset();
+                                reorder.reset();
+                            } else {
+                                debug!("    ...{} on {} subscription renewed", typ, uuid.as_deref().unwrap_or("unknown UUID"));
+                            }
                         }
-                    },
-                    _ = tx.closed() => {
-                        info!("No more subscribers. Shutting down {} on {}", service_type.typ(), uuid.as_deref().unwrap_or("unknown UUID"));
-                        service.unsubscribe(&url, &sid).await.unwrap_or(());
-                        break
-                    },
-                    _ = interval.tick() => {
-                        let uuid = uuid.to_owned();
-                        debug!("Attempting resubscribe to {} on {}...", service_type.typ(), uuid.as_deref().unwrap_or("unknown UUID"));
-                        if let Err(err) = service.renew_subscription(&url, &sid, TIMEOUT_SEC).await {
-                            info!("{} while resubscribing. Attempting new subscription", sonor::Error::UPnP(err));
-                            let new_sub = service.subscribe(&url, TIMEOUT_SEC).await.map_err(|err| {
-                                tx.send(SubscribeError(uuid, service_type.to_owned())).ok();
-                                sonor::Error::UPnP(err)})?;
-                            sid = new_sub.0;
-                            stream = new_sub.1;
-                        } else {
-                            debug!("    ...{} on {} subscription renewed", service_type.typ(), uuid.as_deref().unwrap_or("unknown UUID"));
+                    }
+                    for Notification { vars, seq, .. } in ready {
+                        let check = seq.map(|seq| sequence.check(seq));
+                        let event = ServiceEvent {
+                            uuid: uuid.clone(),
+                            service: service_type.to_owned(),
+                            vars: expand_state_vars(vars),
+                            resync: false,
+                        };
+                        deliver(&tx, &status, ServiceUpdate(event)).await;
+                        let name = uuid.as_deref().unwrap_or("unknown UUID");
+                        match check {
+                            Some(SeqCheck::Missed(missed)) => {
+                                warn!("Missed {} events of {} on {}", missed, typ, name);
+                                record(&status, |s| s.missed_events += u64::from(missed));
+                                resync(&service, &url, &uuid, &tx, &status).await;
+                            }
+                            Some(SeqCheck::Restarted) => {
+                                warn!("Events of {} on {} started over", typ, name);
+                                resync(&service, &url, &uuid, &tx, &status).await;
+                            }
+                            _ => (),
                         }
                     }
                 }
+                Ok(())
+            }
+            .await;
+            if let Err(err) = result {
+                // Queued behind any pending events, so it can't overtake them
+                record_failure(&status, &err);
+                tx.send(SubscribeError(uuid.clone(), service_type.to_owned()))
+                    .await
+                    .ok();
+                return Err(err);
             }
             Ok(tx)
         });
@@ -157,8 +280,125 @@ impl Subscriber {
     }
 }
 
+/// Queue an event for the controller. If the queue is full, the overflow is
+/// counted and the event waits for room, so no event is lost.
+async fn deliver(tx: &Sender, status: &Mutex<SubscriptionStatus>, event: Event) {
+    match tx.try_send(event) {
+        Ok(()) => (),
+        Err(TrySendError::Full(event)) => {
+            debug!("Event queue full, waiting for the controller");
+            record(status, |s| s.queue_overflows += 1);
+            tx.send(event).await.ok();
+        }
+        Err(TrySendError::Closed(_)) => (),
+    }
+}
+
+/// Poll the state of a service after events were missed, and queue it as an
+/// event of its own
+async fn resync(
+    service: &sonor::rupnp::Service,
+    url: &sonor::rupnp::http::Uri,
+    uuid: &Option<Uuid>,
+    tx: &Sender,
+    status: &Mutex<SubscriptionStatus>,
+) {
+    let service_type = service.service_type();
+    // The event without variables tells that the state is unknown, so the
+    // controller drops what it has cached
+    let vars = match poll_state(service, url).await {
+        Ok(Some(vars)) => vars,
+        Ok(None) => {
+            debug!("State of {} can't be polled", service_type.typ());
+            Vec::new()
+        }
+        Err(err) => {
+            warn!("Unable to resync {}: {}", service_type.typ(), err);
+            record(status, |s| s.last_error = Some(err.to_string()));
+            Vec::new()
+        }
+    };
+    debug!("Resynced {}", service_type.typ());
+    record(status, |s| s.resyncs += 1);
+    let event = ServiceEvent {
+        uuid: uuid.clone(),
+        service: service_type.to_owned(),
+        vars,
+        resync: true,
+    };
+    deliver(tx, status, Event::ServiceUpdate(event)).await;
+}
+
+/// Events of a service on a speaker, from [`crate::Zone::subscribe`]. The
+/// subscription is cancelled with the speaker when this is dropped, or
+/// right away with [`ServiceSubscription::unsubscribe`].
+#[derive(Debug)]
+pub struct ServiceSubscription {
+    subscriber: Subscriber,
+    rx: EventReceiver,
+}
+
+impl ServiceSubscription {
+    pub(crate) fn new(subscriber: Subscriber, rx: EventReceiver) -> ServiceSubscription {
+        ServiceSubscription { subscriber, rx }
+    }
+
+    /// Wait for the next event. Fails once the subscription is lost, e.g.
+    /// when the speaker goes offline.
+    pub async fn recv(&mut self) -> Result<ServiceEvent> {
+        match self.rx.recv().await {
+            Some(Event::ServiceUpdate(event)) => Ok(event),
+            _ => Err(SubscriberError(format!(
+                "Lost subscription to {}",
+                self.subscriber.status().service
+            ))),
+        }
+    }
+
+    /// Get the current health of the subscription
+    pub fn status(&self) -> SubscriptionStatus {
+        self.subscriber.status()
+    }
+
+    /// End the subscription, and tell the speaker to stop sending events
+    pub async fn unsubscribe(mut self) {
+        self.subscriber.unsubscribe().await
+    }
+}
+
+/// Update the shared status of a subscription
+fn record(status: &Mutex<SubscriptionStatus>, update: impl FnOnce(&mut SubscriptionStatus)) {
+    if let Ok(mut status) = status.lock() {
+        update(&mut status)
+    }
+}
+
+/// Mark a subscription as lost
+fn record_failure(status: &Mutex<SubscriptionStatus>, err: &impl ToString) {
+    record(status, |s| {
+        s.subscribed = false;
+        s.sid = None;
+        s.last_error = Some(err.to_string());
+    })
+}
+
 impl Drop for Subscriber {
     fn drop(&mut self) {
         self.task_handle.as_ref().map(JoinHandle::abort);
+        // Tell the speaker to stop sending events, unless that was done
+        // already or there's no runtime left to do it
+        let sid = self.status.lock().ok().and_then(|mut s| {
+            s.subscribed = false;
+            s.sid.take()
+        });
+        let event_url = self.event_url.get().cloned();
+        let runtime = tokio::runtime::Handle::try_current();
+        if let (Some(sid), Some(event_url), Ok(runtime)) = (sid, event_url, runtime) {
+            runtime.spawn(async move {
+                if let Err(err) = gena::unsubscribe(&event_url, &sid).await {
+                    debug!("Unable to unsubscribe: {}", err);
+                }
+            });
+        }
     }
 }
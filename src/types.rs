@@ -1,7 +1,11 @@
-use sonor::{SpeakerInfo, URN};
+use sonor::{RepeatMode, SpeakerInfo, URN};
+use std::fmt;
+use std::time::{Duration, SystemTime};
 use tokio::sync::{mpsc, oneshot};
 
-use crate::{Command, Snapshot, Track};
+use crate::{
+    Alarm, Command, HouseSnapshot, MediaSource, NowPlaying, ServiceSubscription, Snapshot, Track,
+};
 
 use super::Error;
 
@@ -11,6 +15,20 @@ pub enum Response {
     NotOk,
     Snapshot(Snapshot),
     Queue(Vec<Track>),
+    Ambiguous(Vec<HouseholdId>),
+    Volume(u16),
+    Level(i8),
+    Bool(bool),
+    Unsupported(String),
+    Zone(Uuid),
+    Rendering(RenderingState),
+    GroupRendering(GroupRenderingState),
+    Alarms(Vec<Alarm>),
+    AlarmId(u32),
+    SleepTimer(Option<Duration>),
+    HouseSnapshot(HouseSnapshot),
+    Position(Position),
+    NowPlaying(NowPlaying),
 }
 
 #[derive(Debug)]
@@ -21,26 +39,399 @@ pub enum ControllerStatus {
 
 #[derive(Debug, Clone)]
 pub enum Event {
-    TopoUpdate(Option<Uuid>, Topology),
+    TopoUpdate(Option<Uuid>, Topology, Bonds),
     AVTransUpdate(Option<Uuid>, AVStatus),
+    /// Some of the AVTransport variables, polled after events were missed
+    AVTransResync(Option<Uuid>, AVStatus),
+    RenderingUpdate(Option<Uuid>, RenderingStatus),
+    GroupRenderingUpdate(Option<Uuid>, AVStatus),
+    ContentUpdate(Option<Uuid>, AVStatus),
+    AlarmUpdate(Option<Uuid>, AVStatus),
+    /// Variables of any service, as they come from the subscriber
+    ServiceUpdate(ServiceEvent),
+    /// Events of a service were missed, and its state can't be polled or
+    /// polling it failed
+    ServiceResync(Option<Uuid>, URN),
     SubscribeError(Option<Uuid>, URN),
-    NoOp,
+}
+
+/// A change made to a room to restore a house snapshot or apply a scene
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    /// Take a room out of its group
+    Ungroup {
+        room: String,
+    },
+    Join {
+        room: String,
+        coordinator: String,
+    },
+    Volume {
+        room: String,
+        volume: u16,
+    },
+    Mute {
+        room: String,
+        mute: bool,
+    },
+    /// Load the queue or stream of a group and pick up playback
+    Transport {
+        room: String,
+    },
+    PlaySource {
+        room: String,
+        source: MediaSource,
+    },
+    Shuffle {
+        room: String,
+        shuffle: bool,
+    },
+    Repeat {
+        room: String,
+        repeat: RepeatMode,
+    },
+    Play {
+        room: String,
+    },
+}
+
+/// Outcome of restoring a house snapshot or applying a scene, step by step
+#[derive(Debug, Default)]
+pub struct StepReport {
+    pub applied: Vec<Step>,
+    pub failed: Vec<(Step, Error)>,
+}
+
+impl StepReport {
+    /// Whether every step succeeded
+    pub fn is_ok(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    pub(crate) fn record(&mut self, step: Step, result: Result<()>) {
+        match result {
+            Ok(_) => self.applied.push(step),
+            Err(err) => {
+                log::warn!("Step {:?} failed: {}", step, err);
+                self.failed.push((step, err));
+            }
+        }
+    }
+}
+
+/// Changed state variables of a service on a speaker
+#[derive(Debug, Clone)]
+pub struct ServiceEvent {
+    pub uuid: Option<Uuid>,
+    pub service: URN,
+    pub vars: Vec<StateVar>,
+    /// The variables were polled from the speaker after events were missed,
+    /// and may be only some of the state. There are none for services whose
+    /// state can't be polled, or when polling failed, so the state may have
+    /// changed in any way.
+    pub resync: bool,
+}
+
+/// A state variable from an event. Variables of a LastChange event carry
+/// the instance they belong to, and some, like volumes, a channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateVar {
+    pub name: String,
+    pub value: String,
+    /// `val` of the instance element, e.g. the InstanceID or QueueID
+    pub instance: Option<String>,
+    pub channel: Option<String>,
 }
 
 pub type Uuid = String;
 pub type CmdSender = mpsc::Sender<Command>;
 pub type CmdReceiver = mpsc::Receiver<Command>;
-pub type EventReceiver = tokio::sync::watch::Receiver<Event>;
+pub type EventReceiver = mpsc::Receiver<Event>;
 
 pub type Topology = Vec<(Uuid, Vec<SpeakerInfo>)>;
+pub type Bonds = Vec<Satellite>;
 pub type AVStatus = Vec<(String, String)>;
+/// Changed RenderingControl variables as (name, channel, value)
+pub type RenderingStatus = Vec<(String, Option<String>, String)>;
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
-/// Type for zone name
-pub type ZoneName = String;
+/// A speaker bonded to another to make one logical room: the second speaker
+/// of a stereo pair, a home theater surround or a sub. Satellites are never
+/// addressed directly; the primary speaker stands for the whole bonded set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Satellite {
+    pub uuid: Uuid,
+    pub primary: Uuid,
+    /// Channels the satellite plays, e.g. "RF,RF", "LR" or "SW"
+    pub channels: String,
+}
+
+/// Health of an event subscription to a service on a speaker
+#[derive(Debug, Clone, Default)]
+pub struct SubscriptionStatus {
+    /// Service type, e.g. "AVTransport"
+    pub service: String,
+    pub uuid: Option<Uuid>,
+    pub speaker_name: Option<String>,
+    pub subscribed: bool,
+    pub sid: Option<String>,
+    /// URL the speaker sends events to
+    pub callback: Option<String>,
+    pub last_notify: Option<SystemTime>,
+    pub notifications: u64,
+    /// Events that never arrived, by the gaps in their event keys
+    pub missed_events: u64,
+    /// Times events were missed and the state was polled, or reported as
+    /// unknown where it can't be polled
+    pub resyncs: u32,
+    /// Events that found the queue to the controller full and had to wait
+    pub queue_overflows: u64,
+    pub renewal_failures: u32,
+    pub resubscribes: u32,
+    pub last_error: Option<String>,
+}
+
+/// Health of the controller and all of its subscriptions
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics {
+    pub rediscovery_attempts: u32,
+    pub rediscovery_failures: u32,
+    pub subscriptions: Vec<SubscriptionStatus>,
+}
+
+/// Audio channel of a speaker, for per-channel volume
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Master,
+    Left,
+    Right,
+}
+
+impl Channel {
+    /// Channel name as used by the RenderingControl service
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Channel::Master => "Master",
+            Channel::Left => "LF",
+            Channel::Right => "RF",
+        }
+    }
+}
+
+/// Volume, mute and EQ of a speaker, kept up-to-date by RenderingControl
+/// events. Values the speaker has not reported yet are `None`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RenderingState {
+    pub volume: Option<u16>,
+    pub mute: Option<bool>,
+    pub left_volume: Option<u16>,
+    pub right_volume: Option<u16>,
+    pub bass: Option<i8>,
+    pub treble: Option<i8>,
+    pub loudness: Option<bool>,
+    pub night_mode: Option<bool>,
+    pub speech_enhancement: Option<bool>,
+    pub sub_gain: Option<i8>,
+    pub surround_level: Option<i8>,
+    pub surround_enabled: Option<bool>,
+}
+
+impl RenderingState {
+    /// Apply the variables of a RenderingControl LastChange event
+    pub(crate) fn update(&mut self, vars: &RenderingStatus) {
+        for (name, channel, value) in vars {
+            let master = channel
+                .as_deref()
+                .is_none_or(|c| c == Channel::Master.as_str());
+            match name.as_str() {
+                "Volume" if master => self.volume = value.parse().ok(),
+                "Volume" if channel.as_deref() == Some(Channel::Left.as_str()) => {
+                    self.left_volume = value.parse().ok()
+                }
+                "Volume" if channel.as_deref() == Some(Channel::Right.as_str()) => {
+                    self.right_volume = value.parse().ok()
+                }
+                "Mute" if master => self.mute = parse_bool(value),
+                "Loudness" if master => self.loudness = parse_bool(value),
+                "Bass" => self.bass = value.parse().ok(),
+                "Treble" => self.treble = value.parse().ok(),
+                "NightMode" => self.night_mode = parse_bool(value),
+                "DialogLevel" => self.speech_enhancement = parse_bool(value),
+                "SubGain" => self.sub_gain = value.parse().ok(),
+                "SurroundLevel" => self.surround_level = value.parse().ok(),
+                "SurroundEnable" => self.surround_enabled = parse_bool(value),
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Playback position in the current track
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub elapsed: Duration,
+    /// Length of the track; `None` for streams
+    pub duration: Option<Duration>,
+}
+
+/// Volume and mute of a group, kept up-to-date by GroupRenderingControl
+/// events on its coordinator.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GroupRenderingState {
+    pub volume: Option<u16>,
+    pub mute: Option<bool>,
+}
+
+impl GroupRenderingState {
+    /// Apply the variables of a GroupRenderingControl event
+    pub(crate) fn update(&mut self, vars: &AVStatus) {
+        for (name, value) in vars {
+            match name.as_str() {
+                "GroupVolume" => self.volume = value.parse().ok(),
+                "GroupMute" => self.mute = parse_bool(value),
+                _ => (),
+            }
+        }
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "1" => Some(true),
+        "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Changes in the sonos system(s), from [`crate::Manager::events`]
+#[derive(Debug, Clone)]
+pub enum ZoneEvent {
+    /// Volume, mute or EQ of a speaker changed
+    Rendering {
+        zone: ZoneName,
+        state: RenderingState,
+    },
+    /// Volume or mute of the group coordinated by the zone's speaker changed
+    GroupRendering {
+        zone: ZoneName,
+        state: GroupRenderingState,
+    },
+    /// The sonos favorites of a household changed
+    FavoritesChanged { household: HouseholdId },
+    /// The sonos playlists of a household changed
+    PlaylistsChanged { household: HouseholdId },
+    /// The queue of the zone's speaker changed
+    QueueChanged { zone: ZoneName },
+    /// Alarms of a household were added, changed or removed
+    AlarmsChanged { household: HouseholdId },
+}
+
+/// Type for household ID
+pub type HouseholdId = String;
+
+/// Type for zone name, optionally qualified by the household it is in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZoneName {
+    pub household: Option<HouseholdId>,
+    pub name: String,
+}
+
+impl ZoneName {
+    /// Whether a zone with this name could be in the given household
+    pub fn matches_household(&self, household: &str) -> bool {
+        self.household
+            .as_deref()
+            .is_none_or(|h| h.eq_ignore_ascii_case(household))
+    }
+}
+
+impl From<String> for ZoneName {
+    fn from(name: String) -> Self {
+        ZoneName {
+            household: None,
+            name,
+        }
+    }
+}
+
+impl fmt::Display for ZoneName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.household {
+            Some(ref household) => write!(f, "{} ({})", self.name, household),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
 
 /// Type for response channel
 pub type ZoneActionResponder = oneshot::Sender<Response>;
 
 /// Type for status response channel
 pub type StatusResponder = oneshot::Sender<ControllerStatus>;
+
+/// Type for households response channel
+pub type HouseholdsResponder = oneshot::Sender<Vec<HouseholdId>>;
+
+/// Type for diagnostics response channel
+pub type DiagnosticsResponder = oneshot::Sender<Diagnostics>;
+
+/// Type for response channel of actions on every group, with the result
+/// for each group's coordinator
+pub type BulkResponder = oneshot::Sender<Vec<(ZoneName, Result<()>)>>;
+
+/// Type for service subscription response channel
+pub type SubscriptionResponder = oneshot::Sender<Result<ServiceSubscription>>;
+
+/// Type for house snapshot restore response channel
+pub type RestoreResponder = oneshot::Sender<Result<StepReport>>;
+
+/// Type for scene response channel
+pub type SceneResponder = oneshot::Sender<Result<StepReport>>;
+
+/// Type for shutdown response channel
+pub type ShutdownResponder = oneshot::Sender<()>;
+
+/// Type for zone listing response channel
+pub type ZonesResponder = oneshot::Sender<Vec<ZoneName>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rendering_state() {
+        let vars = [
+            ("Volume", Some("Master"), "23"),
+            ("Volume", Some("LF"), "100"),
+            ("Volume", Some("RF"), "80"),
+            ("Mute", Some("Master"), "1"),
+            ("Mute", Some("LF"), "0"),
+            ("Bass", None, "-2"),
+            ("NightMode", None, "1"),
+            ("PresetNameList", None, "FactoryDefaults"),
+        ]
+        .iter()
+        .map(|(name, channel, value)| {
+            (
+                name.to_string(),
+                channel.map(str::to_string),
+                value.to_string(),
+            )
+        })
+        .collect();
+        let mut state = RenderingState::default();
+        state.update(&vars);
+        assert_eq!(
+            state,
+            RenderingState {
+                volume: Some(23),
+                mute: Some(true),
+                left_volume: Some(100),
+                right_volume: Some(80),
+                bass: Some(-2),
+                night_mode: Some(true),
+                ..Default::default()
+            }
+        );
+    }
+}
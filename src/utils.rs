@@ -1,5 +1,11 @@
 use roxmltree::{Document, Node};
-use sonor::utils::find_root_node;
+use sonor::rupnp::http::Uri;
+use sonor::{extract_zone_topology, utils::find_root_node, RepeatMode};
+use std::fmt::Write as _;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use crate::types::{Bonds, Satellite, StateVar, Topology};
 
 use super::Result;
 
@@ -21,3 +27,320 @@ pub fn extract_av_transport_last_change(state_xml: &str) -> Result<Vec<(String,
         })
         .collect())
 }
+
+/// Extract the variables of a LastChange event of any service, for every
+/// instance, e.g. `Volume` of channel "LF" of InstanceID "0", or `UpdateID`
+/// of QueueID "0".
+pub fn extract_last_change(state_xml: &str) -> Result<Vec<StateVar>> {
+    let doc = Document::parse(state_xml).map_err(sonor::Error::from)?;
+    Ok(doc
+        .root_element()
+        .children()
+        .filter(Node::is_element)
+        .flat_map(|instance| {
+            let id = instance.attribute("val");
+            instance
+                .children()
+                .filter(Node::is_element)
+                .map(move |var| StateVar {
+                    name: var.tag_name().name().to_string(),
+                    value: var.attribute("val").unwrap_or("").to_string(),
+                    instance: id.map(str::to_string),
+                    channel: var.attribute("channel").map(str::to_string),
+                })
+        })
+        .collect())
+}
+
+/// The state variables of an event, with LastChange expanded into the
+/// variables it holds. A LastChange that can't be parsed is kept as is.
+pub fn expand_state_vars(vars: impl IntoIterator<Item = (String, String)>) -> Vec<StateVar> {
+    let mut expanded = Vec::new();
+    for (name, value) in vars {
+        if name == "LastChange" {
+            match extract_last_change(&value) {
+                Ok(vars) => {
+                    expanded.extend(vars);
+                    continue;
+                }
+                Err(err) => log::warn!("Unable to extract last change: {}", err),
+            }
+        }
+        expanded.push(StateVar {
+            name,
+            value,
+            instance: None,
+            channel: None,
+        });
+    }
+    expanded
+}
+
+/// Extract the topology and bonded sets from ZoneGroupState XML
+pub(crate) fn extract_zone_group_state(state_xml: &str) -> Result<(Topology, Bonds)> {
+    Ok((extract_zone_topology(state_xml)?, extract_bonds(state_xml)?))
+}
+
+/// Find the satellites of bonded sets in ZoneGroupState XML. Bonded sets are
+/// fronted by a visible zone group member whose channel maps list the other
+/// speakers in the set.
+pub(crate) fn extract_bonds(state_xml: &str) -> Result<Bonds> {
+    let doc = Document::parse(state_xml).map_err(sonor::Error::from)?;
+    let mut bonds = Bonds::new();
+    for member in doc
+        .descendants()
+        .filter(|n| n.has_tag_name("ZoneGroupMember"))
+        .filter(|n| n.attribute("Invisible") != Some("1"))
+    {
+        let Some(primary) = member.attribute("UUID") else {
+            continue;
+        };
+        let entries = ["ChannelMapSet", "HTSatChanMapSet"]
+            .iter()
+            .filter_map(|attr| member.attribute(*attr))
+            .flat_map(|map_set| map_set.split(';'))
+            .filter_map(|entry| entry.split_once(':'));
+        for (uuid, channels) in entries {
+            if uuid.eq_ignore_ascii_case(primary)
+                || bonds.iter().any(|s| s.uuid.eq_ignore_ascii_case(uuid))
+            {
+                continue;
+            }
+            bonds.push(Satellite {
+                uuid: uuid.to_owned(),
+                primary: primary.to_owned(),
+                channels: channels.to_owned(),
+            });
+        }
+    }
+    Ok(bonds)
+}
+
+/// Escape a string for use as an XML attribute value
+pub(crate) fn escape_attribute(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Write a topology as ZoneGroupState XML, the inverse of
+/// `extract_zone_group_state`.
+pub(crate) fn topology_to_xml(topology: &Topology, bonds: &Bonds) -> String {
+    let mut xml = String::from("<ZoneGroupState><ZoneGroups>");
+    for (coordinator, infos) in topology {
+        let coordinator = escape_attribute(coordinator);
+        let _ = write!(
+            xml,
+            r#"<ZoneGroup Coordinator="{}" ID="{}:0">"#,
+            coordinator, coordinator
+        );
+        for info in infos {
+            let uuid = info.uuid();
+            let _ = write!(
+                xml,
+                r#"<ZoneGroupMember UUID="{}" Location="{}" ZoneName="{}""#,
+                escape_attribute(uuid),
+                escape_attribute(info.location()),
+                escape_attribute(info.name())
+            );
+            if bonds.iter().any(|s| s.uuid.eq_ignore_ascii_case(uuid)) {
+                xml.push_str(r#" Invisible="1""#);
+            }
+            let map_set = bonds
+                .iter()
+                .filter(|s| s.primary.eq_ignore_ascii_case(uuid))
+                .map(|s| format!("{}:{}", s.uuid, s.channels))
+                .collect::<Vec<_>>();
+            if !map_set.is_empty() {
+                let _ = write!(
+                    xml,
+                    r#" HTSatChanMapSet="{}""#,
+                    escape_attribute(&map_set.join(";"))
+                );
+            }
+            xml.push_str("/>");
+        }
+        xml.push_str("</ZoneGroup>");
+    }
+    xml.push_str("</ZoneGroups></ZoneGroupState>");
+    xml
+}
+
+const SONOS_PORT: u16 = 1400;
+const DESCRIPTION_PATH: &str = "/xml/device_description.xml";
+
+/// Make a device description URL from a speaker address, which can be an IP,
+/// IP and port, host name or URL. URLs without a path get the default sonos
+/// description path.
+pub(crate) fn speaker_description_url(addr: &str) -> Option<Uri> {
+    let addr = addr.trim();
+    let url = if addr.contains("://") {
+        let uri: Uri = addr.parse().ok()?;
+        if uri.path().len() > 1 {
+            return Some(uri);
+        }
+        format!(
+            "{}://{}{}",
+            uri.scheme_str()?,
+            uri.authority()?,
+            DESCRIPTION_PATH
+        )
+    } else if let Ok(ip) = addr.parse::<IpAddr>() {
+        format!(
+            "http://{}{}",
+            SocketAddr::new(ip, SONOS_PORT),
+            DESCRIPTION_PATH
+        )
+    } else if let Ok(socket) = addr.parse::<SocketAddr>() {
+        format!("http://{}{}", socket, DESCRIPTION_PATH)
+    } else if let Some((host, port)) = addr
+        .rsplit_once(':')
+        .filter(|(host, port)| !host.contains(':') && port.parse::<u16>().is_ok())
+    {
+        format!("http://{}:{}{}", host, port, DESCRIPTION_PATH)
+    } else if !addr.is_empty() {
+        format!("http://{}:{}{}", addr, SONOS_PORT, DESCRIPTION_PATH)
+    } else {
+        return None;
+    };
+    url.parse().ok()
+}
+
+/// Format a time or duration as HH:MM:SS, as sonos services expect
+pub(crate) fn format_time(time: Duration) -> String {
+    let secs = time.as_secs();
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Parse a time or duration given as HH:MM:SS
+pub(crate) fn parse_time(time: &str) -> Option<Duration> {
+    let mut parts = time.split(':').map(|p| p.parse::<u64>().ok());
+    let (h, m, s) = (parts.next()??, parts.next()??, parts.next()??);
+    Some(Duration::from_secs(h * 3600 + m * 60 + s))
+}
+
+/// Play mode as sonos services name it
+pub(crate) fn play_mode(shuffle: bool, repeat: RepeatMode) -> &'static str {
+    match (shuffle, repeat) {
+        (false, RepeatMode::None) => "NORMAL",
+        (false, RepeatMode::All) => "REPEAT_ALL",
+        (false, RepeatMode::One) => "REPEAT_ONE",
+        (true, RepeatMode::None) => "SHUFFLE_NOREPEAT",
+        (true, RepeatMode::All) => "SHUFFLE",
+        (true, RepeatMode::One) => "SHUFFLE_REPEAT_ONE",
+    }
+}
+
+/// Shuffle and repeat mode of a sonos play mode
+pub(crate) fn parse_play_mode(mode: &str) -> (bool, RepeatMode) {
+    match mode {
+        "REPEAT_ALL" => (false, RepeatMode::All),
+        "REPEAT_ONE" => (false, RepeatMode::One),
+        "SHUFFLE_NOREPEAT" => (true, RepeatMode::None),
+        "SHUFFLE" => (true, RepeatMode::All),
+        "SHUFFLE_REPEAT_ONE" => (true, RepeatMode::One),
+        _ => (false, RepeatMode::None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_bonds() -> Result<()> {
+        let state = r#"<ZoneGroupState><ZoneGroups>
+            <ZoneGroup Coordinator="RINCON_TV" ID="RINCON_TV:1">
+                <ZoneGroupMember UUID="RINCON_TV" ZoneName="Living Room" HTSatChanMapSet="RINCON_TV:LF,RF;RINCON_SUB:SW;RINCON_SL:LR;RINCON_SR:RR">
+                    <Satellite UUID="RINCON_SUB" ZoneName="Living Room" HTSatChanMapSet="RINCON_TV:LF,RF;RINCON_SUB:SW;RINCON_SL:LR;RINCON_SR:RR" Invisible="1"/>
+                    <Satellite UUID="RINCON_SL" ZoneName="Living Room" HTSatChanMapSet="RINCON_TV:LF,RF;RINCON_SUB:SW;RINCON_SL:LR;RINCON_SR:RR" Invisible="1"/>
+                    <Satellite UUID="RINCON_SR" ZoneName="Living Room" HTSatChanMapSet="RINCON_TV:LF,RF;RINCON_SUB:SW;RINCON_SL:LR;RINCON_SR:RR" Invisible="1"/>
+                </ZoneGroupMember>
+            </ZoneGroup>
+            <ZoneGroup Coordinator="RINCON_L" ID="RINCON_L:2">
+                <ZoneGroupMember UUID="RINCON_L" ZoneName="Kitchen" ChannelMapSet="RINCON_L:LF,LF;RINCON_R:RF,RF"/>
+                <ZoneGroupMember UUID="RINCON_R" ZoneName="Kitchen" ChannelMapSet="RINCON_L:LF,LF;RINCON_R:RF,RF" Invisible="1"/>
+            </ZoneGroup>
+            <ZoneGroup Coordinator="RINCON_D" ID="RINCON_D:3">
+                <ZoneGroupMember UUID="RINCON_D" ZoneName="Den"/>
+            </ZoneGroup>
+        </ZoneGroups></ZoneGroupState>"#;
+        let bonds = extract_bonds(state)?;
+        let summary = bonds
+            .iter()
+            .map(|s| (s.uuid.as_str(), s.primary.as_str(), s.channels.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("RINCON_SUB", "RINCON_TV", "SW"),
+                ("RINCON_SL", "RINCON_TV", "LR"),
+                ("RINCON_SR", "RINCON_TV", "RR"),
+                ("RINCON_R", "RINCON_L", "RF,RF"),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_expand_state_vars() {
+        let last_change = r#"<Event xmlns="urn:schemas-upnp-org:metadata-1-0/RCS/"><InstanceID val="0"><Volume channel="Master" val="23"/><Volume channel="LF" val="100"/><Loudness channel="Master" val="1"/></InstanceID></Event>"#;
+        let vars = expand_state_vars([
+            ("LastChange".to_owned(), last_change.to_owned()),
+            ("GroupVolume".to_owned(), "12".to_owned()),
+        ]);
+        let var =
+            |name: &str, value: &str, instance: Option<&str>, channel: Option<&str>| StateVar {
+                name: name.to_owned(),
+                value: value.to_owned(),
+                instance: instance.map(str::to_owned),
+                channel: channel.map(str::to_owned),
+            };
+        assert_eq!(
+            vars,
+            vec![
+                var("Volume", "23", Some("0"), Some("Master")),
+                var("Volume", "100", Some("0"), Some("LF")),
+                var("Loudness", "1", Some("0"), Some("Master")),
+                var("GroupVolume", "12", None, None),
+            ]
+        );
+
+        let queue = r#"<Event xmlns="urn:schemas-sonos-com:metadata-1-0/Queue/"><QueueID val="0"><UpdateID val="5"/></QueueID></Event>"#;
+        assert_eq!(
+            extract_last_change(queue).unwrap(),
+            vec![var("UpdateID", "5", Some("0"), None)]
+        );
+    }
+
+    #[test]
+    fn test_speaker_description_url() {
+        let expected = "http://192.168.1.20:1400/xml/device_description.xml";
+        for addr in [
+            "192.168.1.20",
+            "192.168.1.20:1400",
+            "http://192.168.1.20:1400",
+            "http://192.168.1.20:1400/",
+            expected,
+        ] {
+            assert_eq!(speaker_description_url(addr).unwrap(), expected);
+        }
+        assert_eq!(
+            speaker_description_url("kitchen.local").unwrap(),
+            "http://kitchen.local:1400/xml/device_description.xml"
+        );
+        assert_eq!(
+            speaker_description_url("kitchen.local:1401").unwrap(),
+            "http://kitchen.local:1401/xml/device_description.xml"
+        );
+        assert_eq!(
+            speaker_description_url("fe80::1").unwrap(),
+            "http://[fe80::1]:1400/xml/device_description.xml"
+        );
+        assert!(speaker_description_url("").is_none());
+        assert!(speaker_description_url("not a host").is_none());
+    }
+}